@@ -0,0 +1,448 @@
+//! 静态文件：编译进二进制的资源、开发模式下的磁盘目录和自动刷新。
+
+use crate::{to_hex, HttpError};
+use axum::body::{boxed, Bytes, Empty, Full};
+use axum::headers::{
+    AcceptRanges, CacheControl, ContentRange, ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch,
+    LastModified, Range,
+};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use rust_embed::utils::read_file_from_fs;
+use rust_embed::{EmbeddedFile, RustEmbed};
+use std::borrow::Cow;
+use std::convert::Infallible;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tracing::warn;
+
+#[derive(RustEmbed)]
+#[folder = "static/"]
+pub(crate) struct Assets;
+
+// 不做 SPA 回退的 API 路径前缀，未知路径直接返回 404
+pub(crate) const API_PREFIXES: &[&str] = &[
+    "api",
+    "todos",
+    "lists",
+    "invitations",
+    "tokens",
+    "webhooks",
+    "trash",
+    "metrics",
+    "graphql",
+    "login",
+];
+
+// 预压缩版本：(Content-Encoding, 文件后缀)，按优先级排列
+pub(crate) const PRECOMPRESSED: &[(&str, &str)] = &[("br", "br"), ("gzip", "gz")];
+
+// 文件名带内容 hash 的资源可以长期缓存
+pub(crate) const HASHED_ASSET_MAX_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+// GraphiQL 页面只能执行本站和 CDN 上这几个固定版本的文件，CDN 上别的路径和内联脚本都不行。
+// 页面里引用的版本变了这里也要一起改
+pub(crate) const GRAPHIQL_CSP: &str = concat!(
+    "default-src 'none'; ",
+    "script-src 'self' ",
+    "https://unpkg.com/react@18.2.0/umd/react.production.min.js ",
+    "https://unpkg.com/react-dom@18.2.0/umd/react-dom.production.min.js ",
+    "https://unpkg.com/graphql-ws@5.14.0/umd/graphql-ws.min.js ",
+    "https://unpkg.com/graphiql@3.0.6/graphiql.min.js; ",
+    "style-src 'self' 'unsafe-inline' https://unpkg.com/graphiql@3.0.6/graphiql.min.css; ",
+    "connect-src 'self'; img-src 'self' data:; font-src 'self' data:"
+);
+
+// 开发模式下注入到 html 里的脚本，收到 reload 事件就刷新页面
+pub(crate) const LIVE_RELOAD_SCRIPT: &str = r#"<script>new EventSource("/__livereload").addEventListener("reload", () => location.reload());</script>"#;
+
+/// 静态文件来源：release 默认使用编译进二进制的资源，
+/// 开发模式直接读磁盘上的 `static/` 目录
+#[derive(Clone)]
+pub(crate) enum AssetSource {
+    Embedded,
+    Disk(Arc<DevAssets>),
+}
+
+pub(crate) struct DevAssets {
+    pub(crate) root: PathBuf,
+    reload: broadcast::Sender<String>,
+}
+
+impl AssetSource {
+    /// 设置了 `AXUM_LIVE_DEV` 环境变量或者带 `--dev` 参数启动时进入开发模式
+    pub(crate) fn from_env() -> Self {
+        let dev = std::env::var_os("AXUM_LIVE_DEV").is_some()
+            || std::env::args().any(|arg| arg == "--dev");
+        if !dev {
+            return AssetSource::Embedded;
+        }
+
+        AssetSource::disk(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("static"))
+    }
+
+    /// 开发模式，从 `root` 目录读取静态文件
+    pub(crate) fn disk(root: PathBuf) -> Self {
+        let (reload, _) = broadcast::channel(16);
+        AssetSource::Disk(Arc::new(DevAssets { root, reload }))
+    }
+
+    pub(crate) fn get(&self, path: &str) -> Option<EmbeddedFile> {
+        match self {
+            AssetSource::Embedded => Assets::get(path),
+            AssetSource::Disk(dev) => {
+                // 不允许 `..` 之类的路径跳出 static 目录
+                let relative = std::path::Path::new(path);
+                if !relative
+                    .components()
+                    .all(|c| matches!(c, std::path::Component::Normal(_)))
+                {
+                    return None;
+                }
+                read_file_from_fs(&dev.root.join(relative)).ok()
+            }
+        }
+    }
+
+    pub(crate) fn is_dev(&self) -> bool {
+        matches!(self, AssetSource::Disk(_))
+    }
+}
+
+/// 监听 static 目录，文件有变化时通知所有 `/__livereload` 连接。
+/// 返回的 watcher 需要一直持有，drop 掉就不再监听了
+pub(crate) fn watch_assets(dev: &DevAssets) -> notify::Result<RecommendedWatcher> {
+    let root = dev.root.clone();
+    let reload = dev.reload.clone();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        match res {
+            Ok(event) if !event.kind.is_access() => {
+                for path in event.paths {
+                    let path = path.strip_prefix(&root).unwrap_or(&path);
+                    // 没有浏览器连着的时候发送会失败，忽略即可
+                    let _ = reload.send(path.display().to_string());
+                }
+            }
+            Ok(_) => (),
+            Err(e) => warn!("watch error: {:?}", e),
+        }
+    })?;
+    watcher.watch(&dev.root, RecursiveMode::Recursive)?;
+    Ok(watcher)
+}
+
+pub(crate) struct StaticFile {
+    path: String,
+    method: Method,
+    headers: HeaderMap,
+    source: AssetSource,
+}
+
+impl IntoResponse for StaticFile {
+    fn into_response(self) -> Response {
+        if self.method != Method::GET && self.method != Method::HEAD {
+            return (
+                StatusCode::METHOD_NOT_ALLOWED,
+                [(header::ALLOW, "GET, HEAD")],
+            )
+                .into_response();
+        }
+
+        let path = match resolve_asset_path(&self.source, &self.path) {
+            Some(path) => path,
+            None => return (StatusCode::NOT_FOUND, "Not Found").into_response(),
+        };
+        let (file, encoding) = match select_variant(&self.source, &path, &self.headers) {
+            Some(variant) => variant,
+            None => return (StatusCode::NOT_FOUND, "Not Found").into_response(),
+        };
+
+        self.build_response(&path, file, encoding)
+            .unwrap_or_else(|_| HttpError::Internal.into_response())
+    }
+}
+
+impl StaticFile {
+    pub(crate) fn build_response(
+        &self,
+        path: &str,
+        file: EmbeddedFile,
+        encoding: Option<&str>,
+    ) -> Result<Response, axum::http::Error> {
+        let hash = file.metadata.sha256_hash();
+        let etag = format!("\"{}\"", to_hex(&hash[..16]))
+            .parse::<ETag>()
+            .expect("hex etag is always valid");
+        let last_modified = file
+            .metadata
+            .last_modified()
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        let mime = mime_guess::from_path(path).first_or_octet_stream();
+
+        let mut builder = Response::builder()
+            .header(header::CONTENT_TYPE, mime.as_ref())
+            .header(header::VARY, "Accept-Encoding");
+        if let Some(encoding) = encoding {
+            builder = builder.header(header::CONTENT_ENCODING, encoding);
+        }
+        let headers = builder.headers_mut().expect("builder has no errors yet");
+        headers.typed_insert(etag.clone());
+        headers.typed_insert(AcceptRanges::bytes());
+        headers.typed_insert(cache_control_for(path));
+        if let Some(last_modified) = last_modified {
+            headers.typed_insert(LastModified::from(last_modified));
+        }
+
+        // If-None-Match 优先于 If-Modified-Since
+        let not_modified = match self.headers.typed_get::<IfNoneMatch>() {
+            Some(if_none_match) => !if_none_match.precondition_passes(&etag),
+            None => match (self.headers.typed_get::<IfModifiedSince>(), last_modified) {
+                (Some(since), Some(modified)) => !since.is_modified(modified),
+                _ => false,
+            },
+        };
+        if not_modified {
+            return builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(boxed(Empty::new()));
+        }
+
+        let data = match file.data {
+            Cow::Borrowed(data) => Bytes::from_static(data),
+            Cow::Owned(data) => Bytes::from(data),
+        };
+        let data =
+            if self.source.is_dev() && encoding.is_none() && mime == mime_guess::mime::TEXT_HTML {
+                let mut html = data.to_vec();
+                html.extend_from_slice(LIVE_RELOAD_SCRIPT.as_bytes());
+                Bytes::from(html)
+            } else {
+                data
+            };
+        let len = data.len() as u64;
+        let (status, data) = match self.headers.typed_get::<Range>() {
+            Some(range) => match byte_range(&range, len) {
+                ByteRange::Full => (StatusCode::OK, data),
+                ByteRange::Partial(start, end) => {
+                    let content_range = ContentRange::bytes(start..=end, len)
+                        .expect("range is bounded on both ends");
+                    builder
+                        .headers_mut()
+                        .expect("builder has no errors yet")
+                        .typed_insert(content_range);
+                    let data = data.slice(start as usize..=end as usize);
+                    (StatusCode::PARTIAL_CONTENT, data)
+                }
+                ByteRange::Unsatisfiable => {
+                    builder
+                        .headers_mut()
+                        .expect("builder has no errors yet")
+                        .typed_insert(ContentRange::unsatisfied_bytes(len));
+                    return builder
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .body(boxed(Empty::new()));
+                }
+            },
+            None => (StatusCode::OK, data),
+        };
+
+        let builder = builder
+            .status(status)
+            .header(header::CONTENT_LENGTH, data.len());
+        // HEAD 请求只返回头部
+        if self.method == Method::HEAD {
+            builder.body(boxed(Empty::new()))
+        } else {
+            builder.body(boxed(Full::from(data)))
+        }
+    }
+}
+
+/// 找到真正要返回的资源路径：存在的文件原样返回，
+/// 未知的非 API 路径回退到 `index.html`，交给前端路由处理
+pub(crate) fn resolve_asset_path(source: &AssetSource, path: &str) -> Option<String> {
+    let path = if path.is_empty() { "index.html" } else { path };
+    if source.get(path).is_some() {
+        return Some(path.to_string());
+    }
+
+    let first_segment = path.split('/').next().unwrap_or_default();
+    let is_api = API_PREFIXES.contains(&first_segment);
+    // 带扩展名的路径是在请求具体文件，找不到就该 404，而不是返回 html
+    let is_file = std::path::Path::new(path).extension().is_some();
+    (!is_api && !is_file).then(|| "index.html".to_string())
+}
+
+/// 根据 Accept-Encoding 选择预压缩版本，没有可用的则返回原文件
+pub(crate) fn select_variant(
+    source: &AssetSource,
+    path: &str,
+    headers: &HeaderMap,
+) -> Option<(EmbeddedFile, Option<&'static str>)> {
+    PRECOMPRESSED
+        .iter()
+        .filter(|(encoding, _)| accepts_encoding(headers, encoding))
+        .find_map(|(encoding, ext)| {
+            source
+                .get(&format!("{}.{}", path, ext))
+                .map(|file| (file, Some(*encoding)))
+        })
+        .or_else(|| source.get(path).map(|file| (file, None)))
+}
+
+pub(crate) fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let quality = parts
+                .find_map(|part| part.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            name.eq_ignore_ascii_case(encoding) && quality > 0.0
+        })
+}
+
+pub(crate) fn cache_control_for(path: &str) -> CacheControl {
+    if is_hashed_asset(path) {
+        CacheControl::new()
+            .with_public()
+            .with_max_age(HASHED_ASSET_MAX_AGE)
+            .with_immutable()
+    } else {
+        // 每次都用 ETag 向服务器确认一下
+        CacheControl::new().with_no_cache()
+    }
+}
+
+/// 形如 `app.3f2a9c1b.js` 或 `app-f71b1eba01039194.js` 的文件名视为带 hash：
+/// hash 是文件名和扩展名之间单独的一段，前面必须还有真正的名字，
+/// `deadbeef.js` 这种整个名字碰巧是十六进制的不算
+pub(crate) fn is_hashed_asset(path: &str) -> bool {
+    let is_hash = |part: &str| part.len() >= 8 && part.chars().all(|c| c.is_ascii_hexdigit());
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+    let mut parts: Vec<&str> = file_name.split('.').collect();
+    // 去掉扩展名，没有扩展名的不是打包出来的资源
+    if parts.len() < 2 || parts.pop().unwrap_or_default().is_empty() {
+        return false;
+    }
+    let (name, rest) = parts.split_first().expect("at least the name is left");
+    if name.is_empty() {
+        return false;
+    }
+    let dashed = name
+        .rsplit_once('-')
+        .is_some_and(|(name, hash)| !name.is_empty() && is_hash(hash));
+    dashed || rest.iter().any(|part| is_hash(part))
+}
+
+pub(crate) enum ByteRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// 只支持单个区间，多区间请求按 RFC 7233 的允许直接返回整个文件
+pub(crate) fn byte_range(range: &Range, len: u64) -> ByteRange {
+    let mut ranges = range.iter();
+    let (start, end) = match (ranges.next(), ranges.next()) {
+        (Some(bounds), None) => bounds,
+        _ => return ByteRange::Full,
+    };
+    if len == 0 {
+        return ByteRange::Unsatisfiable;
+    }
+
+    let (start, end) = match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => (start, end.min(len - 1)),
+        (Bound::Included(start), Bound::Unbounded) => (start, len - 1),
+        // bytes=-N 表示最后 N 个字节
+        (Bound::Unbounded, Bound::Included(n)) if n > 0 => (len.saturating_sub(n), len - 1),
+        _ => return ByteRange::Unsatisfiable,
+    };
+    if start > end || start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+pub(crate) async fn index_handler(
+    method: Method,
+    headers: HeaderMap,
+    Extension(source): Extension<AssetSource>,
+) -> impl IntoResponse {
+    StaticFile {
+        path: "index.html".to_string(),
+        method,
+        headers,
+        source,
+    }
+}
+
+/// GraphiQL 调试页面，和其他静态文件一样编译进二进制。
+/// 页面里的 GraphiQL 和 React 从 unpkg CDN 加载固定的版本，离线时用不了
+pub(crate) async fn graphiql_handler(
+    method: Method,
+    headers: HeaderMap,
+    Extension(source): Extension<AssetSource>,
+) -> Response {
+    // 开发模式注入的自动刷新脚本是内联的，不加 CSP
+    let is_dev = source.is_dev();
+    let mut res = StaticFile {
+        path: "graphiql.html".to_string(),
+        method,
+        headers,
+        source,
+    }
+    .into_response();
+    if !is_dev {
+        res.headers_mut().insert(
+            header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static(GRAPHIQL_CSP),
+        );
+    }
+    res
+}
+
+pub(crate) async fn static_handler(
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    Extension(source): Extension<AssetSource>,
+) -> impl IntoResponse {
+    let path = uri.path().trim_start_matches('/').to_string();
+    StaticFile {
+        path,
+        method,
+        headers,
+        source,
+    }
+}
+
+/// 开发模式下把 static 目录的变化推给浏览器，非开发模式没有这个接口
+pub(crate) async fn livereload_handler(
+    Extension(source): Extension<AssetSource>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    let dev = match source {
+        AssetSource::Disk(dev) => dev,
+        AssetSource::Embedded => return Err(StatusCode::NOT_FOUND),
+    };
+
+    // 落后太多的消息直接丢掉，反正只需要刷新一次
+    let stream = BroadcastStream::new(dev.reload.subscribe())
+        .filter_map(|path| path.ok())
+        .map(|path| Ok(Event::default().event("reload").data(path)));
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
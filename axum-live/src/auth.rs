@@ -0,0 +1,449 @@
+//! 登录、JWT 和 personal access token。
+
+use crate::metrics::{AuthFailure, Metrics};
+use crate::{get_epoch, get_next_id, to_hex, HttpError};
+use axum::extract::rejection::TypedHeaderRejectionReason;
+use axum::extract::{FromRequest, FromRequestParts, Path};
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::http::request::Parts;
+use axum::http::{Extensions, Method, Request, StatusCode};
+use axum::{async_trait, Extension, Json, TypedHeader};
+use jsonwebtoken as jwt;
+use jwt::Validation;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::warn;
+
+pub(crate) const SECRET_KEY: &[u8] = b"abcdefghijklmnopqrstuvwxy";
+// personal access token 最长的有效期，更久的直接不设过期时间就好
+pub(crate) const MAX_TOKEN_DAYS: usize = 10 * 365;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CreateToken {
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    // 不传表示永不过期
+    #[serde(default)]
+    pub expires_in_days: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CreateTokenResponse {
+    // 明文只会返回这一次
+    pub token: String,
+    #[serde(flatten)]
+    pub info: ApiToken,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LoginRequest {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LoginResponse {
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Claims {
+    pub(crate) id: usize,
+    name: String,
+    exp: usize,
+    // 用 personal access token 登录时才有，JWT 登录拥有全部权限
+    #[serde(skip)]
+    token: Option<TokenGrant>,
+}
+
+#[derive(Debug, Clone)]
+struct TokenGrant {
+    id: usize,
+    scopes: Vec<TokenScope>,
+}
+
+impl Claims {
+    /// 管理 token 之类的敏感操作只允许交互式登录
+    pub(crate) fn require_interactive(&self) -> Result<(), HttpError> {
+        match &self.token {
+            Some(token) => {
+                warn!("token {} is not allowed to manage tokens", token.id);
+                Err(HttpError::Forbidden)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// 用 token 登录时要有对应的 scope
+    pub(crate) fn require_scope(&self, required: TokenScope) -> Result<(), HttpError> {
+        let Some(token) = &self.token else {
+            return Ok(());
+        };
+        if token.scopes.iter().any(|scope| scope.allows(required)) {
+            Ok(())
+        } else {
+            Err(HttpError::Forbidden)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TokenScope {
+    Read,
+    // 包含 read
+    Write,
+}
+
+impl TokenScope {
+    /// GET/HEAD 只需要 read，其余方法都需要 write
+    pub(crate) fn required_for(method: &Method) -> Self {
+        if method == Method::GET || method == Method::HEAD {
+            TokenScope::Read
+        } else {
+            TokenScope::Write
+        }
+    }
+
+    pub(crate) fn allows(&self, required: TokenScope) -> bool {
+        *self == TokenScope::Write || *self == required
+    }
+}
+
+// 用前缀区分 personal access token 和 JWT
+pub(crate) const TOKEN_PREFIX: &str = "pat_";
+
+/// 给脚本和 CI 用的长期 token，只保存 hash，明文只在创建时返回一次
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ApiToken {
+    pub id: usize,
+    #[serde(skip)]
+    pub user_id: usize,
+    pub name: String,
+    // 明文的前几位，方便用户辨认是哪个 token
+    pub hint: String,
+    #[serde(skip)]
+    pub hash: String,
+    pub scopes: Vec<TokenScope>,
+    pub created_at: usize,
+    pub expires_at: Option<usize>,
+    pub last_used_at: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TokenStore {
+    tokens: Arc<std::sync::RwLock<Vec<ApiToken>>>,
+}
+
+impl TokenStore {
+    /// 返回明文 token 和保存的记录
+    pub(crate) fn create(
+        &self,
+        user_id: usize,
+        name: String,
+        scopes: Vec<TokenScope>,
+        expires_at: Option<usize>,
+    ) -> (String, ApiToken) {
+        let secret: [u8; 32] = rand::thread_rng().gen();
+        let plain = format!("{}{}", TOKEN_PREFIX, to_hex(&secret));
+        let token = ApiToken {
+            id: get_next_id(),
+            user_id,
+            name,
+            hint: plain[..TOKEN_PREFIX.len() + 6].to_string(),
+            hash: hash_token(&plain),
+            scopes,
+            created_at: get_epoch(),
+            expires_at,
+            last_used_at: None,
+        };
+        self.tokens.write().unwrap().push(token.clone());
+        (plain, token)
+    }
+
+    pub(crate) fn list(&self, user_id: usize) -> Vec<ApiToken> {
+        let tokens = self.tokens.read().unwrap();
+        tokens
+            .iter()
+            .filter(|token| token.user_id == user_id)
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn revoke(&self, user_id: usize, id: usize) -> Option<ApiToken> {
+        let mut tokens = self.tokens.write().unwrap();
+        let index = tokens
+            .iter()
+            .position(|token| token.id == id && token.user_id == user_id)?;
+        Some(tokens.remove(index))
+    }
+
+    /// 校验明文 token，成功时记录最后使用时间
+    pub(crate) fn verify(&self, plain: &str) -> Result<ApiToken, AuthFailure> {
+        let hash = hash_token(plain);
+        let now = get_epoch();
+        let mut tokens = self.tokens.write().unwrap();
+        let token = tokens
+            .iter_mut()
+            .find(|token| token.hash == hash)
+            .ok_or(AuthFailure::Invalid)?;
+        if token.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(AuthFailure::Expired);
+        }
+        token.last_used_at = Some(now);
+        Ok(token.clone())
+    }
+
+    /// 和 verify 一样，但不更新最后使用时间，给限流之类只需要识别用户的地方用
+    pub(crate) fn peek(&self, plain: &str) -> Option<ApiToken> {
+        let hash = hash_token(plain);
+        let now = get_epoch();
+        let tokens = self.tokens.read().unwrap();
+        tokens
+            .iter()
+            .find(|token| token.hash == hash)
+            .filter(|token| token.expires_at.is_none_or(|expires_at| expires_at > now))
+            .cloned()
+    }
+}
+
+pub(crate) fn hash_token(plain: &str) -> String {
+    to_hex(&Sha256::digest(plain.as_bytes()))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct User {
+    pub id: usize,
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UserStore {
+    users: Arc<std::sync::RwLock<Vec<User>>>,
+}
+
+impl UserStore {
+    /// 还没有校验密码，第一次登录的邮箱会自动注册
+    pub(crate) fn login(&self, email: &str) -> User {
+        let email = email.trim().to_lowercase();
+        let mut users = self.users.write().unwrap();
+        if let Some(user) = users.iter().find(|user| user.email == email) {
+            return user.clone();
+        }
+
+        let name = email.split('@').next().unwrap_or_default().to_string();
+        let user = User {
+            id: get_next_id(),
+            name,
+            email,
+        };
+        users.push(user.clone());
+        user
+    }
+
+    pub(crate) fn get(&self, id: usize) -> Option<User> {
+        let users = self.users.read().unwrap();
+        users.iter().find(|user| user.id == id).cloned()
+    }
+}
+
+pub(crate) async fn login_handler(
+    Extension(users): Extension<UserStore>,
+    Json(login): Json<LoginRequest>,
+) -> Json<LoginResponse> {
+    // skip password validation
+    let user = users.login(&login.email);
+    let claims: Claims = Claims {
+        id: user.id,
+        name: user.name,
+        exp: get_epoch() + 14 * 24 * 60 * 60,
+        token: None,
+    };
+    let key = jwt::EncodingKey::from_secret(SECRET_KEY);
+    let token = jwt::encode(&jwt::Header::default(), &claims, &key).unwrap();
+
+    Json(LoginResponse { token })
+}
+
+pub(crate) async fn create_token_handler(
+    claims: Claims,
+    Extension(tokens): Extension<TokenStore>,
+    Json(request): Json<CreateToken>,
+) -> Result<(StatusCode, Json<CreateTokenResponse>), HttpError> {
+    claims.require_interactive()?;
+    let name = request.name.trim();
+    if name.is_empty() {
+        return Err(HttpError::BadRequest("token name is empty"));
+    }
+    if request.scopes.is_empty() {
+        return Err(HttpError::BadRequest("token needs at least one scope"));
+    }
+
+    if request
+        .expires_in_days
+        .is_some_and(|days| days > MAX_TOKEN_DAYS)
+    {
+        return Err(HttpError::BadRequest("token expires too late"));
+    }
+    let expires_at = request
+        .expires_in_days
+        .map(|days| get_epoch() + days * 24 * 60 * 60);
+    let (token, info) = tokens.create(claims.id, name.to_string(), request.scopes, expires_at);
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateTokenResponse { token, info }),
+    ))
+}
+
+pub(crate) async fn tokens_handler(
+    claims: Claims,
+    Extension(tokens): Extension<TokenStore>,
+) -> Result<Json<Vec<ApiToken>>, HttpError> {
+    claims.require_interactive()?;
+    Ok(Json(tokens.list(claims.id)))
+}
+
+pub(crate) async fn revoke_token_handler(
+    claims: Claims,
+    Extension(tokens): Extension<TokenStore>,
+    Path(id): Path<usize>,
+) -> Result<StatusCode, HttpError> {
+    claims.require_interactive()?;
+    tokens
+        .revoke(claims.id, id)
+        .map(|_| StatusCode::NO_CONTENT)
+        .ok_or(HttpError::NotFound)
+}
+
+pub(crate) fn decode_token(token: &str) -> Result<Claims, jwt::errors::Error> {
+    let key = jwt::DecodingKey::from_secret(SECRET_KEY);
+    jwt::decode::<Claims>(token, &key, &Validation::default()).map(|token| token.claims)
+}
+
+#[async_trait]
+impl<S, B> FromRequest<S, B> for Claims
+where
+    // these bounds are required by `async_trait`
+    B: Send + 'static,
+    S: Send + Sync,
+{
+    type Rejection = HttpError;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let (mut parts, _) = req.into_parts();
+        Claims::from_request_parts(&mut parts, state).await
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Claims
+where
+    S: Send + Sync,
+{
+    type Rejection = HttpError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let AnyScope(claims) = AnyScope::from_request_parts(parts, state).await?;
+        claims.require_scope(TokenScope::required_for(&parts.method))?;
+        Ok(claims)
+    }
+}
+
+/// 和 `Claims` 一样做认证，但不按请求方法检查 token 的 scope，
+/// 给 GraphQL 这种读写都走 POST 的接口用，需要自己调用 `require_scope`
+pub(crate) struct AnyScope(pub Claims);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AnyScope
+where
+    S: Send + Sync,
+{
+    type Rejection = HttpError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let metrics = parts.extensions.get::<Metrics>().cloned();
+        let header = TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state).await;
+        let result = match header {
+            Ok(TypedHeader(Authorization(bearer))) => {
+                authenticate(bearer.token(), &parts.extensions)
+            }
+            Err(e) => {
+                warn!("FromRequestParts1: {:?}", e);
+                match e.reason() {
+                    TypedHeaderRejectionReason::Missing => Err(AuthFailure::Missing),
+                    _ => Err(AuthFailure::Malformed),
+                }
+            }
+        };
+
+        match result {
+            Ok(claims) => {
+                if let Some(metrics) = &metrics {
+                    metrics.user_seen(claims.id);
+                }
+                Ok(AnyScope(claims))
+            }
+            Err(failure) => {
+                if let Some(metrics) = &metrics {
+                    metrics.auth_failed(failure);
+                }
+                Err(HttpError::Auth)
+            }
+        }
+    }
+}
+
+pub(crate) fn authenticate(token: &str, extensions: &Extensions) -> Result<Claims, AuthFailure> {
+    authenticate_with(token, extensions.get(), extensions.get())
+}
+
+/// 根据前缀区分 JWT 和 personal access token
+pub(crate) fn authenticate_with(
+    token: &str,
+    tokens: Option<&TokenStore>,
+    users: Option<&UserStore>,
+) -> Result<Claims, AuthFailure> {
+    if !token.starts_with(TOKEN_PREFIX) {
+        return decode_token(token).map_err(|e| {
+            warn!("authenticate jwt: {:?}", e);
+            match e.kind() {
+                jwt::errors::ErrorKind::ExpiredSignature => AuthFailure::Expired,
+                jwt::errors::ErrorKind::InvalidSignature => AuthFailure::Invalid,
+                _ => AuthFailure::Malformed,
+            }
+        });
+    }
+
+    let tokens = tokens.ok_or(AuthFailure::Invalid)?;
+    let token = tokens
+        .verify(token)
+        .inspect_err(|failure| warn!("authenticate token: {:?}", failure))?;
+
+    let name = users
+        .and_then(|users| users.get(token.user_id))
+        .map(|user| user.name)
+        .unwrap_or_default();
+    Ok(Claims {
+        id: token.user_id,
+        name,
+        exp: token.expires_at.unwrap_or(usize::MAX),
+        token: Some(TokenGrant {
+            id: token.id,
+            scopes: token.scopes,
+        }),
+    })
+}
+
+/// 只识别出是哪个用户，不做权限检查
+pub(crate) fn peek_user_id(token: &str, extensions: &Extensions) -> Option<usize> {
+    if token.starts_with(TOKEN_PREFIX) {
+        let tokens = extensions.get::<TokenStore>()?;
+        tokens.peek(token).map(|token| token.user_id)
+    } else {
+        decode_token(token).ok().map(|claims| claims.id)
+    }
+}
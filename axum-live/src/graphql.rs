@@ -0,0 +1,459 @@
+//! GraphQL 接口：和 REST 共用 `Claims` 认证和 `TodoStore`，订阅走 WebSocket。
+
+use crate::auth::{authenticate_with, AnyScope, Claims, TokenScope, TokenStore, UserStore};
+use crate::lists::TodoList;
+use crate::todos::{
+    version_precondition, CreateTodo, ListTodosQuery, Priority, StoreError, Todo, TodoEventKind,
+    TodoStore, UpdateTodo,
+};
+use crate::HttpError;
+use async_graphql::http::ALL_WEBSOCKET_PROTOCOLS;
+use async_graphql::{
+    Context, Data, Enum, ErrorExtensions, InputObject, MaybeUndefined, Object, Schema,
+    SimpleObject, Subscription, ID,
+};
+use async_graphql_axum::{GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLWebSocket};
+use axum::extract::WebSocketUpgrade;
+use axum::headers::authorization::Bearer;
+use axum::headers::{Authorization, HeaderMapExt};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+pub(crate) type TodoSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+pub(crate) fn build_schema(store: TodoStore, users: UserStore) -> TodoSchema {
+    Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(store)
+        .data(users)
+        .finish()
+}
+
+/// 错误里带上和 REST 接口一致的状态码，放在 `extensions.code`
+fn error(status: StatusCode, message: &str) -> async_graphql::Error {
+    async_graphql::Error::new(message).extend_with(|_, e| e.set("code", status.as_u16()))
+}
+
+impl From<StoreError> for async_graphql::Error {
+    fn from(e: StoreError) -> Self {
+        error(e.status(), e.message())
+    }
+}
+
+fn claims<'a>(ctx: &Context<'a>) -> async_graphql::Result<&'a Claims> {
+    ctx.data_opt::<Claims>()
+        .ok_or_else(|| error(StatusCode::UNAUTHORIZED, "unauthorized"))
+}
+
+/// 读写都走 POST，只能在这里按操作类型检查 token 的 scope
+fn require<'a>(ctx: &Context<'a>, scope: TokenScope) -> async_graphql::Result<&'a Claims> {
+    let claims = claims(ctx)?;
+    claims
+        .require_scope(scope)
+        .map_err(|_| error(StatusCode::FORBIDDEN, "token scope does not allow this"))?;
+    Ok(claims)
+}
+
+fn parse_id(id: &ID) -> async_graphql::Result<usize> {
+    id.parse()
+        .map_err(|_| error(StatusCode::BAD_REQUEST, "invalid id"))
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(name = "Priority")]
+pub(crate) enum PriorityValue {
+    Low,
+    Normal,
+    High,
+}
+
+impl From<Priority> for PriorityValue {
+    fn from(priority: Priority) -> Self {
+        match priority {
+            Priority::Low => PriorityValue::Low,
+            Priority::Normal => PriorityValue::Normal,
+            Priority::High => PriorityValue::High,
+        }
+    }
+}
+
+impl From<PriorityValue> for Priority {
+    fn from(priority: PriorityValue) -> Self {
+        match priority {
+            PriorityValue::Low => Priority::Low,
+            PriorityValue::Normal => Priority::Normal,
+            PriorityValue::High => Priority::High,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "User")]
+pub(crate) struct UserNode {
+    id: ID,
+    name: String,
+}
+
+impl UserNode {
+    fn load(ctx: &Context<'_>, id: usize) -> Option<Self> {
+        let user = ctx.data_unchecked::<UserStore>().get(id)?;
+        Some(Self {
+            id: user.id.into(),
+            name: user.name,
+        })
+    }
+}
+
+pub(crate) struct TodoNode(Todo);
+
+#[Object(name = "Todo")]
+impl TodoNode {
+    async fn id(&self) -> ID {
+        self.0.id.into()
+    }
+
+    async fn title(&self) -> &str {
+        &self.0.title
+    }
+
+    async fn completed(&self) -> bool {
+        self.0.completed
+    }
+
+    async fn version(&self) -> u64 {
+        self.0.version
+    }
+
+    async fn created_at(&self) -> usize {
+        self.0.created_at
+    }
+
+    async fn updated_at(&self) -> usize {
+        self.0.updated_at
+    }
+
+    async fn due_at(&self) -> Option<usize> {
+        self.0.due_at
+    }
+
+    async fn overdue(&self) -> bool {
+        self.0.is_overdue(crate::get_epoch())
+    }
+
+    async fn priority(&self) -> PriorityValue {
+        self.0.priority.into()
+    }
+
+    async fn tags(&self) -> &[String] {
+        &self.0.tags
+    }
+
+    async fn position(&self) -> usize {
+        self.0.position
+    }
+
+    async fn list_id(&self) -> Option<ID> {
+        self.0.list_id.map(ID::from)
+    }
+
+    /// 创建者
+    async fn owner(&self, ctx: &Context<'_>) -> Option<UserNode> {
+        UserNode::load(ctx, self.0.user_id)
+    }
+}
+
+pub(crate) struct ListNode(TodoList);
+
+#[Object(name = "List")]
+impl ListNode {
+    async fn id(&self) -> ID {
+        self.0.id.into()
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    async fn created_at(&self) -> usize {
+        self.0.created_at
+    }
+
+    async fn owner(&self, ctx: &Context<'_>) -> Option<UserNode> {
+        UserNode::load(ctx, self.0.owner_id)
+    }
+
+    async fn members(&self, ctx: &Context<'_>) -> Vec<UserNode> {
+        self.0
+            .members
+            .iter()
+            .filter_map(|member| UserNode::load(ctx, member.user_id))
+            .collect()
+    }
+
+    async fn todos(
+        &self,
+        ctx: &Context<'_>,
+        tags: Option<Vec<String>>,
+        overdue: Option<bool>,
+    ) -> async_graphql::Result<Vec<TodoNode>> {
+        let claims = claims(ctx)?;
+        let store = ctx.data_unchecked::<TodoStore>();
+        let todos = store
+            .list_shared(claims.id, self.0.id, &list_query(tags, overdue))
+            .await?;
+        Ok(todos.into_iter().map(TodoNode).collect())
+    }
+}
+
+fn list_query(tags: Option<Vec<String>>, overdue: Option<bool>) -> ListTodosQuery {
+    ListTodosQuery {
+        tag: tags.map(|tags| tags.join(",")),
+        overdue,
+    }
+}
+
+#[derive(InputObject)]
+pub(crate) struct CreateTodoInput {
+    title: String,
+    due_at: Option<usize>,
+    priority: Option<PriorityValue>,
+    #[graphql(default)]
+    tags: Vec<String>,
+    /// 放到共享清单里，需要 editor 权限
+    list_id: Option<ID>,
+}
+
+#[derive(InputObject)]
+pub(crate) struct UpdateTodoInput {
+    title: Option<String>,
+    completed: Option<bool>,
+    /// 不传表示不修改，传 null 表示清除截止时间
+    due_at: MaybeUndefined<usize>,
+    priority: Option<PriorityValue>,
+    tags: Option<Vec<String>>,
+}
+
+pub(crate) struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn me(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<UserNode>> {
+        let claims = require(ctx, TokenScope::Read)?;
+        Ok(UserNode::load(ctx, claims.id))
+    }
+
+    /// 个人 todo，按 position 排序
+    async fn todos(
+        &self,
+        ctx: &Context<'_>,
+        tags: Option<Vec<String>>,
+        overdue: Option<bool>,
+    ) -> async_graphql::Result<Vec<TodoNode>> {
+        let claims = require(ctx, TokenScope::Read)?;
+        let store = ctx.data_unchecked::<TodoStore>();
+        let todos = store.list(claims.id, &list_query(tags, overdue)).await;
+        Ok(todos.into_iter().map(TodoNode).collect())
+    }
+
+    /// 个人的或者所在清单里的 todo，不存在或者没有权限时返回 null
+    async fn todo(&self, ctx: &Context<'_>, id: ID) -> async_graphql::Result<Option<TodoNode>> {
+        let claims = require(ctx, TokenScope::Read)?;
+        let store = ctx.data_unchecked::<TodoStore>();
+        match store.get(claims.id, parse_id(&id)?).await {
+            Ok(todo) => Ok(Some(TodoNode(todo))),
+            Err(StoreError::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// 自己拥有或者加入的共享清单
+    async fn lists(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<ListNode>> {
+        let claims = require(ctx, TokenScope::Read)?;
+        let store = ctx.data_unchecked::<TodoStore>();
+        Ok(store
+            .lists
+            .visible(claims.id)
+            .into_iter()
+            .map(ListNode)
+            .collect())
+    }
+}
+
+pub(crate) struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn create_todo(
+        &self,
+        ctx: &Context<'_>,
+        input: CreateTodoInput,
+    ) -> async_graphql::Result<TodoNode> {
+        let claims = require(ctx, TokenScope::Write)?;
+        let store = ctx.data_unchecked::<TodoStore>();
+        let list_id = input.list_id.as_ref().map(parse_id).transpose()?;
+        let create = CreateTodo {
+            title: input.title,
+            due_at: input.due_at,
+            priority: input.priority.map(Priority::from).unwrap_or_default(),
+            tags: input.tags,
+        };
+        let todo = match list_id {
+            Some(list_id) => store.create_shared(claims.id, list_id, create).await?,
+            None => store.create(claims.id, create).await,
+        };
+        Ok(TodoNode(todo))
+    }
+
+    /// 传了 `version` 时和 If-Match 一样，版本对不上就拒绝修改
+    async fn update_todo(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+        input: UpdateTodoInput,
+        version: Option<u64>,
+    ) -> async_graphql::Result<TodoNode> {
+        let claims = require(ctx, TokenScope::Write)?;
+        let store = ctx.data_unchecked::<TodoStore>();
+        let id = parse_id(&id)?;
+        let update = UpdateTodo {
+            title: input.title,
+            completed: input.completed,
+            due_at: match input.due_at {
+                MaybeUndefined::Undefined => None,
+                MaybeUndefined::Null => Some(None),
+                MaybeUndefined::Value(due_at) => Some(Some(due_at)),
+            },
+            priority: input.priority.map(Priority::from),
+            tags: input.tags,
+        };
+        let if_match = version.map(|version| version_precondition(id, version));
+        let todo = store
+            .update(claims.id, id, update, if_match.as_ref())
+            .await?;
+        Ok(TodoNode(todo))
+    }
+
+    /// 删除的 todo 进回收站，返回被删除的 todo
+    async fn delete_todo(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+        version: Option<u64>,
+    ) -> async_graphql::Result<TodoNode> {
+        let claims = require(ctx, TokenScope::Write)?;
+        let store = ctx.data_unchecked::<TodoStore>();
+        let id = parse_id(&id)?;
+        let if_match = version.map(|version| version_precondition(id, version));
+        let todo = store.delete(claims.id, id, if_match.as_ref()).await?;
+        Ok(TodoNode(todo))
+    }
+
+    async fn restore_todo(&self, ctx: &Context<'_>, id: ID) -> async_graphql::Result<TodoNode> {
+        let claims = require(ctx, TokenScope::Write)?;
+        let store = ctx.data_unchecked::<TodoStore>();
+        let todo = store.restore(claims.id, parse_id(&id)?).await?;
+        Ok(TodoNode(todo))
+    }
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(name = "TodoEventKind")]
+pub(crate) enum EventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl From<TodoEventKind> for EventKind {
+    fn from(kind: TodoEventKind) -> Self {
+        match kind {
+            TodoEventKind::Created => EventKind::Created,
+            TodoEventKind::Updated => EventKind::Updated,
+            TodoEventKind::Deleted => EventKind::Deleted,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "TodoEvent")]
+pub(crate) struct EventNode {
+    kind: EventKind,
+    todo: TodoNode,
+}
+
+pub(crate) struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// 和 `/todos/events` 一样只推送有权限查看的 todo，落后太多的事件直接丢掉
+    async fn todo_events(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<impl Stream<Item = EventNode>> {
+        let user_id = require(ctx, TokenScope::Read)?.id;
+        let store = ctx.data_unchecked::<TodoStore>().clone();
+        let lists = store.lists.clone();
+        Ok(BroadcastStream::new(store.listen())
+            .filter_map(|event| event.ok())
+            .filter(move |event| event.visible_to(&lists, user_id))
+            .map(|event| EventNode {
+                kind: event.kind().into(),
+                todo: TodoNode(event.todo().clone()),
+            }))
+    }
+}
+
+pub(crate) async fn graphql_handler(
+    AnyScope(claims): AnyScope,
+    Extension(schema): Extension<TodoSchema>,
+    req: GraphQLRequest,
+) -> GraphQLResponse {
+    schema.execute(req.into_inner().data(claims)).await.into()
+}
+
+/// 订阅的 WebSocket 连接。浏览器建立 WebSocket 时不能带 Authorization 头，
+/// 所以也接受 `connection_init` 里的 `{"Authorization": "Bearer ..."}`
+pub(crate) async fn graphql_ws_handler(
+    Extension(schema): Extension<TodoSchema>,
+    Extension(tokens): Extension<TokenStore>,
+    Extension(users): Extension<UserStore>,
+    headers: HeaderMap,
+    protocol: GraphQLProtocol,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let header_claims = match headers.typed_get::<Authorization<Bearer>>() {
+        Some(Authorization(bearer)) => {
+            match authenticate_with(bearer.token(), Some(&tokens), Some(&users)) {
+                Ok(claims) => Some(claims),
+                Err(_) => return HttpError::Auth.into_response(),
+            }
+        }
+        None => None,
+    };
+
+    upgrade
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |stream| {
+            GraphQLWebSocket::new(stream, schema, protocol)
+                .on_connection_init(move |payload| async move {
+                    let mut data = Data::default();
+                    if let Some(claims) = header_claims {
+                        data.insert(claims);
+                        return Ok(data);
+                    }
+                    let token = payload
+                        .get("Authorization")
+                        .or_else(|| payload.get("authorization"))
+                        .and_then(|value| value.as_str())
+                        .map(|value| value.trim_start_matches("Bearer ").trim())
+                        .ok_or_else(|| error(StatusCode::UNAUTHORIZED, "unauthorized"))?;
+                    let claims = authenticate_with(token, Some(&tokens), Some(&users))
+                        .map_err(|_| error(StatusCode::UNAUTHORIZED, "unauthorized"))?;
+                    data.insert(claims);
+                    Ok(data)
+                })
+                .serve()
+        })
+}
@@ -1,14 +1,252 @@
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
+//! 一个用 axum 写的 todo 服务：JWT / personal access token 登录、共享清单、
+//! SSE 推送、GraphQL、导入导出，以及编译进二进制的静态文件。
+//!
+//! GraphiQL 页面编译进了二进制，但它用到的 GraphiQL 和 React 需要浏览器能访问 unpkg.com，
+//! 版本是固定的，页面的 CSP 只允许加载这几个文件。
+//!
+//! [`build_app`] 返回完整的路由，`examples/basic.rs` 负责启动服务，
+//! `tests/` 里的集成测试直接在进程内调用它。
+
+mod assets;
+mod auth;
+mod graphql;
+mod lists;
+mod metrics;
+mod middleware;
+mod todos;
+mod transfer;
+mod trash;
+mod webhooks;
+
+use assets::{
+    graphiql_handler, index_handler, livereload_handler, static_handler, watch_assets, AssetSource,
+};
+use auth::{
+    create_token_handler, login_handler, revoke_token_handler, tokens_handler, TokenStore,
+    UserStore,
+};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::{delete, get, patch, post};
+use axum::{Extension, Router};
+use graphql::{build_schema, graphql_handler, graphql_ws_handler};
+use lists::{
+    accept_invitation_handler, create_list_handler, create_list_todo_handler,
+    decline_invitation_handler, delete_list_handler, invitations_handler, invite_handler,
+    list_handler, list_todos_handler, lists_handler, remove_member_handler, update_member_handler,
+};
+use metrics::{metrics_handler, Metrics};
+use middleware::with_middleware;
+use notify::RecommendedWatcher;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
+use todos::{
+    batch_todos_handler, complete_all_handler, crate_todo_handler, delete_todo_handler,
+    delete_todos_handler, move_todo_handler, todo_events_handler, todo_handler, todos_handler,
+    update_todo_handler, TodoStore,
+};
+use tracing::info;
+use transfer::{export_handler, import_handler};
+use trash::{restore_handler, trash_handler, Trash, DEFAULT_RETENTION};
+use webhooks::{
+    create_webhook_handler, delete_webhook_handler, deliveries_handler, run_dispatcher,
+    webhooks_handler, WebhookStore,
+};
+
+pub use middleware::MiddlewareConfig;
+pub use webhooks::WebhookConfig;
+
+/// 服务运行需要的所有状态，`build_app` 会把它们作为 Extension 挂到路由上
+#[derive(Clone)]
+pub struct AppState {
+    todos: TodoStore,
+    users: UserStore,
+    tokens: TokenStore,
+    webhooks: WebhookStore,
+    metrics: Metrics,
+    assets: AssetSource,
+    config: MiddlewareConfig,
+}
+
+impl AppState {
+    /// 空的存储，静态文件使用编译进二进制的版本
+    pub fn new(config: MiddlewareConfig) -> Self {
+        let metrics = Metrics::new();
+        Self {
+            todos: TodoStore::new(Trash::default(), metrics.clone()),
+            users: UserStore::default(),
+            tokens: TokenStore::default(),
+            webhooks: WebhookStore::new(WebhookConfig::default()),
+            metrics,
+            assets: AssetSource::Embedded,
+            config,
+        }
+    }
+
+    /// 从环境变量读取各项配置和是否开启开发模式
+    pub fn from_env() -> Self {
+        const DAY: u64 = 24 * 60 * 60;
+        let retention_days =
+            middleware::env_or("TRASH_RETENTION_DAYS", DEFAULT_RETENTION.as_secs() / DAY);
+        let retention = retention_days
+            .checked_mul(DAY)
+            .expect("TRASH_RETENTION_DAYS is too large");
+        Self {
+            assets: AssetSource::from_env(),
+            ..Self::new(MiddlewareConfig::from_env())
+        }
+        .with_webhook_config(WebhookConfig::from_env())
+        .with_trash_retention(Duration::from_secs(retention))
+    }
+
+    /// 删除的 todo 在回收站里保留多久，默认 30 天。已有的 todo 保持不变
+    pub fn with_trash_retention(mut self, retention: Duration) -> Self {
+        self.todos.trash.set_retention(retention);
+        self
+    }
+
+    pub fn with_webhook_config(mut self, config: WebhookConfig) -> Self {
+        self.webhooks = WebhookStore::new(config);
+        self
+    }
+
+    /// 开发模式：从 `root` 目录读取静态文件，html 里会注入自动刷新的脚本
+    pub fn with_dev_assets(mut self, root: impl Into<PathBuf>) -> Self {
+        self.assets = AssetSource::disk(root.into());
+        self
+    }
+
+    /// 开发模式下监听 static 目录，返回的 watcher 要一直持有
+    pub fn watch_assets(&self) -> notify::Result<Option<RecommendedWatcher>> {
+        match &self.assets {
+            AssetSource::Disk(dev) => {
+                info!("Dev mode: serving {} from disk", dev.root.display());
+                watch_assets(dev).map(Some)
+            }
+            AssetSource::Embedded => Ok(None),
+        }
+    }
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self::new(MiddlewareConfig::default())
+    }
+}
+
+/// 组装所有路由和中间件。会启动投递 webhook 的后台任务，所以要在 tokio runtime 里调用
+pub fn build_app(state: AppState) -> Router {
+    let app = Router::new()
+        .route("/", get(index_handler))
+        .route(
+            "/todos",
+            get(todos_handler)
+                .post(crate_todo_handler)
+                .delete(delete_todos_handler),
+        )
+        .route("/todos/batch", post(batch_todos_handler))
+        .route("/todos/complete-all", post(complete_all_handler))
+        .route("/todos/export", get(export_handler))
+        .route("/todos/import", post(import_handler))
+        .route("/todos/events", get(todo_events_handler))
+        .route(
+            "/todos/:id",
+            get(todo_handler)
+                .patch(update_todo_handler)
+                .delete(delete_todo_handler),
+        )
+        .route("/todos/:id/move", post(move_todo_handler))
+        .route("/lists", get(lists_handler).post(create_list_handler))
+        .route("/lists/:id", get(list_handler).delete(delete_list_handler))
+        .route(
+            "/lists/:id/todos",
+            get(list_todos_handler).post(create_list_todo_handler),
+        )
+        .route("/lists/:id/invitations", post(invite_handler))
+        .route(
+            "/lists/:id/members/:user_id",
+            patch(update_member_handler).delete(remove_member_handler),
+        )
+        .route("/invitations", get(invitations_handler))
+        .route("/invitations/:id/accept", post(accept_invitation_handler))
+        .route("/invitations/:id/decline", post(decline_invitation_handler))
+        .route("/tokens", get(tokens_handler).post(create_token_handler))
+        .route("/tokens/:id", delete(revoke_token_handler))
+        .route("/trash", get(trash_handler))
+        .route("/trash/:id/restore", post(restore_handler))
+        .route(
+            "/webhooks",
+            get(webhooks_handler).post(create_webhook_handler),
+        )
+        .route("/webhooks/:id", delete(delete_webhook_handler))
+        .route("/webhooks/:id/deliveries", get(deliveries_handler))
+        .route("/graphql", get(graphiql_handler).post(graphql_handler))
+        .route("/graphql/ws", get(graphql_ws_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/login", post(login_handler))
+        .route("/__livereload", get(livereload_handler))
+        .fallback(static_handler);
+    tokio::spawn(state.todos.trash.purger());
+    tokio::spawn(run_dispatcher(
+        state.webhooks.clone(),
+        state.todos.lists.clone(),
+        state.todos.listen(),
+    ));
+    let schema = build_schema(state.todos.clone(), state.users.clone());
+
+    // 限流中间件需要从 extensions 里拿到 TokenStore 识别用户，所以这些放在最外层
+    with_middleware(app, &state.config)
+        .layer(Extension(state.todos))
+        .layer(Extension(state.users))
+        .layer(Extension(state.tokens))
+        .layer(Extension(state.webhooks))
+        .layer(Extension(state.metrics))
+        .layer(Extension(state.assets))
+        .layer(Extension(schema))
+}
+
+pub(crate) static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(Debug)]
+pub(crate) enum HttpError {
+    Auth,
+    BadRequest(&'static str),
+    Forbidden,
+    NotFound,
+    PreconditionFailed,
+    Internal,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl IntoResponse for HttpError {
+    fn into_response(self) -> axum::response::Response {
+        let (code, msg) = match self {
+            HttpError::Auth => (StatusCode::UNAUTHORIZED, "Unauthorized"),
+            HttpError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            HttpError::Forbidden => (StatusCode::FORBIDDEN, "Forbidden"),
+            HttpError::NotFound => (StatusCode::NOT_FOUND, "Not Found"),
+            HttpError::PreconditionFailed => {
+                (StatusCode::PRECONDITION_FAILED, "Precondition Failed")
+            }
+            HttpError::Internal => (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"),
+        };
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
+        (code, msg).into_response()
     }
 }
+
+pub(crate) fn get_epoch() -> usize {
+    use std::time::SystemTime;
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as usize
+}
+
+pub(crate) fn get_next_id() -> usize {
+    NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+}
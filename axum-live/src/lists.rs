@@ -0,0 +1,466 @@
+//! 共享清单：成员角色和邀请。
+
+use crate::auth::{Claims, UserStore};
+use crate::todos::{CreateTodo, ListTodosQuery, StoreError, Todo, TodoStore};
+use crate::{get_epoch, get_next_id, HttpError};
+use axum::extract::{Path, Query};
+use axum::headers::ETag;
+use axum::http::StatusCode;
+use axum::{Extension, Json, TypedHeader};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CreateList {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CreateInvitation {
+    pub email: String,
+    pub role: ListRole,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct UpdateMember {
+    pub role: ListRole,
+}
+
+/// 清单成员的角色，按权限从低到高排列
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ListRole {
+    Viewer,
+    Editor,
+    Owner,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ListMember {
+    pub user_id: usize,
+    pub role: ListRole,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct TodoList {
+    pub id: usize,
+    pub name: String,
+    pub owner_id: usize,
+    // 不包含 owner
+    pub members: Vec<ListMember>,
+    pub created_at: usize,
+}
+
+impl TodoList {
+    pub(crate) fn role_of(&self, user_id: usize) -> Option<ListRole> {
+        if self.owner_id == user_id {
+            return Some(ListRole::Owner);
+        }
+        self.members
+            .iter()
+            .find(|member| member.user_id == user_id)
+            .map(|member| member.role)
+    }
+
+    /// 非成员一律当作不存在，避免泄露清单 id
+    pub(crate) fn require(&self, user_id: usize, required: ListRole) -> Result<(), StoreError> {
+        match self.role_of(user_id) {
+            Some(role) if role >= required => Ok(()),
+            Some(_) => Err(StoreError::Forbidden),
+            None => Err(StoreError::NotFound),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Invitation {
+    pub id: usize,
+    pub list_id: usize,
+    pub list_name: String,
+    pub inviter_id: usize,
+    // 按邮箱邀请，对方还没注册也可以
+    pub email: String,
+    pub role: ListRole,
+    pub created_at: usize,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Lists {
+    lists: Vec<TodoList>,
+    invitations: Vec<Invitation>,
+}
+
+/// 共享清单和邀请。都是很短的同步操作，用标准库的锁，
+/// 这样 SSE 过滤事件时也能同步检查权限
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ListStore {
+    inner: Arc<std::sync::RwLock<Lists>>,
+}
+
+impl ListStore {
+    /// 用户当前在各个清单里的角色
+    pub(crate) fn access(&self, user_id: usize) -> Access {
+        let inner = self.inner.read().unwrap();
+        let roles = inner
+            .lists
+            .iter()
+            .filter_map(|list| list.role_of(user_id).map(|role| (list.id, role)))
+            .collect();
+        Access { user_id, roles }
+    }
+
+    pub(crate) fn can_read(&self, user_id: usize, todo: &Todo) -> bool {
+        match todo.list_id {
+            None => todo.user_id == user_id,
+            Some(list_id) => {
+                let inner = self.inner.read().unwrap();
+                inner
+                    .lists
+                    .iter()
+                    .any(|list| list.id == list_id && list.role_of(user_id).is_some())
+            }
+        }
+    }
+
+    pub(crate) fn create(&self, owner_id: usize, name: String) -> TodoList {
+        let list = TodoList {
+            id: get_next_id(),
+            name,
+            owner_id,
+            members: Vec::new(),
+            created_at: get_epoch(),
+        };
+        self.inner.write().unwrap().lists.push(list.clone());
+        list
+    }
+
+    pub(crate) fn visible(&self, user_id: usize) -> Vec<TodoList> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .lists
+            .iter()
+            .filter(|list| list.role_of(user_id).is_some())
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn get(&self, user_id: usize, list_id: usize) -> Result<TodoList, StoreError> {
+        let inner = self.inner.read().unwrap();
+        let list = find_list(&inner.lists, list_id)?;
+        list.require(user_id, ListRole::Viewer)?;
+        Ok(list.clone())
+    }
+
+    pub(crate) fn remove(&self, user_id: usize, list_id: usize) -> Result<TodoList, StoreError> {
+        let mut inner = self.inner.write().unwrap();
+        find_list(&inner.lists, list_id)?.require(user_id, ListRole::Owner)?;
+        inner
+            .invitations
+            .retain(|invitation| invitation.list_id != list_id);
+        let index = inner
+            .lists
+            .iter()
+            .position(|list| list.id == list_id)
+            .expect("list was just found");
+        Ok(inner.lists.remove(index))
+    }
+
+    /// 只有 owner 可以邀请，同一个邮箱重复邀请会覆盖之前的角色
+    pub(crate) fn invite(
+        &self,
+        user_id: usize,
+        list_id: usize,
+        email: &str,
+        role: ListRole,
+    ) -> Result<Invitation, StoreError> {
+        let mut inner = self.inner.write().unwrap();
+        let list = find_list(&inner.lists, list_id)?;
+        list.require(user_id, ListRole::Owner)?;
+
+        let email = email.trim().to_lowercase();
+        let invitation = Invitation {
+            id: get_next_id(),
+            list_id,
+            list_name: list.name.clone(),
+            inviter_id: user_id,
+            email: email.clone(),
+            role,
+            created_at: get_epoch(),
+        };
+        inner
+            .invitations
+            .retain(|invitation| !(invitation.list_id == list_id && invitation.email == email));
+        inner.invitations.push(invitation.clone());
+        Ok(invitation)
+    }
+
+    pub(crate) fn invitations_for(&self, email: &str) -> Vec<Invitation> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .invitations
+            .iter()
+            .filter(|invitation| invitation.email == email)
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn take_invitation(
+        inner: &mut Lists,
+        email: &str,
+        invitation_id: usize,
+    ) -> Result<Invitation, StoreError> {
+        let index = inner
+            .invitations
+            .iter()
+            .position(|invitation| invitation.id == invitation_id && invitation.email == email)
+            .ok_or(StoreError::NotFound)?;
+        Ok(inner.invitations.remove(index))
+    }
+
+    pub(crate) fn accept(
+        &self,
+        user_id: usize,
+        email: &str,
+        invitation_id: usize,
+    ) -> Result<TodoList, StoreError> {
+        let mut inner = self.inner.write().unwrap();
+        let invitation = Self::take_invitation(&mut inner, email, invitation_id)?;
+        let list = inner
+            .lists
+            .iter_mut()
+            .find(|list| list.id == invitation.list_id)
+            .ok_or(StoreError::NotFound)?;
+        if list.owner_id != user_id {
+            list.members.retain(|member| member.user_id != user_id);
+            list.members.push(ListMember {
+                user_id,
+                role: invitation.role,
+            });
+        }
+        Ok(list.clone())
+    }
+
+    pub(crate) fn decline(&self, email: &str, invitation_id: usize) -> Result<(), StoreError> {
+        let mut inner = self.inner.write().unwrap();
+        Self::take_invitation(&mut inner, email, invitation_id).map(|_| ())
+    }
+
+    pub(crate) fn set_role(
+        &self,
+        user_id: usize,
+        list_id: usize,
+        member_id: usize,
+        role: ListRole,
+    ) -> Result<TodoList, StoreError> {
+        let mut inner = self.inner.write().unwrap();
+        let list = find_list_mut(&mut inner.lists, list_id)?;
+        list.require(user_id, ListRole::Owner)?;
+        let member = list
+            .members
+            .iter_mut()
+            .find(|member| member.user_id == member_id)
+            .ok_or(StoreError::NotFound)?;
+        member.role = role;
+        Ok(list.clone())
+    }
+
+    /// owner 可以移除任何成员，成员也可以移除自己（退出清单）
+    pub(crate) fn remove_member(
+        &self,
+        user_id: usize,
+        list_id: usize,
+        member_id: usize,
+    ) -> Result<TodoList, StoreError> {
+        let mut inner = self.inner.write().unwrap();
+        let list = find_list_mut(&mut inner.lists, list_id)?;
+        if user_id != member_id {
+            list.require(user_id, ListRole::Owner)?;
+        }
+        let index = list
+            .members
+            .iter()
+            .position(|member| member.user_id == member_id)
+            .ok_or(StoreError::NotFound)?;
+        list.members.remove(index);
+        Ok(list.clone())
+    }
+}
+
+pub(crate) fn find_list(lists: &[TodoList], list_id: usize) -> Result<&TodoList, StoreError> {
+    lists
+        .iter()
+        .find(|list| list.id == list_id)
+        .ok_or(StoreError::NotFound)
+}
+
+pub(crate) fn find_list_mut(
+    lists: &mut [TodoList],
+    list_id: usize,
+) -> Result<&mut TodoList, StoreError> {
+    lists
+        .iter_mut()
+        .find(|list| list.id == list_id)
+        .ok_or(StoreError::NotFound)
+}
+
+/// todo 所在的范围：某个用户的个人 todo，或者某个共享清单
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Scope {
+    Personal(usize),
+    List(usize),
+}
+
+/// 一次操作开始时用户的权限快照
+#[derive(Debug, Default)]
+pub(crate) struct Access {
+    user_id: usize,
+    roles: HashMap<usize, ListRole>,
+}
+
+impl Access {
+    pub(crate) fn check(&self, todo: &Todo, required: ListRole) -> Result<(), StoreError> {
+        match todo.list_id {
+            None if todo.user_id == self.user_id => Ok(()),
+            None => Err(StoreError::NotFound),
+            Some(list_id) => match self.roles.get(&list_id) {
+                Some(role) if *role >= required => Ok(()),
+                Some(_) => Err(StoreError::Forbidden),
+                None => Err(StoreError::NotFound),
+            },
+        }
+    }
+}
+
+pub(crate) async fn create_list_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Json(list): Json<CreateList>,
+) -> Result<(StatusCode, Json<TodoList>), HttpError> {
+    let name = list.name.trim();
+    if name.is_empty() {
+        return Err(HttpError::BadRequest("list name is empty"));
+    }
+    let list = store.lists.create(claims.id, name.to_string());
+    Ok((StatusCode::CREATED, Json(list)))
+}
+
+pub(crate) async fn lists_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+) -> Result<Json<Vec<TodoList>>, HttpError> {
+    Ok(Json(store.lists.visible(claims.id)))
+}
+
+pub(crate) async fn list_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Path(list_id): Path<usize>,
+) -> Result<Json<TodoList>, HttpError> {
+    Ok(Json(store.lists.get(claims.id, list_id)?))
+}
+
+pub(crate) async fn delete_list_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Path(list_id): Path<usize>,
+) -> Result<StatusCode, HttpError> {
+    store.delete_list(claims.id, list_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_todos_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Path(list_id): Path<usize>,
+    Query(query): Query<ListTodosQuery>,
+) -> Result<Json<Vec<Todo>>, HttpError> {
+    Ok(Json(store.list_shared(claims.id, list_id, &query).await?))
+}
+
+pub(crate) async fn create_list_todo_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Path(list_id): Path<usize>,
+    Json(todo): Json<CreateTodo>,
+) -> Result<(StatusCode, TypedHeader<ETag>, Json<Todo>), HttpError> {
+    let todo = store.create_shared(claims.id, list_id, todo).await?;
+    Ok((StatusCode::CREATED, TypedHeader(todo.etag()), Json(todo)))
+}
+
+pub(crate) async fn invite_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Path(list_id): Path<usize>,
+    Json(invitation): Json<CreateInvitation>,
+) -> Result<(StatusCode, Json<Invitation>), HttpError> {
+    if invitation.role == ListRole::Owner {
+        return Err(HttpError::BadRequest("a list can only have one owner"));
+    }
+    if !invitation.email.contains('@') {
+        return Err(HttpError::BadRequest("invalid email"));
+    }
+    let invitation = store
+        .lists
+        .invite(claims.id, list_id, &invitation.email, invitation.role)?;
+    Ok((StatusCode::CREATED, Json(invitation)))
+}
+
+pub(crate) async fn update_member_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Path((list_id, member_id)): Path<(usize, usize)>,
+    Json(update): Json<UpdateMember>,
+) -> Result<Json<TodoList>, HttpError> {
+    if update.role == ListRole::Owner {
+        return Err(HttpError::BadRequest("a list can only have one owner"));
+    }
+    let list = store
+        .lists
+        .set_role(claims.id, list_id, member_id, update.role)?;
+    Ok(Json(list))
+}
+
+pub(crate) async fn remove_member_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Path((list_id, member_id)): Path<(usize, usize)>,
+) -> Result<StatusCode, HttpError> {
+    store.lists.remove_member(claims.id, list_id, member_id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// 当前用户收到的、还没处理的邀请
+pub(crate) async fn invitations_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Extension(users): Extension<UserStore>,
+) -> Result<Json<Vec<Invitation>>, HttpError> {
+    let user = users.get(claims.id).ok_or(HttpError::Auth)?;
+    Ok(Json(store.lists.invitations_for(&user.email)))
+}
+
+pub(crate) async fn accept_invitation_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Extension(users): Extension<UserStore>,
+    Path(invitation_id): Path<usize>,
+) -> Result<Json<TodoList>, HttpError> {
+    let user = users.get(claims.id).ok_or(HttpError::Auth)?;
+    Ok(Json(store.lists.accept(
+        claims.id,
+        &user.email,
+        invitation_id,
+    )?))
+}
+
+pub(crate) async fn decline_invitation_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Extension(users): Extension<UserStore>,
+    Path(invitation_id): Path<usize>,
+) -> Result<StatusCode, HttpError> {
+    let user = users.get(claims.id).ok_or(HttpError::Auth)?;
+    store.lists.decline(&user.email, invitation_id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,203 @@
+//! Prometheus 指标：请求耗时、认证失败、活跃用户和存储操作耗时，通过 `/metrics` 暴露。
+
+use crate::todos::TodoStore;
+use axum::extract::MatchedPath;
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+// 这段时间内成功认证过的用户算活跃用户
+const ACTIVE_USER_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// 认证失败的原因
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum AuthFailure {
+    // 没有 Authorization 头
+    Missing,
+    // 头或者 token 的格式不对
+    Malformed,
+    Expired,
+    // 签名不对，或者 token 不存在、已经吊销
+    Invalid,
+}
+
+impl AuthFailure {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuthFailure::Missing => "missing",
+            AuthFailure::Malformed => "malformed",
+            AuthFailure::Expired => "expired",
+            AuthFailure::Invalid => "invalid",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Metrics {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    registry: Registry,
+    http_requests: HistogramVec,
+    auth_failures: IntCounterVec,
+    active_users: IntGauge,
+    store_operations: HistogramVec,
+    todos: IntGauge,
+    trashed_todos: IntGauge,
+    last_seen: Mutex<HashMap<usize, Instant>>,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        let registry = Registry::new_custom(Some("axum_live".to_string()), None)
+            .expect("valid metrics prefix");
+        let http_requests = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request latency by route and status",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let auth_failures = IntCounterVec::new(
+            Opts::new("auth_failures_total", "Rejected credentials by reason"),
+            &["reason"],
+        )
+        .unwrap();
+        let active_users = IntGauge::new(
+            "active_users",
+            "Users that authenticated in the last 5 minutes",
+        )
+        .unwrap();
+        let store_operations = HistogramVec::new(
+            HistogramOpts::new(
+                "store_operation_duration_seconds",
+                "Todo store operation latency, including lock waits",
+            )
+            .buckets(vec![
+                0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5,
+            ]),
+            &["operation"],
+        )
+        .unwrap();
+        let todos = IntGauge::new("todos", "Todos currently stored").unwrap();
+        let trashed_todos = IntGauge::new("trashed_todos", "Todos in the trash").unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(auth_failures.clone())).unwrap();
+        registry.register(Box::new(active_users.clone())).unwrap();
+        registry
+            .register(Box::new(store_operations.clone()))
+            .unwrap();
+        registry.register(Box::new(todos.clone())).unwrap();
+        registry.register(Box::new(trashed_todos.clone())).unwrap();
+
+        Self {
+            inner: Arc::new(Inner {
+                registry,
+                http_requests,
+                auth_failures,
+                active_users,
+                store_operations,
+                todos,
+                trashed_todos,
+                last_seen: Default::default(),
+            }),
+        }
+    }
+
+    pub(crate) fn auth_failed(&self, reason: AuthFailure) {
+        self.inner
+            .auth_failures
+            .with_label_values(&[reason.as_str()])
+            .inc();
+    }
+
+    pub(crate) fn user_seen(&self, user_id: usize) {
+        let mut last_seen = self.inner.last_seen.lock().unwrap();
+        last_seen.insert(user_id, Instant::now());
+    }
+
+    /// drop 的时候记录耗时
+    pub(crate) fn store_timer(&self, operation: &str) -> HistogramTimer {
+        self.inner
+            .store_operations
+            .with_label_values(&[operation])
+            .start_timer()
+    }
+
+    fn update_active_users(&self) {
+        let mut last_seen = self.inner.last_seen.lock().unwrap();
+        let now = Instant::now();
+        last_seen.retain(|_, seen| now.duration_since(*seen) < ACTIVE_USER_WINDOW);
+        self.inner.active_users.set(last_seen.len() as i64);
+    }
+}
+
+/// 按路由模板统计请求，没匹配到路由的（静态文件和 404）都算在 fallback 里，避免标签太多
+pub(crate) async fn track_metrics<B>(req: Request<B>, next: Next<B>) -> Response {
+    let metrics = req.extensions().get::<Metrics>().cloned();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "fallback".to_string());
+    let method = req.method().clone();
+    let start = Instant::now();
+
+    let res = next.run(req).await;
+
+    if let Some(metrics) = metrics {
+        metrics
+            .inner
+            .http_requests
+            .with_label_values(&[method.as_str(), &route, res.status().as_str()])
+            .observe(start.elapsed().as_secs_f64());
+    }
+    res
+}
+
+pub(crate) async fn metrics_handler(
+    Extension(metrics): Extension<Metrics>,
+    Extension(store): Extension<TodoStore>,
+) -> Response {
+    metrics.update_active_users();
+    let (todos, trashed) = store.count().await;
+    metrics.inner.todos.set(todos as i64);
+    metrics.inner.trashed_todos.set(trashed as i64);
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&metrics.inner.registry.gather(), &mut body) {
+        warn!("failed to encode metrics: {:?}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        body,
+    )
+        .into_response()
+}
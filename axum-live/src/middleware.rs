@@ -0,0 +1,261 @@
+//! request id、访问日志、CORS、压缩、超时和限流等中间件。
+
+use crate::auth::peek_user_id;
+use crate::metrics::track_metrics;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, DefaultBodyLimit, State};
+use axum::headers::authorization::Bearer;
+use axum::headers::{Authorization, HeaderMapExt};
+use axum::http::{
+    header, Extensions, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode, Version,
+};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tower::ServiceBuilder;
+use tower_http::compression::predicate::{DefaultPredicate, Predicate};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::timeout::TimeoutLayer;
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tracing::{info_span, warn, Level};
+
+pub(crate) static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// 中间件的配置，都可以通过环境变量覆盖
+#[derive(Debug, Clone)]
+pub struct MiddlewareConfig {
+    /// 每分钟允许的请求数，登录用户按用户算，其余按 IP 算，0 表示不限流
+    pub user_rate_limit: u32,
+    pub ip_rate_limit: u32,
+    pub cors_origins: Vec<HeaderValue>,
+    pub request_timeout: Duration,
+    pub body_limit: usize,
+}
+
+impl Default for MiddlewareConfig {
+    fn default() -> Self {
+        Self {
+            user_rate_limit: 600,
+            ip_rate_limit: 300,
+            cors_origins: vec![
+                HeaderValue::from_static("http://localhost:8000"),
+                HeaderValue::from_static("http://127.0.0.1:8000"),
+            ],
+            request_timeout: Duration::from_secs(30),
+            body_limit: 1024 * 1024,
+        }
+    }
+}
+
+impl MiddlewareConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let cors_origins = match std::env::var("CORS_ALLOW_ORIGINS") {
+            Ok(origins) => origins
+                .split(',')
+                .filter_map(|origin| origin.trim().parse().ok())
+                .collect(),
+            Err(_) => default.cors_origins,
+        };
+        Self {
+            user_rate_limit: env_or("RATE_LIMIT_PER_USER", default.user_rate_limit),
+            ip_rate_limit: env_or("RATE_LIMIT_PER_IP", default.ip_rate_limit),
+            cors_origins,
+            request_timeout: Duration::from_secs(env_or(
+                "REQUEST_TIMEOUT_SECS",
+                default.request_timeout.as_secs(),
+            )),
+            body_limit: env_or("BODY_LIMIT_BYTES", default.body_limit),
+        }
+    }
+}
+
+pub(crate) fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum RateLimitKey {
+    User(usize),
+    Ip(IpAddr),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// 令牌桶限流，容量是每分钟的请求数，按秒匀速补充
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    user_limit: u32,
+    ip_limit: u32,
+    buckets: std::sync::Mutex<HashMap<RateLimitKey, Bucket>>,
+}
+
+impl RateLimiter {
+    // 桶太多时清理一下已经补满的，避免无限增长
+    const MAX_BUCKETS: usize = 10_000;
+
+    pub(crate) fn new(user_limit: u32, ip_limit: u32) -> Self {
+        Self {
+            user_limit,
+            ip_limit,
+            buckets: Default::default(),
+        }
+    }
+
+    /// 放行返回 Ok，否则返回需要等待的时间
+    pub(crate) fn check(&self, key: RateLimitKey) -> Result<(), Duration> {
+        let capacity = match key {
+            RateLimitKey::User(_) => self.user_limit,
+            RateLimitKey::Ip(_) => self.ip_limit,
+        } as f64;
+        // 不限流，也避免下面除以 0
+        if capacity == 0.0 {
+            return Ok(());
+        }
+        let per_sec = capacity / 60.0;
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= Self::MAX_BUCKETS {
+            buckets.retain(|key, bucket| {
+                let capacity = match key {
+                    RateLimitKey::User(_) => self.user_limit,
+                    RateLimitKey::Ip(_) => self.ip_limit,
+                } as f64;
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * capacity / 60.0 < capacity
+            });
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+}
+
+pub(crate) async fn rate_limit<B>(
+    State(limiter): State<Arc<RateLimiter>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    // 这里只用来区分调用方，token 无效的请求后面的 Claims 会拒绝掉
+    let user = req
+        .headers()
+        .typed_get::<Authorization<Bearer>>()
+        .and_then(|bearer| peek_user_id(bearer.token(), req.extensions()));
+    let key = match user {
+        Some(user_id) => RateLimitKey::User(user_id),
+        None => {
+            let ip = req
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip())
+                .unwrap_or(IpAddr::from([0, 0, 0, 0]));
+            RateLimitKey::Ip(ip)
+        }
+    };
+
+    match limiter.check(key) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => {
+            warn!(?key, "rate limited");
+            let retry_after = retry_after.as_secs().max(1).to_string();
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after)],
+                "Too Many Requests",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// 请求经过的中间件，从外到里依次是：
+/// request id -> 访问日志 -> 指标 -> CORS -> 压缩 -> 超时 -> 请求体大小 -> 限流
+pub(crate) fn with_middleware(app: Router, config: &MiddlewareConfig) -> Router {
+    let cors = CorsLayer::new()
+        .allow_origin(AllowOrigin::list(config.cors_origins.clone()))
+        .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::IF_MATCH,
+            header::IF_NONE_MATCH,
+            HeaderName::from_static("last-event-id"),
+        ])
+        .expose_headers([header::ETAG, REQUEST_ID_HEADER.clone()])
+        // CORS 会覆盖响应里已有的 Vary，压缩和预压缩的文件要按 Accept-Encoding 区分缓存
+        .vary([
+            header::ORIGIN,
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            header::ACCEPT_ENCODING,
+        ]);
+
+    // 已经分段返回的内容不能再压缩
+    let compression = CompressionLayer::new().compress_when(DefaultPredicate::new().and(
+        |_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions| {
+            !headers.contains_key(header::CONTENT_RANGE)
+        },
+    ));
+
+    let limiter = Arc::new(RateLimiter::new(
+        config.user_rate_limit,
+        config.ip_rate_limit,
+    ));
+
+    app.layer(
+        ServiceBuilder::new()
+            .layer(SetRequestIdLayer::new(
+                REQUEST_ID_HEADER.clone(),
+                MakeRequestUuid,
+            ))
+            .layer(
+                TraceLayer::new_for_http()
+                    .make_span_with(|req: &Request<Body>| {
+                        let request_id = req
+                            .headers()
+                            .get(&REQUEST_ID_HEADER)
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or_default();
+                        info_span!(
+                            "request",
+                            method = %req.method(),
+                            uri = %req.uri(),
+                            request_id,
+                        )
+                    })
+                    .on_response(DefaultOnResponse::new().level(Level::INFO)),
+            )
+            .layer(middleware::from_fn(track_metrics))
+            .layer(PropagateRequestIdLayer::new(REQUEST_ID_HEADER.clone()))
+            .layer(cors)
+            .layer(compression)
+            .layer(TimeoutLayer::new(config.request_timeout))
+            .layer(DefaultBodyLimit::max(config.body_limit))
+            .layer(middleware::from_fn_with_state(limiter, rate_limit)),
+    )
+}
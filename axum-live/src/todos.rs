@@ -0,0 +1,1014 @@
+//! todo 的存储、变更事件和对应的接口。
+
+use crate::auth::Claims;
+use crate::lists::{Access, ListRole, ListStore, Scope, TodoList};
+use crate::metrics::Metrics;
+use crate::transfer::ImportRow;
+use crate::trash::Trash;
+use crate::{get_epoch, get_next_id, HttpError};
+use axum::extract::{Path, Query};
+use axum::headers::{ETag, HeaderMapExt, IfMatch, IfNoneMatch};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json, TypedHeader};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::{broadcast, RwLock};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Todo {
+    pub id: usize,
+    // 创建者，个人 todo 只有创建者能看到
+    pub user_id: usize,
+    // 属于某个共享清单时，清单成员按角色访问
+    pub list_id: Option<usize>,
+    pub title: String,
+    pub completed: bool,
+    // 每次修改加一，用作 ETag
+    pub version: u64,
+    pub created_at: usize,
+    pub updated_at: usize,
+    pub due_at: Option<usize>,
+    pub priority: Priority,
+    pub tags: Vec<String>,
+    // 用户自己拖拽排出来的顺序，列表按它升序返回
+    pub position: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Todo {
+    pub(crate) fn new(
+        user_id: usize,
+        list_id: Option<usize>,
+        todo: CreateTodo,
+        position: usize,
+    ) -> Self {
+        let now = get_epoch();
+        Self {
+            id: get_next_id(),
+            user_id,
+            list_id,
+            title: todo.title,
+            completed: false,
+            version: 1,
+            created_at: now,
+            updated_at: now,
+            due_at: todo.due_at,
+            priority: todo.priority,
+            tags: normalize_tags(todo.tags),
+            position,
+        }
+    }
+
+    pub(crate) fn scope(&self) -> Scope {
+        match self.list_id {
+            Some(list_id) => Scope::List(list_id),
+            None => Scope::Personal(self.user_id),
+        }
+    }
+
+    /// 每次修改后调用，更新版本号和修改时间
+    pub(crate) fn touch(&mut self) {
+        self.version += 1;
+        self.updated_at = get_epoch();
+    }
+
+    pub(crate) fn is_overdue(&self, now: usize) -> bool {
+        !self.completed && self.due_at.is_some_and(|due_at| due_at < now)
+    }
+
+    pub(crate) fn etag(&self) -> ETag {
+        format!("\"{}-{}\"", self.id, self.version)
+            .parse()
+            .expect("numeric etag is always valid")
+    }
+}
+
+/// 列表的 ETag 由其中每个 todo 的 id 和版本决定
+pub(crate) fn list_etag(todos: &[Todo]) -> ETag {
+    let mut hasher = DefaultHasher::new();
+    for todo in todos {
+        (todo.id, todo.version).hash(&mut hasher);
+    }
+    format!("\"list-{:x}\"", hasher.finish())
+        .parse()
+        .expect("hex etag is always valid")
+}
+
+/// 去掉首尾空白、空标签和重复的标签
+pub(crate) fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = tag.trim();
+        if !tag.is_empty() && !normalized.iter().any(|t| t == tag) {
+            normalized.push(tag.to_string());
+        }
+    }
+    normalized
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CreateTodo {
+    pub title: String,
+    #[serde(default)]
+    pub due_at: Option<usize>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct UpdateTodo {
+    pub title: Option<String>,
+    pub completed: Option<bool>,
+    // 不传表示不修改，传 null 表示清除截止时间
+    #[serde(
+        default,
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub due_at: Option<Option<usize>>,
+    pub priority: Option<Priority>,
+    pub tags: Option<Vec<String>>,
+}
+
+/// 配合 `#[serde(default)]` 区分字段缺失和字段为 null
+pub(crate) fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ListTodosQuery {
+    // 逗号分隔，需要同时包含所有标签
+    pub tag: Option<String>,
+    pub overdue: Option<bool>,
+}
+
+impl ListTodosQuery {
+    pub(crate) fn matches(&self, todo: &Todo, now: usize) -> bool {
+        let has_tags = self.tag.as_deref().is_none_or(|tags| {
+            tags.split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .all(|tag| todo.tags.iter().any(|t| t == tag))
+        });
+        let overdue = self
+            .overdue
+            .is_none_or(|overdue| todo.is_overdue(now) == overdue);
+        has_tags && overdue
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct MoveTodo {
+    // 移动到用户列表中的第几个，从 0 开始，超出范围则放到最后
+    pub position: usize,
+}
+
+// 一次批量请求最多包含的操作数
+pub(crate) const MAX_BATCH_OPERATIONS: usize = 100;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum BatchOperation {
+    Create {
+        #[serde(flatten)]
+        todo: CreateTodo,
+    },
+    // 带上 version 时和 If-Match 一样，版本不对就失败
+    Update {
+        id: usize,
+        version: Option<u64>,
+        #[serde(flatten)]
+        changes: UpdateTodo,
+    },
+    Delete {
+        id: usize,
+        version: Option<u64>,
+    },
+}
+
+pub(crate) fn version_precondition(id: usize, version: u64) -> IfMatch {
+    let todo = Todo {
+        id,
+        version,
+        ..Default::default()
+    };
+    IfMatch::from(todo.etag())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BatchRequest {
+    pub operations: Vec<BatchOperation>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BatchResult {
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub todo: Option<Todo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BatchResult {
+    pub(crate) fn ok(status: StatusCode, todo: Todo) -> Self {
+        Self {
+            status: status.as_u16(),
+            todo: Some(todo),
+            error: None,
+        }
+    }
+
+    pub(crate) fn error(status: StatusCode, error: &str) -> Self {
+        Self {
+            status: status.as_u16(),
+            todo: None,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BatchResponse {
+    pub committed: bool,
+    pub results: Vec<BatchResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DeleteTodosQuery {
+    pub completed: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum StoreError {
+    NotFound,
+    // 能看到但是没有修改权限
+    Forbidden,
+    // If-Match 和当前版本对不上
+    Conflict,
+}
+
+impl StoreError {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            StoreError::NotFound => StatusCode::NOT_FOUND,
+            StoreError::Forbidden => StatusCode::FORBIDDEN,
+            StoreError::Conflict => StatusCode::PRECONDITION_FAILED,
+        }
+    }
+
+    pub(crate) fn message(&self) -> &'static str {
+        match self {
+            StoreError::NotFound => "not found",
+            StoreError::Forbidden => "permission denied",
+            StoreError::Conflict => "todo has been modified",
+        }
+    }
+}
+
+impl From<StoreError> for HttpError {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::NotFound => HttpError::NotFound,
+            StoreError::Forbidden => HttpError::Forbidden,
+            StoreError::Conflict => HttpError::PreconditionFailed,
+        }
+    }
+}
+
+pub(crate) fn find_mut<'a>(
+    items: &'a mut [Todo],
+    access: &Access,
+    id: usize,
+    if_match: Option<&IfMatch>,
+) -> Result<&'a mut Todo, StoreError> {
+    let todo = items
+        .iter_mut()
+        .find(|todo| todo.id == id)
+        .ok_or(StoreError::NotFound)?;
+    access.check(todo, ListRole::Editor)?;
+    match if_match {
+        Some(if_match) if !if_match.precondition_passes(&todo.etag()) => Err(StoreError::Conflict),
+        _ => Ok(todo),
+    }
+}
+
+pub(crate) fn apply_update(
+    items: &mut [Todo],
+    access: &Access,
+    id: usize,
+    update: UpdateTodo,
+    if_match: Option<&IfMatch>,
+) -> Result<Todo, StoreError> {
+    let todo = find_mut(items, access, id, if_match)?;
+    if let Some(title) = update.title {
+        todo.title = title;
+    }
+    if let Some(completed) = update.completed {
+        todo.completed = completed;
+    }
+    if let Some(due_at) = update.due_at {
+        todo.due_at = due_at;
+    }
+    if let Some(priority) = update.priority {
+        todo.priority = priority;
+    }
+    if let Some(tags) = update.tags {
+        todo.tags = normalize_tags(tags);
+    }
+    todo.touch();
+    Ok(todo.clone())
+}
+
+/// 新建的 todo 放在所在范围的最后
+pub(crate) fn next_position(items: &[Todo], scope: Scope) -> usize {
+    items
+        .iter()
+        .filter(|todo| todo.scope() == scope)
+        .map(|todo| todo.position + 1)
+        .max()
+        .unwrap_or(0)
+}
+
+pub(crate) fn apply_delete(
+    items: &mut Vec<Todo>,
+    access: &Access,
+    id: usize,
+    if_match: Option<&IfMatch>,
+) -> Result<Todo, StoreError> {
+    find_mut(items, access, id, if_match)?;
+    let index = items
+        .iter()
+        .position(|todo| todo.id == id)
+        .expect("todo was just found");
+    Ok(items.remove(index))
+}
+
+// 断线重连时可以补发的最近事件数
+pub(crate) const EVENT_REPLAY_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TodoEventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl TodoEventKind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            TodoEventKind::Created => "created",
+            TodoEventKind::Updated => "updated",
+            TodoEventKind::Deleted => "deleted",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TodoEvent {
+    id: u64,
+    kind: TodoEventKind,
+    todo: Todo,
+    // 随清单一起删除的 todo 带上删除前的清单，订阅者收到时清单已经不在了
+    removed_list: Option<Arc<TodoList>>,
+}
+
+impl TodoEvent {
+    pub(crate) fn kind(&self) -> TodoEventKind {
+        self.kind
+    }
+
+    pub(crate) fn todo(&self) -> &Todo {
+        &self.todo
+    }
+
+    /// 用户能否看到这个事件，清单已经删除的按删除前的成员判断
+    pub(crate) fn visible_to(&self, lists: &ListStore, user_id: usize) -> bool {
+        match &self.removed_list {
+            Some(list) => list.role_of(user_id).is_some(),
+            None => lists.can_read(user_id, &self.todo),
+        }
+    }
+
+    pub(crate) fn to_sse(&self) -> Result<Event, serde_json::Error> {
+        Event::default()
+            .id(self.id.to_string())
+            .event(self.kind.as_str())
+            .json_data(&self.todo)
+    }
+}
+
+/// 最近的事件，供 `Last-Event-ID` 续传
+#[derive(Debug, Default)]
+pub(crate) struct EventLog {
+    next_id: u64,
+    events: VecDeque<TodoEvent>,
+}
+
+/// 订阅时拿到的补发事件和后续实时事件。
+/// `missed` 为 true 表示请求续传的位置已经不在缓冲区里了，客户端需要重新拉取全量
+pub(crate) struct TodoSubscription {
+    missed: bool,
+    replay: Vec<TodoEvent>,
+    live: broadcast::Receiver<TodoEvent>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TodoStore {
+    items: Arc<RwLock<Vec<Todo>>>,
+    events: broadcast::Sender<TodoEvent>,
+    log: Arc<std::sync::Mutex<EventLog>>,
+    // 共享清单里的 todo 按成员角色做权限检查
+    pub(crate) lists: ListStore,
+    // 删除的 todo 先放进回收站
+    pub(crate) trash: Trash,
+    metrics: Metrics,
+}
+
+impl TodoStore {
+    pub(crate) fn new(trash: Trash, metrics: Metrics) -> Self {
+        let (events, _) = broadcast::channel(EVENT_REPLAY_CAPACITY);
+        Self {
+            items: Default::default(),
+            events,
+            log: Default::default(),
+            lists: Default::default(),
+            trash,
+            metrics,
+        }
+    }
+
+    /// 用户的个人 todo
+    pub(crate) async fn list(&self, user_id: usize, query: &ListTodosQuery) -> Vec<Todo> {
+        self.list_scope(Scope::Personal(user_id), query).await
+    }
+
+    /// 共享清单里的 todo，成员都可以查看
+    pub(crate) async fn list_shared(
+        &self,
+        user_id: usize,
+        list_id: usize,
+        query: &ListTodosQuery,
+    ) -> Result<Vec<Todo>, StoreError> {
+        self.lists.get(user_id, list_id)?;
+        Ok(self.list_scope(Scope::List(list_id), query).await)
+    }
+
+    pub(crate) async fn list_scope(&self, scope: Scope, query: &ListTodosQuery) -> Vec<Todo> {
+        let _timer = self.metrics.store_timer("list");
+        let now = get_epoch();
+        let items = self.items.read().await;
+        let mut todos: Vec<Todo> = items
+            .iter()
+            .filter(|todo| todo.scope() == scope && query.matches(todo, now))
+            .cloned()
+            .collect();
+        todos.sort_by_key(|todo| todo.position);
+        todos
+    }
+
+    /// 按列表顺序分页读取个人 todo，`after` 是上一页最后一个的 `(position, id)`
+    pub(crate) async fn page(
+        &self,
+        user_id: usize,
+        after: Option<(usize, usize)>,
+        limit: usize,
+    ) -> Vec<Todo> {
+        let _timer = self.metrics.store_timer("page");
+        let scope = Scope::Personal(user_id);
+        let items = self.items.read().await;
+        let mut todos: Vec<&Todo> = items
+            .iter()
+            .filter(|todo| todo.scope() == scope)
+            .filter(|todo| after.is_none_or(|after| (todo.position, todo.id) > after))
+            .collect();
+        todos.sort_by_key(|todo| (todo.position, todo.id));
+        todos.into_iter().take(limit).cloned().collect()
+    }
+
+    pub(crate) async fn get(&self, user_id: usize, id: usize) -> Result<Todo, StoreError> {
+        let _timer = self.metrics.store_timer("get");
+        let access = self.lists.access(user_id);
+        let items = self.items.read().await;
+        let todo = items
+            .iter()
+            .find(|todo| todo.id == id)
+            .ok_or(StoreError::NotFound)?;
+        access.check(todo, ListRole::Viewer)?;
+        Ok(todo.clone())
+    }
+
+    pub(crate) async fn create(&self, user_id: usize, todo: CreateTodo) -> Todo {
+        let _timer = self.metrics.store_timer("create");
+        let mut items = self.items.write().await;
+        let position = next_position(&items, Scope::Personal(user_id));
+        let todo = Todo::new(user_id, None, todo, position);
+        items.push(todo.clone());
+        self.publish(TodoEventKind::Created, &todo);
+        todo
+    }
+
+    /// 批量导入个人 todo，按顺序放到最后
+    pub(crate) async fn import(&self, user_id: usize, rows: Vec<ImportRow>) -> Vec<Todo> {
+        let _timer = self.metrics.store_timer("import");
+        let mut items = self.items.write().await;
+        let mut imported = Vec::with_capacity(rows.len());
+        for row in rows {
+            let position = next_position(&items, Scope::Personal(user_id));
+            let create = CreateTodo {
+                title: row.title,
+                due_at: row.due_at,
+                priority: row.priority,
+                tags: row.tags,
+            };
+            let mut todo = Todo::new(user_id, None, create, position);
+            todo.completed = row.completed;
+            items.push(todo.clone());
+            self.publish(TodoEventKind::Created, &todo);
+            imported.push(todo);
+        }
+        imported
+    }
+
+    /// 在共享清单里新建，需要 editor 及以上权限
+    pub(crate) async fn create_shared(
+        &self,
+        user_id: usize,
+        list_id: usize,
+        todo: CreateTodo,
+    ) -> Result<Todo, StoreError> {
+        let _timer = self.metrics.store_timer("create");
+        self.lists
+            .get(user_id, list_id)?
+            .require(user_id, ListRole::Editor)?;
+        let mut items = self.items.write().await;
+        let position = next_position(&items, Scope::List(list_id));
+        let todo = Todo::new(user_id, Some(list_id), todo, position);
+        items.push(todo.clone());
+        self.publish(TodoEventKind::Created, &todo);
+        Ok(todo)
+    }
+
+    /// 删除清单和其中所有的 todo，只有 owner 可以操作
+    pub(crate) async fn delete_list(
+        &self,
+        user_id: usize,
+        list_id: usize,
+    ) -> Result<TodoList, StoreError> {
+        let _timer = self.metrics.store_timer("delete_list");
+        let mut items = self.items.write().await;
+        // 清单已经不在了，里面的 todo 没法恢复，不放进回收站
+        let list = self.lists.remove(user_id, list_id)?;
+        let (deleted, kept) = items
+            .drain(..)
+            .partition(|todo| todo.list_id == Some(list_id));
+        *items = kept;
+        let removed_list = Arc::new(list.clone());
+        for todo in &deleted {
+            self.publish_event(TodoEventKind::Deleted, todo, Some(removed_list.clone()));
+        }
+        Ok(list)
+    }
+
+    pub(crate) async fn update(
+        &self,
+        user_id: usize,
+        id: usize,
+        update: UpdateTodo,
+        if_match: Option<&IfMatch>,
+    ) -> Result<Todo, StoreError> {
+        let _timer = self.metrics.store_timer("update");
+        let access = self.lists.access(user_id);
+        let mut items = self.items.write().await;
+        let todo = apply_update(&mut items, &access, id, update, if_match)?;
+        self.publish(TodoEventKind::Updated, &todo);
+        Ok(todo)
+    }
+
+    pub(crate) async fn delete(
+        &self,
+        user_id: usize,
+        id: usize,
+        if_match: Option<&IfMatch>,
+    ) -> Result<Todo, StoreError> {
+        let _timer = self.metrics.store_timer("delete");
+        let access = self.lists.access(user_id);
+        let mut items = self.items.write().await;
+        let todo = apply_delete(&mut items, &access, id, if_match)?;
+        self.publish(TodoEventKind::Deleted, &todo);
+        self.trash.put([todo.clone()]);
+        Ok(todo)
+    }
+
+    /// 从回收站恢复，放到原来所在清单的最后
+    pub(crate) async fn restore(&self, user_id: usize, id: usize) -> Result<Todo, StoreError> {
+        let _timer = self.metrics.store_timer("restore");
+        let access = self.lists.access(user_id);
+        let mut items = self.items.write().await;
+        let mut todo = self.trash.take(&access, id)?;
+        todo.position = next_position(&items, todo.scope());
+        todo.touch();
+        items.push(todo.clone());
+        self.publish(TodoEventKind::Created, &todo);
+        Ok(todo)
+    }
+
+    /// 把用户所有未完成的个人 todo 标记为完成，返回有变化的
+    pub(crate) async fn complete_all(&self, user_id: usize) -> Vec<Todo> {
+        let _timer = self.metrics.store_timer("complete_all");
+        let mut items = self.items.write().await;
+        let mut updated = Vec::new();
+        for todo in items
+            .iter_mut()
+            .filter(|todo| todo.scope() == Scope::Personal(user_id) && !todo.completed)
+        {
+            todo.completed = true;
+            todo.touch();
+            self.publish(TodoEventKind::Updated, todo);
+            updated.push(todo.clone());
+        }
+        updated
+    }
+
+    /// 把 todo 移动到所在范围（个人或者清单）中的 `position` 处，其他 todo 依次顺延，
+    /// 所有位置有变化的 todo 都会更新版本并发布事件
+    pub(crate) async fn move_to(
+        &self,
+        user_id: usize,
+        id: usize,
+        position: usize,
+        if_match: Option<&IfMatch>,
+    ) -> Result<Todo, StoreError> {
+        let _timer = self.metrics.store_timer("move");
+        let access = self.lists.access(user_id);
+        let mut items = self.items.write().await;
+        let scope = find_mut(&mut items, &access, id, if_match)?.scope();
+
+        let mut order: Vec<(usize, usize)> = items
+            .iter()
+            .filter(|todo| todo.scope() == scope)
+            .map(|todo| (todo.position, todo.id))
+            .collect();
+        order.sort();
+        let mut order: Vec<usize> = order.into_iter().map(|(_, id)| id).collect();
+        order.retain(|todo_id| *todo_id != id);
+        order.insert(position.min(order.len()), id);
+
+        for todo in items.iter_mut().filter(|todo| todo.scope() == scope) {
+            let position = order
+                .iter()
+                .position(|todo_id| *todo_id == todo.id)
+                .expect("all todos in scope are in order");
+            if todo.position != position {
+                todo.position = position;
+                todo.touch();
+                self.publish(TodoEventKind::Updated, todo);
+            }
+        }
+        let todo = items
+            .iter()
+            .find(|todo| todo.id == id)
+            .cloned()
+            .expect("todo was just found");
+        Ok(todo)
+    }
+
+    /// 删除用户所有完成状态为 `completed` 的个人 todo，返回被删掉的
+    pub(crate) async fn delete_where(&self, user_id: usize, completed: bool) -> Vec<Todo> {
+        let _timer = self.metrics.store_timer("delete_where");
+        let mut items = self.items.write().await;
+        let (deleted, kept) = items.drain(..).partition(|todo| {
+            todo.scope() == Scope::Personal(user_id) && todo.completed == completed
+        });
+        *items = kept;
+        for todo in &deleted {
+            self.publish(TodoEventKind::Deleted, todo);
+        }
+        self.trash.put(deleted.clone());
+        deleted
+    }
+
+    /// 在一份副本上依次执行所有操作，全部成功才替换原数据并发布事件，
+    /// 任何一个失败则整体回滚。新建的是个人 todo，修改和删除按权限检查
+    pub(crate) async fn apply_batch(
+        &self,
+        user_id: usize,
+        operations: Vec<BatchOperation>,
+    ) -> (bool, Vec<BatchResult>) {
+        let _timer = self.metrics.store_timer("batch");
+        let access = self.lists.access(user_id);
+        let mut items = self.items.write().await;
+        let mut staged = items.clone();
+        let mut events = Vec::new();
+        let mut results = Vec::new();
+        for operation in operations {
+            let applied = match operation {
+                BatchOperation::Create { todo } => {
+                    let position = next_position(&staged, Scope::Personal(user_id));
+                    let todo = Todo::new(user_id, None, todo, position);
+                    staged.push(todo.clone());
+                    Ok((TodoEventKind::Created, StatusCode::CREATED, todo))
+                }
+                BatchOperation::Update {
+                    id,
+                    version,
+                    changes,
+                } => {
+                    let if_match = version.map(|version| version_precondition(id, version));
+                    apply_update(&mut staged, &access, id, changes, if_match.as_ref())
+                        .map(|todo| (TodoEventKind::Updated, StatusCode::OK, todo))
+                }
+                BatchOperation::Delete { id, version } => {
+                    let if_match = version.map(|version| version_precondition(id, version));
+                    apply_delete(&mut staged, &access, id, if_match.as_ref())
+                        .map(|todo| (TodoEventKind::Deleted, StatusCode::OK, todo))
+                }
+            };
+            match applied {
+                Ok((kind, status, todo)) => {
+                    results.push(BatchResult::ok(status, todo.clone()));
+                    events.push((kind, todo));
+                }
+                Err(e) => results.push(BatchResult::error(e.status(), e.message())),
+            }
+        }
+
+        let committed = results.iter().all(|result| result.error.is_none());
+        if committed {
+            *items = staged;
+            for (kind, todo) in &events {
+                self.publish(*kind, todo);
+            }
+            self.trash.put(
+                events
+                    .into_iter()
+                    .filter(|(kind, _)| *kind == TodoEventKind::Deleted)
+                    .map(|(_, todo)| todo),
+            );
+        } else {
+            // 本身成功但因为其他操作失败而被回滚的
+            for result in results.iter_mut().filter(|result| result.error.is_none()) {
+                *result = BatchResult::error(StatusCode::FAILED_DEPENDENCY, "rolled back");
+            }
+        }
+        (committed, results)
+    }
+
+    /// 写操作都持有 items 的写锁调用，保证事件 id 和修改顺序一致
+    pub(crate) fn publish(&self, kind: TodoEventKind, todo: &Todo) {
+        self.publish_event(kind, todo, None);
+    }
+
+    fn publish_event(&self, kind: TodoEventKind, todo: &Todo, removed_list: Option<Arc<TodoList>>) {
+        let mut log = self.log.lock().unwrap();
+        log.next_id += 1;
+        let event = TodoEvent {
+            id: log.next_id,
+            kind,
+            todo: todo.clone(),
+            removed_list,
+        };
+        if log.events.len() == EVENT_REPLAY_CAPACITY {
+            log.events.pop_front();
+        }
+        log.events.push_back(event.clone());
+        // 没有订阅者时发送会失败，忽略即可
+        let _ = self.events.send(event);
+    }
+
+    /// 现有的 todo 数量和回收站里的数量
+    pub(crate) async fn count(&self) -> (usize, usize) {
+        let items = self.items.read().await;
+        (items.len(), self.trash.len())
+    }
+
+    /// 只要之后的实时事件，不需要补发
+    pub(crate) fn listen(&self) -> broadcast::Receiver<TodoEvent> {
+        self.events.subscribe()
+    }
+
+    pub(crate) fn subscribe(&self, last_event_id: Option<u64>) -> TodoSubscription {
+        // 拿着 log 的锁订阅，保证补发和实时事件之间不会漏掉或重复
+        let log = self.log.lock().unwrap();
+        let live = self.events.subscribe();
+        let (missed, replay) = match last_event_id {
+            Some(last_id) => {
+                let oldest = log
+                    .events
+                    .front()
+                    .map(|event| event.id)
+                    .unwrap_or(log.next_id + 1);
+                let replay = log
+                    .events
+                    .iter()
+                    .filter(|event| event.id > last_id)
+                    .cloned()
+                    .collect();
+                // id 比当前还大说明服务重启过，之前的 id 已经没有意义了
+                (last_id + 1 < oldest || last_id > log.next_id, replay)
+            }
+            None => (false, Vec::new()),
+        };
+        TodoSubscription {
+            missed,
+            replay,
+            live,
+        }
+    }
+}
+
+/// 支持 `?tag=a,b` 和 `?overdue=true|false` 过滤
+pub(crate) async fn todos_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Query(query): Query<ListTodosQuery>,
+    headers: HeaderMap,
+) -> Result<Response, HttpError> {
+    let todos = store.list(claims.id, &query).await;
+    let etag = list_etag(&todos);
+    if is_not_modified(&headers, &etag) {
+        return Ok(not_modified(etag));
+    }
+    Ok((TypedHeader(etag), Json(todos)).into_response())
+}
+
+pub(crate) async fn todo_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Path(id): Path<usize>,
+    headers: HeaderMap,
+) -> Result<Response, HttpError> {
+    let todo = store.get(claims.id, id).await?;
+    let etag = todo.etag();
+    if is_not_modified(&headers, &etag) {
+        return Ok(not_modified(etag));
+    }
+    Ok((TypedHeader(etag), Json(todo)).into_response())
+}
+
+// 用 typed_get 而不是 Option<TypedHeader<_>>，后者在头部缺失时会解析出一个空列表
+pub(crate) fn is_not_modified(headers: &HeaderMap, etag: &ETag) -> bool {
+    headers
+        .typed_get::<IfNoneMatch>()
+        .is_some_and(|if_none_match| !if_none_match.precondition_passes(etag))
+}
+
+pub(crate) fn not_modified(etag: ETag) -> Response {
+    (StatusCode::NOT_MODIFIED, TypedHeader(etag)).into_response()
+}
+
+// Claims 需要实现 FromRequestParts
+// Json(todo) 必须放在最后面
+pub(crate) async fn crate_todo_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Json(todo): Json<CreateTodo>,
+) -> Result<(StatusCode, TypedHeader<ETag>, Json<Todo>), HttpError> {
+    let todo = store.create(claims.id, todo).await;
+    Ok((StatusCode::CREATED, TypedHeader(todo.etag()), Json(todo)))
+}
+
+/// 带 `If-Match` 时只有版本一致才会修改，否则返回 412
+pub(crate) async fn update_todo_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Path(id): Path<usize>,
+    headers: HeaderMap,
+    Json(update): Json<UpdateTodo>,
+) -> Result<(TypedHeader<ETag>, Json<Todo>), HttpError> {
+    let if_match = headers.typed_get::<IfMatch>();
+    let todo = store
+        .update(claims.id, id, update, if_match.as_ref())
+        .await?;
+    Ok((TypedHeader(todo.etag()), Json(todo)))
+}
+
+pub(crate) async fn move_todo_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Path(id): Path<usize>,
+    headers: HeaderMap,
+    Json(target): Json<MoveTodo>,
+) -> Result<(TypedHeader<ETag>, Json<Todo>), HttpError> {
+    let if_match = headers.typed_get::<IfMatch>();
+    let todo = store
+        .move_to(claims.id, id, target.position, if_match.as_ref())
+        .await?;
+    Ok((TypedHeader(todo.etag()), Json(todo)))
+}
+
+pub(crate) async fn delete_todo_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Path(id): Path<usize>,
+    headers: HeaderMap,
+) -> Result<StatusCode, HttpError> {
+    let if_match = headers.typed_get::<IfMatch>();
+    store.delete(claims.id, id, if_match.as_ref()).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// 目前只支持 `?completed=true|false`，不带条件时拒绝，避免误删全部
+pub(crate) async fn delete_todos_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Query(query): Query<DeleteTodosQuery>,
+) -> Result<Json<Vec<Todo>>, HttpError> {
+    let completed = query
+        .completed
+        .ok_or(HttpError::BadRequest("missing `completed` filter"))?;
+    Ok(Json(store.delete_where(claims.id, completed).await))
+}
+
+pub(crate) async fn complete_all_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+) -> Result<Json<Vec<Todo>>, HttpError> {
+    Ok(Json(store.complete_all(claims.id).await))
+}
+
+/// 全部成功返回 200，否则返回 422，每个操作的结果都在 `results` 里
+pub(crate) async fn batch_todos_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Json(batch): Json<BatchRequest>,
+) -> Result<(StatusCode, Json<BatchResponse>), HttpError> {
+    if batch.operations.len() > MAX_BATCH_OPERATIONS {
+        return Err(HttpError::BadRequest("too many operations"));
+    }
+
+    let (committed, results) = store.apply_batch(claims.id, batch.operations).await;
+    let status = if committed {
+        StatusCode::OK
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    };
+    Ok((status, Json(BatchResponse { committed, results })))
+}
+
+/// 当前用户能看到的 todo（包括共享清单里的）的变化推送，断线后浏览器会带上 `Last-Event-ID` 自动续传
+pub(crate) async fn todo_events_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<Event, serde_json::Error>>> {
+    let user_id = claims.id;
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    let subscription = store.subscribe(last_event_id);
+    // 实时事件里可能还有已经补发过的，按 id 去重。续传位置失效时（比如服务重启后 id
+    // 从头开始）只能按真正补发过的去重，否则 id 追上之前的事件都会被丢掉
+    let replayed_up_to = match subscription.replay.last() {
+        Some(event) => event.id,
+        None if subscription.missed => 0,
+        None => last_event_id.unwrap_or(0),
+    };
+
+    let resync = subscription.missed.then(|| Ok(resync_event(None)));
+    let replay = subscription.replay.into_iter().map(Ok);
+    let live = BroadcastStream::new(subscription.live)
+        .filter(move |event| !matches!(event, Ok(event) if event.id <= replayed_up_to));
+    // 每个事件都实时检查权限，被移出清单后就收不到了
+    let lists = store.lists.clone();
+    let stream = tokio_stream::iter(replay)
+        .chain(live)
+        .filter_map(move |event| match event {
+            Ok(event) if event.visible_to(&lists, user_id) => Some(event.to_sse()),
+            Ok(_) => None,
+            // 客户端太慢，错过的事件已经被覆盖了，让它重新拉取
+            Err(BroadcastStreamRecvError::Lagged(n)) => Some(Ok(resync_event(Some(n)))),
+        });
+    let stream = tokio_stream::iter(resync).chain(stream);
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+pub(crate) fn resync_event(missed: Option<u64>) -> Event {
+    let data = missed.map(|n| n.to_string()).unwrap_or_default();
+    Event::default().event("resync").data(data)
+}
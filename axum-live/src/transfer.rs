@@ -0,0 +1,406 @@
+//! todo 的导入导出，支持 JSON、CSV 和 todo.txt。
+
+use crate::auth::Claims;
+use crate::todos::{normalize_tags, Priority, Todo, TodoStore};
+use crate::HttpError;
+use axum::body::{Bytes, StreamBody};
+use axum::extract::Query;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+    Todotxt,
+}
+
+impl ExportFormat {
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Todotxt => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub(crate) fn file_name(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "todos.json",
+            ExportFormat::Csv => "todos.csv",
+            ExportFormat::Todotxt => "todo.txt",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ImportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+    // 只校验不写入
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// 导入的一行，和导出的字段对应，id、时间等由服务端重新生成
+#[derive(Debug, Deserialize)]
+pub(crate) struct ImportRow {
+    pub title: String,
+    #[serde(default)]
+    pub completed: bool,
+    #[serde(default)]
+    pub due_at: Option<usize>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ImportError {
+    // 从 1 开始，csv 不算表头，todo.txt 是行号
+    pub row: usize,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ImportReport {
+    pub dry_run: bool,
+    pub total: usize,
+    pub imported: usize,
+    pub todos: Vec<Todo>,
+    pub errors: Vec<ImportError>,
+}
+
+pub(crate) const CSV_HEADER: &[&str] = &[
+    "id",
+    "title",
+    "completed",
+    "priority",
+    "due_at",
+    "tags",
+    "created_at",
+    "updated_at",
+];
+
+// 导出时每次从存储里读取的 todo 数
+pub(crate) const EXPORT_PAGE_SIZE: usize = 100;
+
+/// 导出内容的开头，csv 是表头，json 是数组的左括号
+pub(crate) fn export_header(format: ExportFormat) -> Option<Bytes> {
+    match format {
+        ExportFormat::Json => Some(Bytes::from_static(b"[")),
+        ExportFormat::Csv => Some(csv_line(CSV_HEADER.iter().copied())),
+        ExportFormat::Todotxt => None,
+    }
+}
+
+/// 一个 todo 导出成一块，json 除了第一个都在前面加上逗号
+pub(crate) fn export_row(todo: &Todo, format: ExportFormat, first: bool) -> Bytes {
+    match format {
+        ExportFormat::Json => {
+            let mut chunk = if first { Vec::new() } else { b",".to_vec() };
+            serde_json::to_writer(&mut chunk, todo).expect("todo is always serializable");
+            Bytes::from(chunk)
+        }
+        ExportFormat::Csv => {
+            let tags = todo.tags.join(";");
+            csv_line([
+                todo.id.to_string().as_str(),
+                todo.title.as_str(),
+                if todo.completed { "true" } else { "false" },
+                priority_name(todo.priority),
+                todo.due_at
+                    .map(|due| due.to_string())
+                    .unwrap_or_default()
+                    .as_str(),
+                tags.as_str(),
+                todo.created_at.to_string().as_str(),
+                todo.updated_at.to_string().as_str(),
+            ])
+        }
+        ExportFormat::Todotxt => Bytes::from(format!("{}\n", to_todotxt(todo))),
+    }
+}
+
+pub(crate) fn export_footer(format: ExportFormat) -> Option<Bytes> {
+    (format == ExportFormat::Json).then(|| Bytes::from_static(b"]"))
+}
+
+/// 一页页地从存储里读取用户的 todo，边读边序列化发出去，不会把整个导出放在内存里。
+/// 客户端断开后发送失败，任务随之结束
+pub(crate) fn export_stream(
+    store: TodoStore,
+    user_id: usize,
+    format: ExportFormat,
+) -> ReceiverStream<Result<Bytes, Infallible>> {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let send = |chunk| tx.send(Ok(chunk));
+        if let Some(header) = export_header(format) {
+            if send(header).await.is_err() {
+                return;
+            }
+        }
+        let mut after = None;
+        let mut first = true;
+        loop {
+            let page = store.page(user_id, after, EXPORT_PAGE_SIZE).await;
+            for todo in &page {
+                if send(export_row(todo, format, first)).await.is_err() {
+                    return;
+                }
+                first = false;
+            }
+            match page.last() {
+                Some(last) if page.len() == EXPORT_PAGE_SIZE => {
+                    after = Some((last.position, last.id))
+                }
+                _ => break,
+            }
+        }
+        if let Some(footer) = export_footer(format) {
+            let _ = send(footer).await;
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+pub(crate) fn csv_line<'a>(fields: impl IntoIterator<Item = &'a str>) -> Bytes {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer
+        .write_record(fields)
+        .expect("writing to memory never fails");
+    Bytes::from(writer.into_inner().expect("writing to memory never fails"))
+}
+
+pub(crate) fn priority_name(priority: Priority) -> &'static str {
+    match priority {
+        Priority::Low => "low",
+        Priority::Normal => "normal",
+        Priority::High => "high",
+    }
+}
+
+/// 解析导入的内容，返回可以导入的行和出错的行。json 整体不是数组时直接报错
+pub(crate) fn parse_import(
+    body: &str,
+    format: ExportFormat,
+) -> Result<(usize, Vec<ImportRow>, Vec<ImportError>), HttpError> {
+    let results: Vec<(usize, Result<ImportRow, String>)> = match format {
+        ExportFormat::Json => serde_json::from_str::<Vec<serde_json::Value>>(body)
+            .map_err(|_| HttpError::BadRequest("expected a json array"))?
+            .into_iter()
+            .map(|value| serde_json::from_value(value).map_err(|e| e.to_string()))
+            .enumerate()
+            .map(|(i, row)| (i + 1, row))
+            .collect(),
+        ExportFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(body.as_bytes());
+            reader
+                .deserialize::<CsvRow>()
+                .map(|row| row.map(ImportRow::from).map_err(|e| e.to_string()))
+                .enumerate()
+                .map(|(i, row)| (i + 1, row))
+                .collect()
+        }
+        ExportFormat::Todotxt => body
+            .lines()
+            .map(str::trim)
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(i, line)| (i + 1, parse_todotxt(line)))
+            .collect(),
+    };
+
+    let total = results.len();
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    for (row, result) in results {
+        match result.and_then(validate_import_row) {
+            Ok(valid) => rows.push(valid),
+            Err(error) => errors.push(ImportError { row, error }),
+        }
+    }
+    Ok((total, rows, errors))
+}
+
+pub(crate) fn validate_import_row(mut row: ImportRow) -> Result<ImportRow, String> {
+    row.title = row.title.trim().to_string();
+    if row.title.is_empty() {
+        return Err("missing title".to_string());
+    }
+    row.tags = normalize_tags(row.tags);
+    Ok(row)
+}
+
+/// csv 里的标签用分号分隔，其余列名和导出的一致，多余的列忽略
+#[derive(Debug, Deserialize)]
+pub(crate) struct CsvRow {
+    title: String,
+    #[serde(default)]
+    completed: Option<bool>,
+    #[serde(default)]
+    priority: Option<Priority>,
+    #[serde(default)]
+    due_at: Option<usize>,
+    #[serde(default)]
+    tags: Option<String>,
+}
+
+impl From<CsvRow> for ImportRow {
+    fn from(row: CsvRow) -> Self {
+        Self {
+            title: row.title,
+            completed: row.completed.unwrap_or_default(),
+            due_at: row.due_at,
+            priority: row.priority.unwrap_or_default(),
+            tags: row
+                .tags
+                .map(|tags| tags.split(';').map(str::to_string).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// todo.txt 格式：`x (A) 2023-07-01 title +tag due:2023-07-31`，
+/// 优先级 A/B/C 对应 high/normal/low，标签用 `+tag`
+pub(crate) fn to_todotxt(todo: &Todo) -> String {
+    let mut parts = Vec::new();
+    if todo.completed {
+        parts.push("x".to_string());
+    }
+    let priority = match todo.priority {
+        Priority::High => "(A)",
+        Priority::Normal => "(B)",
+        Priority::Low => "(C)",
+    };
+    parts.push(priority.to_string());
+    parts.push(format_date(todo.created_at));
+    parts.push(todo.title.clone());
+    parts.extend(todo.tags.iter().map(|tag| format!("+{}", tag)));
+    if let Some(due_at) = todo.due_at {
+        parts.push(format!("due:{}", format_date(due_at)));
+    }
+    parts.join(" ")
+}
+
+/// 也兼容 `@context`，同样当作标签；完成日期和创建日期会被忽略。
+/// 完成的任务是 `x [完成日期] [创建日期]`，未完成的是 `[优先级] [创建日期]`
+pub(crate) fn parse_todotxt(line: &str) -> Result<ImportRow, String> {
+    let mut words = line.split_whitespace().peekable();
+    let completed = words.next_if_eq(&"x").is_some();
+    // 只有这些位置上的日期是完成日期和创建日期，标题本身以日期开头时要保留
+    let max_dates = if completed { 2 } else { 1 };
+    let mut dates = 0;
+    while completed && dates < max_dates && words.next_if(|word| is_date(word)).is_some() {
+        dates += 1;
+    }
+    let priority = words
+        .next_if(|word| word.len() == 3 && word.starts_with('(') && word.ends_with(')'))
+        .map(|word| match word.as_bytes()[1] {
+            b'A' => Ok(Priority::High),
+            b'B' => Ok(Priority::Normal),
+            b'C'..=b'Z' => Ok(Priority::Low),
+            _ => Err(format!("invalid priority {}", word)),
+        })
+        .transpose()?
+        .unwrap_or_default();
+    while dates < max_dates && words.next_if(|word| is_date(word)).is_some() {
+        dates += 1;
+    }
+    let mut title = Vec::new();
+    let mut tags = Vec::new();
+    let mut due_at = None;
+    for word in words {
+        if let Some(date) = word.strip_prefix("due:") {
+            due_at = Some(parse_date(date).ok_or_else(|| format!("invalid due date {}", date))?);
+        } else if word.len() > 1 && (word.starts_with('+') || word.starts_with('@')) {
+            tags.push(word[1..].to_string());
+        } else {
+            title.push(word);
+        }
+    }
+
+    Ok(ImportRow {
+        title: title.join(" "),
+        completed,
+        due_at,
+        priority,
+        tags,
+    })
+}
+
+pub(crate) fn is_date(word: &str) -> bool {
+    parse_date(word).is_some()
+}
+
+pub(crate) fn format_date(epoch: usize) -> String {
+    chrono::DateTime::from_timestamp(epoch as i64, 0)
+        .map(|time| time.format("%Y-%m-%d").to_string())
+        .unwrap_or_default()
+}
+
+/// `YYYY-MM-DD` 转成当天零点（UTC）的时间戳
+pub(crate) fn parse_date(date: &str) -> Option<usize> {
+    let date = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+    let epoch = date.and_hms_opt(0, 0, 0)?.and_utc().timestamp();
+    usize::try_from(epoch).ok()
+}
+
+pub(crate) async fn export_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, HttpError> {
+    let body = StreamBody::new(export_stream(store, claims.id, query.format));
+    let disposition = format!("attachment; filename=\"{}\"", query.format.file_name());
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                query.format.content_type().to_string(),
+            ),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// 能解析的行都会导入，出错的行在 `errors` 里返回；`dry_run=true` 时只校验。
+/// json 不是数组时返回 400
+pub(crate) async fn import_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Query(query): Query<ImportQuery>,
+    body: String,
+) -> Result<Json<ImportReport>, HttpError> {
+    let (total, rows, errors) = parse_import(&body, query.format)?;
+    let imported = rows.len();
+    let todos = if query.dry_run {
+        Vec::new()
+    } else {
+        store.import(claims.id, rows).await
+    };
+    Ok(Json(ImportReport {
+        dry_run: query.dry_run,
+        total,
+        imported,
+        todos,
+        errors,
+    }))
+}
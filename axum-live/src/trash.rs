@@ -0,0 +1,128 @@
+//! 回收站：删除的 todo 先放在这里，可以恢复，超过保留时间后自动清理。
+
+use crate::auth::Claims;
+use crate::lists::{Access, ListRole};
+use crate::todos::{StoreError, Todo, TodoStore};
+use crate::{get_epoch, HttpError};
+use axum::extract::Path;
+use axum::headers::ETag;
+use axum::{Extension, Json, TypedHeader};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+
+pub(crate) const DEFAULT_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct TrashedTodo {
+    #[serde(flatten)]
+    pub todo: Todo,
+    pub deleted_at: usize,
+    // 到这个时间之后会被永久删除
+    pub purge_at: usize,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Trash {
+    items: Arc<Mutex<Vec<TrashedTodo>>>,
+    retention: Duration,
+}
+
+impl Default for Trash {
+    fn default() -> Self {
+        Self::new(DEFAULT_RETENTION)
+    }
+}
+
+impl Trash {
+    pub(crate) fn new(retention: Duration) -> Self {
+        Self {
+            items: Default::default(),
+            retention,
+        }
+    }
+
+    pub(crate) fn set_retention(&mut self, retention: Duration) {
+        self.retention = retention;
+    }
+
+    pub(crate) fn put(&self, todos: impl IntoIterator<Item = Todo>) {
+        let deleted_at = get_epoch();
+        let purge_at = deleted_at.saturating_add(self.retention.as_secs() as usize);
+        let mut items = self.items.lock().unwrap();
+        items.extend(todos.into_iter().map(|todo| TrashedTodo {
+            todo,
+            deleted_at,
+            purge_at,
+        }));
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.items.lock().unwrap().len()
+    }
+
+    /// 用户能看到的已删除 todo，最近删除的在前
+    pub(crate) fn list(&self, access: &Access) -> Vec<TrashedTodo> {
+        let items = self.items.lock().unwrap();
+        items
+            .iter()
+            .rev()
+            .filter(|trashed| access.check(&trashed.todo, ListRole::Viewer).is_ok())
+            .cloned()
+            .collect()
+    }
+
+    /// 取出要恢复的 todo，共享清单里的需要 editor 权限
+    pub(crate) fn take(&self, access: &Access, id: usize) -> Result<Todo, StoreError> {
+        let mut items = self.items.lock().unwrap();
+        let index = items
+            .iter()
+            .position(|trashed| trashed.todo.id == id)
+            .ok_or(StoreError::NotFound)?;
+        access.check(&items[index].todo, ListRole::Editor)?;
+        Ok(items.remove(index).todo)
+    }
+
+    /// 后台清理任务，最多隔一分钟检查一次过期的 todo。
+    /// 只持有弱引用，TodoStore 全部 drop 之后退出
+    pub(crate) fn purger(&self) -> impl Future<Output = ()> + 'static {
+        let items = Arc::downgrade(&self.items);
+        let period = self
+            .retention
+            .clamp(Duration::from_millis(100), Duration::from_secs(60));
+        async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let Some(items) = items.upgrade() else {
+                    return;
+                };
+                let now = get_epoch();
+                let mut items = items.lock().unwrap();
+                let before = items.len();
+                items.retain(|trashed| trashed.purge_at > now);
+                if items.len() < before {
+                    info!("purged {} todos from trash", before - items.len());
+                }
+            }
+        }
+    }
+}
+
+pub(crate) async fn trash_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+) -> Json<Vec<TrashedTodo>> {
+    Json(store.trash.list(&store.lists.access(claims.id)))
+}
+
+pub(crate) async fn restore_handler(
+    claims: Claims,
+    Extension(store): Extension<TodoStore>,
+    Path(id): Path<usize>,
+) -> Result<(TypedHeader<ETag>, Json<Todo>), HttpError> {
+    let todo = store.restore(claims.id, id).await?;
+    Ok((TypedHeader(todo.etag()), Json(todo)))
+}
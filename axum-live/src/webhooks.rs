@@ -0,0 +1,546 @@
+//! webhook：todo 变化时把签名过的 JSON 推给用户登记的地址，失败按指数退避重试。
+
+use crate::auth::Claims;
+use crate::lists::ListStore;
+use crate::middleware::env_or;
+use crate::todos::{Todo, TodoEvent, TodoEventKind};
+use crate::{get_epoch, get_next_id, to_hex, HttpError};
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+// 只保留最近的投递记录
+const DELIVERY_LOG_CAPACITY: usize = 1000;
+// 最多记住多少个 todo 的完成状态，超出后先忘掉最早创建的
+const COMPLETED_CACHE_CAPACITY: usize = 10_000;
+
+pub(crate) const SIGNATURE_HEADER: &str = "x-webhook-signature";
+pub(crate) const EVENT_HEADER: &str = "x-webhook-event";
+pub(crate) const DELIVERY_HEADER: &str = "x-webhook-delivery";
+
+/// webhook 投递的配置，都可以通过环境变量覆盖
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// 包括第一次在内最多尝试几次
+    pub max_attempts: u32,
+    /// 第一次重试前等待的时间，之后每次翻倍
+    pub initial_backoff: Duration,
+    pub request_timeout: Duration,
+    /// 是否允许投递到回环、内网等地址，默认不允许，避免被用来访问服务所在的内网
+    pub allow_private_targets: bool,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            request_timeout: Duration::from_secs(10),
+            allow_private_targets: false,
+        }
+    }
+}
+
+impl WebhookConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_attempts: env_or("WEBHOOK_MAX_ATTEMPTS", default.max_attempts).max(1),
+            initial_backoff: Duration::from_millis(env_or(
+                "WEBHOOK_BACKOFF_MS",
+                default.initial_backoff.as_millis() as u64,
+            )),
+            request_timeout: Duration::from_secs(env_or(
+                "WEBHOOK_TIMEOUT_SECS",
+                default.request_timeout.as_secs(),
+            )),
+            allow_private_targets: env_or(
+                "WEBHOOK_ALLOW_PRIVATE_TARGETS",
+                default.allow_private_targets,
+            ),
+        }
+    }
+
+    /// 第 `attempt` 次失败后要等多久再试，attempt 从 1 开始
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WebhookEvent {
+    Created,
+    Updated,
+    // 从未完成变成完成时发送，同时也会发送一次 updated
+    Completed,
+    Deleted,
+}
+
+impl WebhookEvent {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::Created => "created",
+            WebhookEvent::Updated => "updated",
+            WebhookEvent::Completed => "completed",
+            WebhookEvent::Deleted => "deleted",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Webhook {
+    pub id: usize,
+    #[serde(skip)]
+    pub user_id: usize,
+    pub url: String,
+    // 为空表示订阅所有事件
+    pub events: Vec<WebhookEvent>,
+    #[serde(skip)]
+    pub secret: String,
+    pub created_at: usize,
+}
+
+impl Webhook {
+    fn wants(&self, event: WebhookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CreateWebhook {
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CreateWebhookResponse {
+    // 用来校验签名，只会返回这一次
+    pub secret: String,
+    #[serde(flatten)]
+    pub info: Webhook,
+}
+
+/// 发给接收方的 JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct WebhookPayload {
+    pub delivery_id: usize,
+    pub event: WebhookEvent,
+    pub created_at: usize,
+    pub todo: Todo,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DeliveryStatus {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DeliveryAttempt {
+    pub at: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_code: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Delivery {
+    pub id: usize,
+    pub webhook_id: usize,
+    pub event: WebhookEvent,
+    pub todo_id: usize,
+    pub status: DeliveryStatus,
+    pub attempts: Vec<DeliveryAttempt>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_attempt_at: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    webhooks: Vec<Webhook>,
+    deliveries: VecDeque<Delivery>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct WebhookStore {
+    inner: Arc<std::sync::RwLock<Inner>>,
+    config: Arc<WebhookConfig>,
+    client: reqwest::Client,
+}
+
+impl WebhookStore {
+    pub(crate) fn new(config: WebhookConfig) -> Self {
+        // 不跟随重定向：公网地址可以把请求 302 到内网，绕过注册时的地址检查。
+        // 3xx 和其他非 2xx 响应一样算投递失败
+        let client = reqwest::Client::builder()
+            .timeout(config.request_timeout)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("failed to build webhook client");
+        Self {
+            inner: Default::default(),
+            config: Arc::new(config),
+            client,
+        }
+    }
+
+    pub(crate) fn create(
+        &self,
+        user_id: usize,
+        url: String,
+        events: Vec<WebhookEvent>,
+    ) -> (String, Webhook) {
+        let secret: [u8; 32] = rand::thread_rng().gen();
+        let secret = to_hex(&secret);
+        let webhook = Webhook {
+            id: get_next_id(),
+            user_id,
+            url,
+            events,
+            secret: secret.clone(),
+            created_at: get_epoch(),
+        };
+        let mut inner = self.inner.write().unwrap();
+        inner.webhooks.push(webhook.clone());
+        (secret, webhook)
+    }
+
+    pub(crate) fn list(&self, user_id: usize) -> Vec<Webhook> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .webhooks
+            .iter()
+            .filter(|webhook| webhook.user_id == user_id)
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn remove(&self, user_id: usize, id: usize) -> Option<Webhook> {
+        let mut inner = self.inner.write().unwrap();
+        let index = inner
+            .webhooks
+            .iter()
+            .position(|webhook| webhook.id == id && webhook.user_id == user_id)?;
+        Some(inner.webhooks.remove(index))
+    }
+
+    /// 某个 webhook 的投递记录，最新的在前
+    pub(crate) fn deliveries(&self, user_id: usize, id: usize) -> Option<Vec<Delivery>> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .webhooks
+            .iter()
+            .find(|webhook| webhook.id == id && webhook.user_id == user_id)?;
+        Some(
+            inner
+                .deliveries
+                .iter()
+                .rev()
+                .filter(|delivery| delivery.webhook_id == id)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    fn record(&self, delivery: &Delivery) {
+        let mut inner = self.inner.write().unwrap();
+        match inner.deliveries.iter_mut().find(|d| d.id == delivery.id) {
+            Some(existing) => *existing = delivery.clone(),
+            None => {
+                if inner.deliveries.len() == DELIVERY_LOG_CAPACITY {
+                    inner.deliveries.pop_front();
+                }
+                inner.deliveries.push_back(delivery.clone());
+            }
+        }
+    }
+
+    /// 把事件分发给所有能看到这个 todo 并且订阅了这个事件的 webhook
+    fn dispatch(&self, lists: &ListStore, event: WebhookEvent, source: &TodoEvent) {
+        let todo = source.todo();
+        let targets: Vec<Webhook> = {
+            let inner = self.inner.read().unwrap();
+            inner
+                .webhooks
+                .iter()
+                .filter(|webhook| webhook.wants(event) && source.visible_to(lists, webhook.user_id))
+                .cloned()
+                .collect()
+        };
+
+        for webhook in targets {
+            let payload = WebhookPayload {
+                delivery_id: get_next_id(),
+                event,
+                created_at: get_epoch(),
+                todo: todo.clone(),
+            };
+            // 每次投递单独一个任务，慢的接收方不会拖住别人
+            tokio::spawn(self.clone().deliver(webhook, payload));
+        }
+    }
+
+    async fn deliver(self, webhook: Webhook, payload: WebhookPayload) {
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("failed to serialize webhook payload: {:?}", e);
+                return;
+            }
+        };
+        let signature = sign(&webhook.secret, &body);
+        let mut delivery = Delivery {
+            id: payload.delivery_id,
+            webhook_id: webhook.id,
+            event: payload.event,
+            todo_id: payload.todo.id,
+            status: DeliveryStatus::Pending,
+            attempts: Vec::new(),
+            next_attempt_at: None,
+        };
+        self.record(&delivery);
+
+        for attempt in 1..=self.config.max_attempts {
+            let result = self
+                .client
+                .post(&webhook.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .header(EVENT_HEADER, payload.event.as_str())
+                .header(DELIVERY_HEADER, delivery.id.to_string())
+                .body(body.clone())
+                .send()
+                .await;
+            let (status_code, error) = match result {
+                Ok(res) if res.status().is_success() => (Some(res.status().as_u16()), None),
+                Ok(res) => (
+                    Some(res.status().as_u16()),
+                    Some(format!("unexpected status {}", res.status())),
+                ),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            let failed = error.is_some();
+            delivery.attempts.push(DeliveryAttempt {
+                at: get_epoch(),
+                status_code,
+                error,
+            });
+
+            if !failed {
+                info!(
+                    webhook = webhook.id,
+                    delivery = delivery.id,
+                    "webhook delivered"
+                );
+                delivery.status = DeliveryStatus::Succeeded;
+                delivery.next_attempt_at = None;
+                self.record(&delivery);
+                return;
+            }
+            if attempt == self.config.max_attempts {
+                break;
+            }
+
+            let backoff = self.config.backoff(attempt);
+            delivery.next_attempt_at = Some(get_epoch() + backoff.as_secs() as usize);
+            self.record(&delivery);
+            tokio::time::sleep(backoff).await;
+        }
+
+        warn!(
+            webhook = webhook.id,
+            delivery = delivery.id,
+            "webhook delivery failed"
+        );
+        delivery.status = DeliveryStatus::Failed;
+        delivery.next_attempt_at = None;
+        self.record(&delivery);
+    }
+}
+
+/// `sha256=<hex>`，接收方用创建 webhook 时拿到的 secret 对原始 body 做 HMAC-SHA256 校验
+pub(crate) fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key size");
+    mac.update(body);
+    format!("sha256={}", to_hex(&mac.finalize().into_bytes()))
+}
+
+/// 后台任务：监听 todo 事件并投递 webhook，TodoStore 全部 drop 之后退出
+pub(crate) async fn run_dispatcher(
+    webhooks: WebhookStore,
+    lists: ListStore,
+    mut events: broadcast::Receiver<TodoEvent>,
+) {
+    // 记录每个 todo 上次是否完成，用来判断 completed 事件。todo id 是递增的，
+    // 超出容量时忘掉 id 最小的，这些 todo 之后再更新可能会多发一次 completed
+    let mut completed: BTreeMap<usize, bool> = BTreeMap::new();
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("webhook dispatcher lagged, {} todo events skipped", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        let todo = event.todo();
+        match event.kind() {
+            TodoEventKind::Created => {
+                completed.insert(todo.id, todo.completed);
+                webhooks.dispatch(&lists, WebhookEvent::Created, &event);
+                if todo.completed {
+                    webhooks.dispatch(&lists, WebhookEvent::Completed, &event);
+                }
+            }
+            TodoEventKind::Updated => {
+                let was_completed = completed.insert(todo.id, todo.completed).unwrap_or(false);
+                webhooks.dispatch(&lists, WebhookEvent::Updated, &event);
+                if todo.completed && !was_completed {
+                    webhooks.dispatch(&lists, WebhookEvent::Completed, &event);
+                }
+            }
+            TodoEventKind::Deleted => {
+                completed.remove(&todo.id);
+                webhooks.dispatch(&lists, WebhookEvent::Deleted, &event);
+            }
+        }
+        while completed.len() > COMPLETED_CACHE_CAPACITY {
+            completed.pop_first();
+        }
+    }
+}
+
+pub(crate) async fn create_webhook_handler(
+    claims: Claims,
+    Extension(webhooks): Extension<WebhookStore>,
+    Json(request): Json<CreateWebhook>,
+) -> Result<(StatusCode, Json<CreateWebhookResponse>), HttpError> {
+    let url = reqwest::Url::parse(request.url.trim())
+        .map_err(|_| HttpError::BadRequest("invalid webhook url"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(HttpError::BadRequest("webhook url must be http or https"));
+    }
+    if !webhooks.config.allow_private_targets {
+        check_public_target(&url).await?;
+    }
+
+    let (secret, info) = webhooks.create(claims.id, url.to_string(), request.events);
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateWebhookResponse { secret, info }),
+    ))
+}
+
+/// 域名要解析出来检查每个地址。解析结果之后可能会变，这里只挡住直接填内网地址的情况
+async fn check_public_target(url: &reqwest::Url) -> Result<(), HttpError> {
+    let port = url.port_or_known_default().unwrap_or(80);
+    let host = url.host_str().unwrap_or_default();
+    // IPv6 地址在 url 里带着方括号
+    let addrs: Vec<IpAddr> = match host.trim_matches(['[', ']']).parse() {
+        Ok(ip) => vec![ip],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|_| HttpError::BadRequest("webhook host can't be resolved"))?
+            .map(|addr| addr.ip())
+            .collect(),
+    };
+    if addrs.is_empty() {
+        return Err(HttpError::BadRequest("webhook host can't be resolved"));
+    }
+    if addrs.into_iter().any(is_private) {
+        return Err(HttpError::BadRequest(
+            "webhook url must be a public address",
+        ));
+    }
+    Ok(())
+}
+
+/// 回环、内网、链路本地（包括云服务的元数据地址）以及其他特殊用途等不该从服务端访问的地址
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // 本网络 0.0.0.0/8，包括 0.0.0.0
+                || a == 0
+                // 运营商级 NAT，100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+                // IETF 协议分配，192.0.0.0/24
+                || (a == 192 && b == 0 && c == 0)
+                // 网络性能测试，198.18.0.0/15
+                || (a == 198 && (b & 0xfe) == 18)
+                // 保留地址，240.0.0.0/4
+                || a >= 240
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private(ip.into()),
+            None => {
+                let [first, second, ..] = ip.segments();
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // 唯一本地地址 fc00::/7 和链路本地地址 fe80::/10
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80
+                    // 文档地址 2001:db8::/32
+                    || (first == 0x2001 && second == 0x0db8)
+                    // NAT64 64:ff9b::/96 和 6to4 2002::/16 会转发到任意 IPv4 地址
+                    || (first == 0x64 && second == 0xff9b)
+                    || first == 0x2002
+            }
+        },
+    }
+}
+
+pub(crate) async fn webhooks_handler(
+    claims: Claims,
+    Extension(webhooks): Extension<WebhookStore>,
+) -> Json<Vec<Webhook>> {
+    Json(webhooks.list(claims.id))
+}
+
+pub(crate) async fn delete_webhook_handler(
+    claims: Claims,
+    Extension(webhooks): Extension<WebhookStore>,
+    Path(id): Path<usize>,
+) -> Result<StatusCode, HttpError> {
+    webhooks
+        .remove(claims.id, id)
+        .map(|_| StatusCode::NO_CONTENT)
+        .ok_or(HttpError::NotFound)
+}
+
+pub(crate) async fn deliveries_handler(
+    claims: Claims,
+    Extension(webhooks): Extension<WebhookStore>,
+    Path(id): Path<usize>,
+) -> Result<Json<Vec<Delivery>>, HttpError> {
+    webhooks
+        .deliveries(claims.id, id)
+        .map(Json)
+        .ok_or(HttpError::NotFound)
+}
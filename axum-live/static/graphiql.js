@@ -0,0 +1,25 @@
+if (typeof GraphiQL === "undefined") {
+  document.getElementById("graphiql").textContent =
+    "GraphiQL could not be loaded from unpkg.com. It needs network access to the CDN.";
+  throw new Error("GraphiQL is not available");
+}
+// 先用 POST /login 拿到 token。只存在当前标签页的 sessionStorage 里，关掉就没了
+const token = sessionStorage.getItem("token") || prompt("Bearer token") || "";
+sessionStorage.setItem("token", token);
+const authorization = "Bearer " + token;
+const scheme = location.protocol === "https:" ? "wss:" : "ws:";
+
+const fetcher = GraphiQL.createFetcher({
+  url: "/graphql",
+  headers: { Authorization: authorization },
+  wsClient: graphqlWs.createClient({
+    url: scheme + "//" + location.host + "/graphql/ws",
+    connectionParams: { Authorization: authorization },
+  }),
+});
+ReactDOM.createRoot(document.getElementById("graphiql")).render(
+  React.createElement(GraphiQL, {
+    fetcher,
+    defaultQuery: "{\n  me { name }\n  todos { id title completed owner { name } }\n}\n",
+  })
+);
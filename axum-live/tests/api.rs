@@ -0,0 +1,816 @@
+mod common;
+
+use axum::http::{header, Request, StatusCode};
+use common::{RequestBuilderExt, TestApp};
+use serde_json::json;
+
+#[tokio::test]
+async fn login_should_issue_token() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+    assert_eq!(token.split('.').count(), 3);
+
+    let res = app
+        .request(Request::post("/login").json(json!({ "password": "secret" })))
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn requests_without_valid_token_should_be_rejected() {
+    let app = TestApp::new();
+
+    let res = app.request(Request::get("/todos").empty()).await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let res = app.get("/todos", "not-a-jwt").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let res = app.get("/todos", "pat_0000").await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let res = app
+        .request(
+            Request::get("/todos")
+                .header(header::AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0")
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let expired = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &json!({ "id": 1, "name": "alice", "exp": 1 }),
+        &jsonwebtoken::EncodingKey::from_secret(b"abcdefghijklmnopqrstuvwxy"),
+    )
+    .unwrap();
+    let res = app.get("/todos", &expired).await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn todo_crud_should_work() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+
+    let res = app
+        .post(
+            "/todos",
+            &token,
+            json!({ "title": "write tests", "tags": ["dev"] }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    let etag = res.header(header::ETAG).unwrap().to_string();
+    let todo = res.json();
+    let id = todo["id"].as_u64().unwrap();
+    assert_eq!(todo["title"], "write tests");
+    assert_eq!(todo["completed"], false);
+
+    let res = app.get("/todos", &token).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json().as_array().unwrap().len(), 1);
+
+    let res = app.get("/todos?tag=dev", &token).await;
+    assert_eq!(res.json().as_array().unwrap().len(), 1);
+    let res = app.get("/todos?tag=ops", &token).await;
+    assert_eq!(res.json().as_array().unwrap().len(), 0);
+
+    let res = app.get(&format!("/todos/{}", id), &token).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.header(header::ETAG), Some(etag.as_str()));
+
+    let res = app
+        .request(
+            Request::get(format!("/todos/{}", id))
+                .bearer(&token)
+                .header(header::IF_NONE_MATCH, &etag)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+
+    let res = app
+        .request(
+            Request::patch(format!("/todos/{}", id))
+                .bearer(&token)
+                .header(header::IF_MATCH, &etag)
+                .json(json!({ "completed": true })),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["completed"], true);
+
+    // 版本已经变了，旧的 ETag 不能再用
+    let res = app
+        .request(
+            Request::patch(format!("/todos/{}", id))
+                .bearer(&token)
+                .header(header::IF_MATCH, &etag)
+                .json(json!({ "title": "stale" })),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::PRECONDITION_FAILED);
+
+    let res = app.delete(&format!("/todos/{}", id), &token).await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    let res = app.get(&format!("/todos/{}", id), &token).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn writes_without_if_match_should_not_be_conditional() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+    let todo = app
+        .post("/todos", &token, json!({ "title": "no etag" }))
+        .await
+        .json();
+    let uri = format!("/todos/{}", todo["id"]);
+
+    let res = app.patch(&uri, &token, json!({ "completed": true })).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["completed"], true);
+
+    let res = app.delete(&uri, &token).await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn todo_list_etag_should_change_with_its_todos() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+    let todo = app
+        .post("/todos", &token, json!({ "title": "first" }))
+        .await
+        .json();
+
+    let list_if_none_match = |etag: &str| {
+        Request::get("/todos")
+            .bearer(&token)
+            .header(header::IF_NONE_MATCH, etag)
+            .empty()
+    };
+    let etag = app
+        .get("/todos", &token)
+        .await
+        .header(header::ETAG)
+        .unwrap()
+        .to_string();
+    let res = app.request(list_if_none_match(&etag)).await;
+    assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+
+    app.patch(
+        &format!("/todos/{}", todo["id"]),
+        &token,
+        json!({ "completed": true }),
+    )
+    .await;
+    let res = app.request(list_if_none_match(&etag)).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_ne!(res.header(header::ETAG), Some(etag.as_str()));
+}
+
+#[tokio::test]
+async fn todo_fields_should_be_stored_and_filtered() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+
+    let res = app
+        .post(
+            "/todos",
+            &token,
+            json!({
+                "title": "file taxes",
+                "due_at": 1,
+                "priority": "high",
+                "tags": [" home ", "", "home", "money"],
+            }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    let late = res.json();
+    assert_eq!(late["priority"], "high");
+    assert_eq!(late["tags"], json!(["home", "money"]));
+    assert!(late["created_at"].as_u64().unwrap() > 0);
+    let later = app
+        .post(
+            "/todos",
+            &token,
+            json!({ "title": "someday", "tags": ["home"] }),
+        )
+        .await
+        .json();
+    assert_eq!(later["priority"], "normal");
+
+    // 新建的排在最后
+    let todos = app.get("/todos", &token).await.json();
+    assert_eq!(todos[0]["id"], late["id"]);
+    assert_eq!(todos[1]["id"], later["id"]);
+
+    let overdue = app.get("/todos?overdue=true", &token).await.json();
+    assert_eq!(overdue.as_array().unwrap().len(), 1);
+    assert_eq!(overdue[0]["id"], late["id"]);
+    let res = app.get("/todos?tag=home,money", &token).await.json();
+    assert_eq!(res.as_array().unwrap().len(), 1);
+
+    // null 清除截止时间，不传则不修改
+    let uri = format!("/todos/{}", late["id"]);
+    let res = app.patch(&uri, &token, json!({ "priority": "low" })).await;
+    assert_eq!(res.json()["due_at"], 1);
+    let res = app.patch(&uri, &token, json!({ "due_at": null })).await;
+    assert_eq!(res.json()["due_at"], json!(null));
+    assert_eq!(res.json()["priority"], "low");
+    let overdue = app.get("/todos?overdue=true", &token).await.json();
+    assert_eq!(overdue, json!([]));
+}
+
+#[tokio::test]
+async fn bulk_todo_operations_should_work() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+    for title in ["a", "b", "c"] {
+        app.post("/todos", &token, json!({ "title": title })).await;
+    }
+
+    let res = app.post("/todos/complete-all", &token, json!({})).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json().as_array().unwrap().len(), 3);
+
+    let res = app.delete("/todos", &token).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    let res = app.delete("/todos?completed=true", &token).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json().as_array().unwrap().len(), 3);
+
+    let res = app
+        .post(
+            "/todos/batch",
+            &token,
+            json!({ "operations": [
+                { "op": "create", "title": "first" },
+                { "op": "create", "title": "second" },
+            ] }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let batch = res.json();
+    assert_eq!(batch["committed"], true);
+    let second = batch["results"][1]["todo"]["id"].as_u64().unwrap();
+
+    // 有一个失败就整体回滚
+    let res = app
+        .post(
+            "/todos/batch",
+            &token,
+            json!({ "operations": [
+                { "op": "create", "title": "third" },
+                { "op": "delete", "id": 999_999 },
+            ] }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(res.json()["committed"], false);
+    let res = app.get("/todos", &token).await;
+    assert_eq!(res.json().as_array().unwrap().len(), 2);
+
+    let res = app
+        .post(
+            &format!("/todos/{}/move", second),
+            &token,
+            json!({ "position": 0 }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let res = app.get("/todos", &token).await;
+    assert_eq!(res.json()[0]["title"], "second");
+}
+
+#[tokio::test]
+async fn batch_should_check_versions() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+    let todo = app
+        .post("/todos", &token, json!({ "title": "draft" }))
+        .await
+        .json();
+    let id = todo["id"].as_u64().unwrap();
+
+    let res = app
+        .post(
+            "/todos/batch",
+            &token,
+            json!({ "operations": [
+                { "op": "update", "id": id, "version": 1, "title": "final" },
+                { "op": "update", "id": id, "version": 1, "completed": true },
+            ] }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let batch = res.json();
+    assert_eq!(batch["results"][1]["status"], 412);
+    let res = app.get(&format!("/todos/{}", id), &token).await;
+    assert_eq!(
+        res.json()["title"],
+        "draft",
+        "failed batch should roll back"
+    );
+
+    let res = app
+        .post(
+            "/todos/batch",
+            &token,
+            json!({ "operations": [
+                { "op": "update", "id": id, "version": 1, "title": "final" },
+                { "op": "delete", "id": id, "version": 2 },
+            ] }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        app.get(&format!("/todos/{}", id), &token).await.status,
+        StatusCode::NOT_FOUND
+    );
+
+    let operations: Vec<_> = (0..101)
+        .map(|i| json!({ "op": "create", "title": i.to_string() }))
+        .collect();
+    let res = app
+        .post("/todos/batch", &token, json!({ "operations": operations }))
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn export_and_import_should_round_trip() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+    app.post(
+        "/todos",
+        &token,
+        json!({ "title": "pay rent", "priority": "high", "tags": ["home"] }),
+    )
+    .await;
+
+    let res = app.get("/todos/export?format=csv", &token).await;
+    assert_eq!(res.status, StatusCode::OK);
+    let csv = res.text();
+    assert!(csv.contains("pay rent"));
+
+    let other = app.login("bob@example.com").await;
+    let res = app
+        .request(
+            Request::post("/todos/import?format=csv&dry_run=true")
+                .bearer(&other)
+                .text(&csv),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["imported"], 1);
+    assert_eq!(
+        app.get("/todos", &other).await.json(),
+        json!([]),
+        "dry run should not write"
+    );
+
+    let res = app
+        .request(
+            Request::post("/todos/import?format=csv")
+                .bearer(&other)
+                .text(&csv),
+        )
+        .await;
+    assert_eq!(res.json()["imported"], 1);
+    let todos = app.get("/todos", &other).await.json();
+    assert_eq!(todos[0]["title"], "pay rent");
+    assert_eq!(todos[0]["priority"], "high");
+}
+
+#[tokio::test]
+async fn export_should_include_every_page() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+    let rows: Vec<_> = (0..250)
+        .map(|i| json!({ "title": format!("todo {}", i) }))
+        .collect();
+    let res = app
+        .request(
+            Request::post("/todos/import")
+                .bearer(&token)
+                .json(json!(rows)),
+        )
+        .await;
+    assert_eq!(res.json()["imported"], 250);
+
+    let exported = app.get("/todos/export", &token).await.json();
+    let titles: Vec<_> = exported
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|todo| todo["title"].as_str().unwrap().to_string())
+        .collect();
+    let expected: Vec<_> = (0..250).map(|i| format!("todo {}", i)).collect();
+    assert_eq!(titles, expected);
+
+    let csv = app.get("/todos/export?format=csv", &token).await.text();
+    assert_eq!(csv.lines().count(), 251);
+    let todotxt = app.get("/todos/export?format=todotxt", &token).await.text();
+    assert_eq!(todotxt.lines().count(), 250);
+}
+
+#[tokio::test]
+async fn todotxt_import_should_keep_dates_in_titles() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+    let body = "(A) 2023-07-01 2024-01-01 kickoff +work due:2023-12-31\n\
+                x 2023-07-02 2023-07-01 done already\n\
+                2024-03-01 planning\n";
+    let res = app
+        .request(
+            Request::post("/todos/import?format=todotxt")
+                .bearer(&token)
+                .text(body),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let report = res.json();
+    assert_eq!(report["imported"], 3);
+    let todos = &report["todos"];
+    assert_eq!(todos[0]["title"], "2024-01-01 kickoff");
+    assert_eq!(todos[0]["priority"], "high");
+    assert_eq!(todos[0]["tags"], json!(["work"]));
+    assert_eq!(todos[1]["title"], "done already");
+    assert_eq!(todos[1]["completed"], true);
+    // 没有优先级时开头的日期是创建日期
+    assert_eq!(todos[2]["title"], "planning");
+
+    // 导出时总会写上创建日期，导回来标题不变
+    let todotxt = app.get("/todos/export?format=todotxt", &token).await.text();
+    let other = app.login("bob@example.com").await;
+    let res = app
+        .request(
+            Request::post("/todos/import?format=todotxt")
+                .bearer(&other)
+                .text(&todotxt),
+        )
+        .await;
+    assert_eq!(res.json()["todos"][0]["title"], "2024-01-01 kickoff");
+}
+
+#[tokio::test]
+async fn json_import_should_require_an_array() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+    let res = app
+        .request(
+            Request::post("/todos/import")
+                .bearer(&token)
+                .json(json!({ "title": "not an array" })),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+
+    // 数组里个别行出错只跳过这些行
+    let res = app
+        .request(
+            Request::post("/todos/import")
+                .bearer(&token)
+                .json(json!([{ "title": "ok" }, { "title": " " }, 42])),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let report = res.json();
+    assert_eq!(report["imported"], 1);
+    assert_eq!(report["errors"][0]["row"], 2);
+    assert_eq!(report["errors"][1]["row"], 3);
+}
+
+#[tokio::test]
+async fn todo_events_should_stream() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+
+    let (status, headers) = app
+        .request_head(Request::get("/todos/events").bearer(&token).empty())
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        headers.get(header::CONTENT_TYPE).unwrap(),
+        "text/event-stream"
+    );
+
+    let mut events = app.events(&token, None).await;
+    let todo = app
+        .post("/todos", &token, json!({ "title": "first" }))
+        .await
+        .json();
+    app.patch(
+        &format!("/todos/{}", todo["id"]),
+        &token,
+        json!({ "completed": true }),
+    )
+    .await;
+    let created = events.next().await;
+    assert_eq!(created.event, "created");
+    let updated = events.next().await;
+    assert_eq!(updated.event, "updated");
+    let data: serde_json::Value = serde_json::from_str(&updated.data).unwrap();
+    assert_eq!(data["completed"], true);
+
+    // 断线重连时从上次收到的位置补发，之后的实时事件不重复
+    let mut resumed = app.events(&token, created.id.as_deref()).await;
+    let replayed = resumed.next().await;
+    assert_eq!(replayed.id, updated.id);
+    assert_eq!(replayed.event, "updated");
+    app.delete(&format!("/todos/{}", todo["id"]), &token).await;
+    let deleted = resumed.next().await;
+    assert_eq!(deleted.event, "deleted");
+    assert_eq!(deleted.id, events.next().await.id);
+}
+
+#[tokio::test]
+async fn todo_events_should_resume_after_restart() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+
+    // 重启前的 id 比现在的都大，先让客户端重新拉取，之后的事件照常收到
+    let mut events = app.events(&token, Some("1000")).await;
+    let resync = events.next().await;
+    assert_eq!(resync.event, "resync");
+
+    app.post("/todos", &token, json!({ "title": "after restart" }))
+        .await;
+    let created = events.next().await;
+    assert_eq!(created.event, "created");
+    assert_eq!(created.id.as_deref(), Some("1"));
+    let todo: serde_json::Value = serde_json::from_str(&created.data).unwrap();
+    assert_eq!(todo["title"], "after restart");
+}
+
+#[tokio::test]
+async fn todo_events_should_only_include_own_todos() {
+    let app = TestApp::new();
+    let alice = app.login("alice@example.com").await;
+    let bob = app.login("bob@example.com").await;
+
+    let mut events = app.events(&bob, None).await;
+    app.post("/todos", &alice, json!({ "title": "alice only" }))
+        .await;
+    app.post("/todos", &bob, json!({ "title": "bob's" })).await;
+    let created = events.next().await;
+    let todo: serde_json::Value = serde_json::from_str(&created.data).unwrap();
+    assert_eq!(todo["title"], "bob's");
+}
+
+#[tokio::test]
+async fn users_should_not_see_each_others_todos() {
+    let app = TestApp::new();
+    let alice = app.login("alice@example.com").await;
+    let bob = app.login("bob@example.com").await;
+
+    let todo = app
+        .post("/todos", &alice, json!({ "title": "alice only" }))
+        .await
+        .json();
+    let uri = format!("/todos/{}", todo["id"]);
+
+    assert_eq!(app.get("/todos", &bob).await.json(), json!([]));
+    assert_eq!(app.get(&uri, &bob).await.status, StatusCode::NOT_FOUND);
+    let res = app.patch(&uri, &bob, json!({ "completed": true })).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(app.delete(&uri, &bob).await.status, StatusCode::NOT_FOUND);
+    let res = app
+        .post(&format!("{}/move", uri), &bob, json!({ "position": 0 }))
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+
+    // bob 的批量操作也碰不到 alice 的 todo
+    app.post("/todos/complete-all", &bob, json!({})).await;
+    app.delete("/todos?completed=false", &bob).await;
+    let res = app.get(&uri, &alice).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["completed"], false);
+    assert_eq!(app.get("/todos/export", &bob).await.json(), json!([]));
+}
+
+#[tokio::test]
+async fn shared_lists_should_respect_roles() {
+    let app = TestApp::new();
+    let alice = app.login("alice@example.com").await;
+    let bob = app.login("bob@example.com").await;
+    let carol = app.login("carol@example.com").await;
+
+    let res = app
+        .post("/lists", &alice, json!({ "name": "groceries" }))
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    let list_id = res.json()["id"].as_u64().unwrap();
+    let list = format!("/lists/{}", list_id);
+
+    assert_eq!(app.get(&list, &bob).await.status, StatusCode::NOT_FOUND);
+
+    let res = app
+        .post(
+            &format!("{}/invitations", list),
+            &alice,
+            json!({ "email": "bob@example.com", "role": "viewer" }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    let invitations = app.get("/invitations", &bob).await.json();
+    let invitation = invitations[0]["id"].as_u64().unwrap();
+    let res = app
+        .post(
+            &format!("/invitations/{}/accept", invitation),
+            &bob,
+            json!({}),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        app.get("/lists", &bob)
+            .await
+            .json()
+            .as_array()
+            .unwrap()
+            .len(),
+        1
+    );
+
+    let res = app
+        .post(
+            &format!("{}/invitations", list),
+            &alice,
+            json!({ "email": "carol@example.com", "role": "editor" }),
+        )
+        .await;
+    let invitation = res.json()["id"].as_u64().unwrap();
+    let res = app
+        .post(
+            &format!("/invitations/{}/decline", invitation),
+            &carol,
+            json!({}),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NO_CONTENT);
+    assert_eq!(app.get(&list, &carol).await.status, StatusCode::NOT_FOUND);
+
+    let res = app
+        .post(
+            &format!("{}/todos", list),
+            &alice,
+            json!({ "title": "milk" }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    let res = app.get(&format!("{}/todos", list), &bob).await;
+    assert_eq!(res.json()[0]["title"], "milk");
+
+    // viewer 只能看
+    let res = app
+        .post(&format!("{}/todos", list), &bob, json!({ "title": "eggs" }))
+        .await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+    let detail = app.get(&list, &alice).await.json();
+    let bob_id = detail["members"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|member| member["user_id"].clone())
+        .find(|id| *id != detail["owner_id"])
+        .unwrap();
+    let member = format!("{}/members/{}", list, bob_id);
+    let res = app
+        .patch(&member, &alice, json!({ "role": "editor" }))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let res = app
+        .post(&format!("{}/todos", list), &bob, json!({ "title": "eggs" }))
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+
+    assert_eq!(app.delete(&list, &bob).await.status, StatusCode::FORBIDDEN);
+    assert_eq!(
+        app.delete(&member, &alice).await.status,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(app.get(&list, &bob).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(
+        app.delete(&list, &alice).await.status,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(app.get(&list, &alice).await.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn deleting_a_list_should_notify_its_members() {
+    let app = TestApp::new();
+    let alice = app.login("alice@example.com").await;
+    let bob = app.login("bob@example.com").await;
+
+    let list_id = app
+        .post("/lists", &alice, json!({ "name": "groceries" }))
+        .await
+        .json()["id"]
+        .clone();
+    let list = format!("/lists/{}", list_id);
+    let invitation = app
+        .post(
+            &format!("{}/invitations", list),
+            &alice,
+            json!({ "email": "bob@example.com", "role": "viewer" }),
+        )
+        .await
+        .json()["id"]
+        .clone();
+    app.post(
+        &format!("/invitations/{}/accept", invitation),
+        &bob,
+        json!({}),
+    )
+    .await;
+    let todo = app
+        .post(
+            &format!("{}/todos", list),
+            &alice,
+            json!({ "title": "milk" }),
+        )
+        .await
+        .json();
+
+    // 收到事件时清单已经删掉了，成员也要知道里面的 todo 没了
+    let mut events = app.events(&bob, None).await;
+    assert_eq!(
+        app.delete(&list, &alice).await.status,
+        StatusCode::NO_CONTENT
+    );
+    let deleted = events.next().await;
+    assert_eq!(deleted.event, "deleted");
+    let deleted: serde_json::Value = serde_json::from_str(&deleted.data).unwrap();
+    assert_eq!(deleted["id"], todo["id"]);
+}
+
+#[tokio::test]
+async fn personal_access_tokens_should_be_scoped() {
+    let app = TestApp::new();
+    let alice = app.login("alice@example.com").await;
+
+    let res = app
+        .post(
+            "/tokens",
+            &alice,
+            json!({ "name": "ci", "scopes": ["read"] }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    let created = res.json();
+    let pat = created["token"].as_str().unwrap().to_string();
+    assert!(pat.starts_with("pat_"));
+
+    let res = app.get("/tokens", &alice).await;
+    let tokens = res.json();
+    assert_eq!(tokens.as_array().unwrap().len(), 1);
+    assert!(
+        tokens[0].get("token").is_none(),
+        "secret must not be listed"
+    );
+
+    // 有效期太长会溢出，直接拒绝
+    let res = app
+        .post(
+            "/tokens",
+            &alice,
+            json!({ "name": "forever", "scopes": ["read"], "expires_in_days": usize::MAX }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    let res = app
+        .post(
+            "/tokens",
+            &alice,
+            json!({ "name": "yearly", "scopes": ["read"], "expires_in_days": 365 }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    let yearly = res.json()["id"].clone();
+    app.delete(&format!("/tokens/{}", yearly), &alice).await;
+
+    assert_eq!(app.get("/todos", &pat).await.status, StatusCode::OK);
+    let res = app.post("/todos", &pat, json!({ "title": "nope" })).await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+    // token 不能用来管理 token
+    assert_eq!(app.get("/tokens", &pat).await.status, StatusCode::FORBIDDEN);
+
+    let bob = app.login("bob@example.com").await;
+    let uri = format!("/tokens/{}", created["id"]);
+    assert_eq!(app.delete(&uri, &bob).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(
+        app.delete(&uri, &alice).await.status,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(
+        app.get("/todos", &pat).await.status,
+        StatusCode::UNAUTHORIZED
+    );
+}
@@ -0,0 +1,217 @@
+mod common;
+
+use axum::http::{header, Request, StatusCode};
+use axum_live::AppState;
+use common::{RequestBuilderExt, TestApp};
+use std::path::PathBuf;
+
+/// 每个测试用自己的静态文件目录
+fn asset_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("axum-live-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    for (path, content) in files {
+        std::fs::write(root.join(path), content).unwrap();
+    }
+    root
+}
+
+#[tokio::test]
+async fn static_files_should_be_served() {
+    let app = TestApp::new();
+
+    let res = app.request(Request::get("/").empty()).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res
+        .header(header::CONTENT_TYPE)
+        .unwrap()
+        .starts_with("text/html"));
+
+    let res = app.request(Request::get("/hello.js").empty()).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.header(header::CACHE_CONTROL), Some("no-cache"));
+
+    // 前端路由回退到 index.html，API 路径和找不到的文件不回退
+    let res = app.request(Request::get("/settings/profile").empty()).await;
+    assert_eq!(res.status, StatusCode::OK);
+    let res = app.request(Request::get("/todos/1/unknown").empty()).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    let res = app.request(Request::get("/missing.js").empty()).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+
+    let res = app.request(Request::head("/hello.js").empty()).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res.body.is_empty());
+    assert!(res.header(header::CONTENT_LENGTH).is_some());
+    let res = app.request(Request::put("/hello.js").empty()).await;
+    assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(res.header(header::ALLOW), Some("GET, HEAD"));
+
+    let res = app.request(Request::get("/__livereload").empty()).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn static_files_should_support_conditional_and_range_requests() {
+    let app = TestApp::new();
+    let full = app.request(Request::get("/hello.js").empty()).await;
+    let etag = full.header(header::ETAG).unwrap().to_string();
+
+    let res = app
+        .request(
+            Request::get("/hello.js")
+                .header(header::IF_NONE_MATCH, &etag)
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+    assert!(res.body.is_empty());
+
+    let res = app
+        .request(
+            Request::get("/hello.js")
+                .header(header::RANGE, "bytes=0-7")
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        res.header(header::CONTENT_RANGE).unwrap(),
+        format!("bytes 0-7/{}", full.body.len())
+    );
+    assert_eq!(res.body, full.body[..8]);
+
+    // bytes=-N 取最后 N 个字节
+    let res = app
+        .request(
+            Request::get("/hello.js")
+                .header(header::RANGE, "bytes=-4")
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.body, full.body[full.body.len() - 4..]);
+
+    let res = app
+        .request(
+            Request::get("/hello.js")
+                .header(header::RANGE, "bytes=1000-")
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(
+        res.header(header::CONTENT_RANGE).unwrap(),
+        format!("bytes */{}", full.body.len())
+    );
+}
+
+#[tokio::test]
+async fn hashed_and_precompressed_assets_should_be_served() {
+    let root = asset_dir(
+        "hashed",
+        &[
+            ("app.3f2a9c1b.js", "console.log('app');"),
+            ("app.3f2a9c1b.js.gz", "gzipped app"),
+            ("app-f71b1eba01039194.css", "body {}"),
+            ("deadbeef.js", "console.log('plain');"),
+            ("12345678.png", "png"),
+        ],
+    );
+    let app = TestApp::with_state(AppState::default().with_dev_assets(&root));
+
+    for path in ["/app.3f2a9c1b.js", "/app-f71b1eba01039194.css"] {
+        let res = app.request(Request::get(path).empty()).await;
+        assert_eq!(
+            res.header(header::CACHE_CONTROL),
+            Some("public, immutable, max-age=31536000"),
+            "{}",
+            path
+        );
+    }
+    // 整个名字碰巧是十六进制的不是带 hash 的资源
+    for path in ["/deadbeef.js", "/12345678.png"] {
+        let res = app.request(Request::get(path).empty()).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(
+            res.header(header::CACHE_CONTROL),
+            Some("no-cache"),
+            "{}",
+            path
+        );
+    }
+
+    let res = app
+        .request(
+            Request::get("/app.3f2a9c1b.js")
+                .header(header::ACCEPT_ENCODING, "br;q=0, gzip")
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.header(header::CONTENT_ENCODING), Some("gzip"));
+    assert!(res
+        .headers
+        .get_all(header::VARY)
+        .iter()
+        .any(|value| value == "accept-encoding"));
+    assert_eq!(res.text(), "gzipped app");
+    assert!(res
+        .header(header::CONTENT_TYPE)
+        .unwrap()
+        .contains("javascript"));
+
+    let res = app
+        .request(
+            Request::get("/app.3f2a9c1b.js")
+                .header(header::ACCEPT_ENCODING, "gzip;q=0")
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.header(header::CONTENT_ENCODING), None);
+    assert_eq!(res.text(), "console.log('app');");
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn dev_mode_should_serve_files_from_disk() {
+    let root = asset_dir("dev", &[("index.html", "<h1>dev</h1>"), ("app.js", "v1")]);
+    let secret = root.with_extension("secret");
+    std::fs::write(&secret, "secret").unwrap();
+    let app = TestApp::with_state(AppState::default().with_dev_assets(&root));
+
+    // html 注入自动刷新脚本，其他文件原样返回
+    let res = app.request(Request::get("/").empty()).await;
+    assert!(res.text().starts_with("<h1>dev</h1>"));
+    assert!(res.text().contains("/__livereload"));
+    let res = app.request(Request::get("/app.js").empty()).await;
+    assert_eq!(res.text(), "v1");
+
+    // 改了文件不用重新编译
+    std::fs::write(root.join("app.js"), "v2").unwrap();
+    let res = app.request(Request::get("/app.js").empty()).await;
+    assert_eq!(res.text(), "v2");
+
+    let uri = format!("/../{}", secret.file_name().unwrap().to_str().unwrap());
+    let res = app.request(Request::get(uri).empty()).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+
+    std::fs::remove_dir_all(&root).unwrap();
+    std::fs::remove_file(&secret).unwrap();
+}
+
+#[tokio::test]
+async fn dev_mode_should_push_reloads() {
+    let root = asset_dir("reload", &[("index.html", "<h1>dev</h1>")]);
+    let state = AppState::default().with_dev_assets(&root);
+    let _watcher = state.watch_assets().unwrap().expect("dev mode watches");
+    let app = TestApp::with_state(state);
+
+    let mut reloads = app.sse(Request::get("/__livereload").empty()).await;
+    std::fs::write(root.join("app.css"), "body {}").unwrap();
+    let reload = reloads.next().await;
+    assert_eq!(reload.event, "reload");
+    assert_eq!(reload.data, "app.css");
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,206 @@
+#![allow(dead_code)]
+
+//! 集成测试用的进程内客户端：直接用 `oneshot` 调用路由，不需要监听端口
+
+use axum::body::{Body, BoxBody};
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::Router;
+use axum_live::{build_app, AppState};
+use serde_json::Value;
+use tower::ServiceExt;
+
+pub struct TestApp {
+    app: Router,
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or_else(|e| {
+            panic!(
+                "invalid json ({}): {}",
+                e,
+                String::from_utf8_lossy(&self.body)
+            )
+        })
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn header(&self, name: impl header::AsHeaderName) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+impl TestApp {
+    pub fn new() -> Self {
+        Self::with_state(AppState::default())
+    }
+
+    pub fn with_state(state: AppState) -> Self {
+        Self {
+            app: build_app(state),
+        }
+    }
+
+    /// 登录（第一次登录会自动注册），返回 JWT
+    pub async fn login(&self, email: &str) -> String {
+        let res = self
+            .request(
+                Request::post("/login")
+                    .json(serde_json::json!({ "email": email, "password": "secret" })),
+            )
+            .await;
+        assert_eq!(res.status, StatusCode::OK, "login failed: {}", res.text());
+        res.json()["token"].as_str().unwrap().to_string()
+    }
+
+    pub async fn request(&self, req: Request<Body>) -> TestResponse {
+        let res = self.app.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let headers = res.headers().clone();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        TestResponse {
+            status,
+            headers,
+            body: body.to_vec(),
+        }
+    }
+
+    /// 只取状态码和响应头，SSE 这种不会结束的响应不能读 body
+    pub async fn request_head(&self, req: Request<Body>) -> (StatusCode, HeaderMap) {
+        let res = self.app.clone().oneshot(req).await.unwrap();
+        (res.status(), res.headers().clone())
+    }
+
+    pub async fn get(&self, uri: &str, token: &str) -> TestResponse {
+        self.request(Request::get(uri).bearer(token).empty()).await
+    }
+
+    pub async fn post(&self, uri: &str, token: &str, body: Value) -> TestResponse {
+        self.request(Request::post(uri).bearer(token).json(body))
+            .await
+    }
+
+    pub async fn patch(&self, uri: &str, token: &str, body: Value) -> TestResponse {
+        self.request(Request::patch(uri).bearer(token).json(body))
+            .await
+    }
+
+    pub async fn delete(&self, uri: &str, token: &str) -> TestResponse {
+        self.request(Request::delete(uri).bearer(token).empty())
+            .await
+    }
+}
+
+/// 给 `http::request::Builder` 加几个拼请求的快捷方法
+pub trait RequestBuilderExt {
+    fn bearer(self, token: &str) -> Self;
+    fn json(self, body: Value) -> Request<Body>;
+    fn text(self, body: &str) -> Request<Body>;
+    fn empty(self) -> Request<Body>;
+}
+
+impl RequestBuilderExt for axum::http::request::Builder {
+    fn bearer(self, token: &str) -> Self {
+        self.header(header::AUTHORIZATION, format!("Bearer {}", token))
+    }
+
+    fn json(self, body: Value) -> Request<Body> {
+        self.header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn text(self, body: &str) -> Request<Body> {
+        self.header(header::CONTENT_TYPE, "text/plain")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn empty(self) -> Request<Body> {
+        self.body(Body::empty()).unwrap()
+    }
+}
+
+/// SSE 响应里的一个事件
+#[derive(Debug)]
+pub struct SseEvent {
+    pub id: Option<String>,
+    pub event: String,
+    pub data: String,
+}
+
+/// 逐个读取 SSE 响应里的事件
+pub struct EventStream {
+    body: BoxBody,
+    buf: String,
+}
+
+impl EventStream {
+    /// 等待下一个事件，跳过 keep-alive 之类只有注释的块
+    pub async fn next(&mut self) -> SseEvent {
+        use hyper::body::HttpBody;
+        loop {
+            while let Some(end) = self.buf.find("\n\n") {
+                let block: String = self.buf.drain(..end + 2).collect();
+                let mut event = SseEvent {
+                    id: None,
+                    event: "message".to_string(),
+                    data: String::new(),
+                };
+                let mut has_fields = false;
+                for line in block.lines() {
+                    let Some((field, value)) = line.split_once(':') else {
+                        continue;
+                    };
+                    let value = value.strip_prefix(' ').unwrap_or(value).to_string();
+                    match field {
+                        "id" => event.id = Some(value),
+                        "event" => event.event = value,
+                        "data" => event.data = value,
+                        _ => continue,
+                    }
+                    has_fields = true;
+                }
+                if has_fields {
+                    return event;
+                }
+            }
+            let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), self.body.data())
+                .await
+                .expect("no event within 5s")
+                .expect("event stream ended")
+                .unwrap();
+            self.buf.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+    }
+}
+
+impl TestApp {
+    /// 订阅 `/todos/events`，可以带上 `Last-Event-ID` 续传
+    pub async fn events(&self, token: &str, last_event_id: Option<&str>) -> EventStream {
+        let mut req = Request::get("/todos/events").bearer(token);
+        if let Some(id) = last_event_id {
+            req = req.header("last-event-id", id);
+        }
+        self.sse(req.empty()).await
+    }
+
+    /// 发请求并逐个读取返回的 SSE 事件
+    pub async fn sse(&self, req: Request<Body>) -> EventStream {
+        let res = self.app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        EventStream {
+            body: res.into_body(),
+            buf: String::new(),
+        }
+    }
+}
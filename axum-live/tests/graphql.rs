@@ -0,0 +1,196 @@
+mod common;
+
+use axum::http::{header, Request, StatusCode};
+use common::{RequestBuilderExt, TestApp};
+use serde_json::{json, Value};
+
+async fn graphql(app: &TestApp, token: &str, query: &str, variables: Value) -> Value {
+    let res = app
+        .post(
+            "/graphql",
+            token,
+            json!({ "query": query, "variables": variables }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK, "{}", res.text());
+    res.json()
+}
+
+const CREATE: &str = r#"
+    mutation($title: String!) {
+        createTodo(input: { title: $title, priority: HIGH, tags: ["work"] }) { id title version }
+    }
+"#;
+
+#[tokio::test]
+async fn graphql_should_query_and_mutate_todos() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+
+    let created = graphql(&app, &token, CREATE, json!({ "title": "write schema" })).await;
+    let todo = &created["data"]["createTodo"];
+    assert_eq!(todo["title"], "write schema");
+    let id = todo["id"].as_str().unwrap().to_string();
+
+    // 和 REST 接口看到的是同一份数据
+    let rest = app.get(&format!("/todos/{}", id), &token).await.json();
+    assert_eq!(rest["priority"], "high");
+
+    let res = graphql(
+        &app,
+        &token,
+        "{ me { name } todos(tags: [\"work\"]) { id title priority owner { name } } }",
+        json!({}),
+    )
+    .await;
+    assert_eq!(res["data"]["me"]["name"], "alice");
+    assert_eq!(
+        res["data"]["todos"],
+        json!([{ "id": id, "title": "write schema", "priority": "HIGH", "owner": { "name": "alice" } }])
+    );
+
+    let update = r#"
+        mutation($id: ID!, $version: Int) {
+            updateTodo(id: $id, version: $version, input: { completed: true }) { completed version }
+        }
+    "#;
+    let res = graphql(&app, &token, update, json!({ "id": id, "version": 1 })).await;
+    assert_eq!(
+        res["data"]["updateTodo"],
+        json!({ "completed": true, "version": 2 })
+    );
+    // 版本过期和 If-Match 一样被拒绝
+    let res = graphql(&app, &token, update, json!({ "id": id, "version": 1 })).await;
+    assert_eq!(res["errors"][0]["extensions"]["code"], 412);
+
+    let delete = "mutation($id: ID!) { deleteTodo(id: $id) { id } }";
+    graphql(&app, &token, delete, json!({ "id": id })).await;
+    let res = graphql(
+        &app,
+        &token,
+        "query($id: ID!) { todo(id: $id) { id } }",
+        json!({ "id": id }),
+    )
+    .await;
+    assert_eq!(res["data"]["todo"], Value::Null);
+    assert_eq!(
+        app.get("/trash", &token).await.json()[0]["id"],
+        id.parse::<u64>().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn graphql_should_respect_ownership_and_lists() {
+    let app = TestApp::new();
+    let alice = app.login("alice@example.com").await;
+    let bob = app.login("bob@example.com").await;
+    let created = graphql(&app, &alice, CREATE, json!({ "title": "mine" })).await;
+    let id = created["data"]["createTodo"]["id"].clone();
+
+    let res = graphql(
+        &app,
+        &bob,
+        "query($id: ID!) { todo(id: $id) { id } }",
+        json!({ "id": id }),
+    )
+    .await;
+    assert_eq!(res["data"]["todo"], Value::Null);
+    let res = graphql(
+        &app,
+        &bob,
+        "mutation($id: ID!) { deleteTodo(id: $id) { id } }",
+        json!({ "id": id }),
+    )
+    .await;
+    assert_eq!(res["errors"][0]["extensions"]["code"], 404);
+
+    let list = app
+        .post("/lists", &alice, json!({ "name": "home" }))
+        .await
+        .json();
+    let res = graphql(
+        &app,
+        &alice,
+        r#"mutation($list: ID!) { createTodo(input: { title: "shared", listId: $list }) { listId } }"#,
+        json!({ "list": list["id"].to_string() }),
+    )
+    .await;
+    assert_eq!(res["data"]["createTodo"]["listId"], list["id"].to_string());
+    let res = graphql(
+        &app,
+        &alice,
+        "{ lists { name owner { name } todos { title owner { name } } } }",
+        json!({}),
+    )
+    .await;
+    assert_eq!(
+        res["data"]["lists"],
+        json!([{
+            "name": "home",
+            "owner": { "name": "alice" },
+            "todos": [{ "title": "shared", "owner": { "name": "alice" } }],
+        }])
+    );
+    let res = graphql(&app, &bob, "{ lists { name } }", json!({})).await;
+    assert_eq!(res["data"]["lists"], json!([]));
+}
+
+#[tokio::test]
+async fn graphql_should_check_auth_and_token_scopes() {
+    let app = TestApp::new();
+    let res = app
+        .request(Request::post("/graphql").json(json!({ "query": "{ me { name } }" })))
+        .await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+
+    let alice = app.login("alice@example.com").await;
+    let pat = app
+        .post(
+            "/tokens",
+            &alice,
+            json!({ "name": "ci", "scopes": ["read"] }),
+        )
+        .await
+        .json()["token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let res = graphql(&app, &pat, "{ todos { id } }", json!({})).await;
+    assert_eq!(res["data"]["todos"], json!([]));
+    let res = graphql(&app, &pat, CREATE, json!({ "title": "nope" })).await;
+    assert_eq!(res["errors"][0]["extensions"]["code"], 403);
+    assert_eq!(app.get("/todos", &alice).await.json(), json!([]));
+}
+
+#[tokio::test]
+async fn graphiql_page_should_be_served() {
+    let app = TestApp::new();
+    let res = app.request(Request::get("/graphql").empty()).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res
+        .header(header::CONTENT_TYPE)
+        .unwrap()
+        .starts_with("text/html"));
+    let html = res.text();
+    assert!(html.contains("/graphiql.js"));
+
+    // CDN 上的文件都固定了版本，并且只有这些能在页面上执行
+    let csp = res.header(header::CONTENT_SECURITY_POLICY).unwrap();
+    let cdn_urls: Vec<&str> = html
+        .split('"')
+        .filter(|part| part.starts_with("https://"))
+        .collect();
+    assert_eq!(cdn_urls.len(), 5);
+    for url in cdn_urls {
+        let version = url.split('@').nth(1).unwrap().split('/').next().unwrap();
+        assert_eq!(version.split('.').count(), 3, "{} is not pinned", url);
+        assert!(csp.contains(url), "{} is not allowed by the CSP", url);
+    }
+    assert!(!csp.contains("unsafe-eval"));
+
+    let res = app.request(Request::get("/graphiql.js").empty()).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res.text().contains("/graphql/ws"));
+    assert!(!res.text().contains("localStorage"));
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use axum::http::{header, Request, StatusCode};
+use common::{RequestBuilderExt, TestApp};
+use serde_json::json;
+
+async fn scrape(app: &TestApp) -> String {
+    let res = app.request(Request::get("/metrics").empty()).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert!(res
+        .header(header::CONTENT_TYPE)
+        .unwrap()
+        .starts_with("text/plain"));
+    res.text()
+}
+
+/// 找到名字和标签都匹配的那一行，返回它的值
+fn sample(metrics: &str, name: &str, labels: &[&str]) -> Option<f64> {
+    metrics
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .find(|line| line.starts_with(name) && labels.iter().all(|label| line.contains(label)))
+        .and_then(|line| line.rsplit(' ').next())
+        .and_then(|value| value.parse().ok())
+}
+
+#[tokio::test]
+async fn metrics_should_track_requests_by_route() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+    let todo = app
+        .post("/todos", &token, json!({ "title": "observe" }))
+        .await
+        .json();
+    app.get(&format!("/todos/{}", todo["id"]), &token).await;
+    app.get("/todos/999999", &token).await;
+
+    let metrics = scrape(&app).await;
+    let count = "axum_live_http_request_duration_seconds_count";
+    assert_eq!(
+        sample(
+            &metrics,
+            count,
+            &[r#"route="/todos/:id""#, r#"status="200""#]
+        ),
+        Some(1.0)
+    );
+    assert_eq!(
+        sample(
+            &metrics,
+            count,
+            &[r#"route="/todos/:id""#, r#"status="404""#]
+        ),
+        Some(1.0)
+    );
+    assert_eq!(
+        sample(&metrics, count, &[r#"route="/todos""#, r#"method="POST""#]),
+        Some(1.0)
+    );
+    assert_eq!(sample(&metrics, "axum_live_todos ", &[]), Some(1.0));
+    assert_eq!(sample(&metrics, "axum_live_active_users", &[]), Some(1.0));
+    assert_eq!(
+        sample(
+            &metrics,
+            "axum_live_store_operation_duration_seconds_count",
+            &[r#"operation="create""#]
+        ),
+        Some(1.0)
+    );
+}
+
+#[tokio::test]
+async fn metrics_should_count_auth_failures_by_reason() {
+    let app = TestApp::new();
+
+    app.request(Request::get("/todos").empty()).await;
+    app.get("/todos", "garbage").await;
+    app.get("/todos", "pat_unknown").await;
+    let expired = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &json!({ "id": 1, "name": "alice", "exp": 1 }),
+        &jsonwebtoken::EncodingKey::from_secret(b"abcdefghijklmnopqrstuvwxy"),
+    )
+    .unwrap();
+    app.get("/todos", &expired).await;
+    app.get("/lists", &expired).await;
+
+    let metrics = scrape(&app).await;
+    let failures = "axum_live_auth_failures_total";
+    assert_eq!(
+        sample(&metrics, failures, &[r#"reason="missing""#]),
+        Some(1.0)
+    );
+    assert_eq!(
+        sample(&metrics, failures, &[r#"reason="malformed""#]),
+        Some(1.0)
+    );
+    assert_eq!(
+        sample(&metrics, failures, &[r#"reason="invalid""#]),
+        Some(1.0)
+    );
+    assert_eq!(
+        sample(&metrics, failures, &[r#"reason="expired""#]),
+        Some(2.0)
+    );
+    assert_eq!(sample(&metrics, "axum_live_active_users", &[]), Some(0.0));
+}
@@ -0,0 +1,140 @@
+mod common;
+
+use axum::http::{header, Request, StatusCode};
+use axum_live::{AppState, MiddlewareConfig};
+use common::{RequestBuilderExt, TestApp};
+use serde_json::json;
+
+fn app_with(config: MiddlewareConfig) -> TestApp {
+    TestApp::with_state(AppState::new(config))
+}
+
+#[tokio::test]
+async fn rate_limit_should_reject_after_the_limit() {
+    let app = app_with(MiddlewareConfig {
+        user_rate_limit: 3,
+        ip_rate_limit: 2,
+        ..Default::default()
+    });
+
+    // 没登录的请求按 IP 算，登录本身也算一次
+    let token = app.login("alice@example.com").await;
+    let res = app.request(Request::get("/").empty()).await;
+    assert_eq!(res.status, StatusCode::OK);
+    let res = app.request(Request::get("/").empty()).await;
+    assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(res.header(header::RETRY_AFTER).is_some());
+
+    // 登录用户有自己的桶
+    for _ in 0..3 {
+        assert_eq!(app.get("/todos", &token).await.status, StatusCode::OK);
+    }
+    let res = app.get("/todos", &token).await;
+    assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn zero_rate_limit_should_disable_limiting() {
+    let app = app_with(MiddlewareConfig {
+        user_rate_limit: 0,
+        ip_rate_limit: 0,
+        ..Default::default()
+    });
+    let token = app.login("alice@example.com").await;
+    for _ in 0..5 {
+        let res = app.request(Request::get("/").empty()).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(app.get("/todos", &token).await.status, StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn cors_should_only_allow_configured_origins() {
+    let app = app_with(MiddlewareConfig {
+        cors_origins: vec!["https://todo.example.com".parse().unwrap()],
+        ..Default::default()
+    });
+
+    let preflight = |origin: &str| {
+        Request::options("/todos")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PATCH")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "if-match")
+            .empty()
+    };
+    let res = app.request(preflight("https://todo.example.com")).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.header(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+        Some("https://todo.example.com")
+    );
+    assert!(res
+        .header(header::ACCESS_CONTROL_ALLOW_METHODS)
+        .unwrap()
+        .contains("PATCH"));
+
+    let res = app.request(preflight("https://evil.example.com")).await;
+    assert_eq!(res.header(header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+}
+
+#[tokio::test]
+async fn request_ids_should_be_returned() {
+    let app = TestApp::new();
+
+    let res = app.request(Request::get("/").empty()).await;
+    let generated = res.header("x-request-id").unwrap();
+    assert!(!generated.is_empty());
+
+    // 调用方带了就沿用
+    let res = app
+        .request(Request::get("/").header("x-request-id", "abc-123").empty())
+        .await;
+    assert_eq!(res.header("x-request-id"), Some("abc-123"));
+}
+
+#[tokio::test]
+async fn large_bodies_should_be_rejected() {
+    let app = app_with(MiddlewareConfig {
+        body_limit: 64,
+        ..Default::default()
+    });
+    let token = app.login("alice@example.com").await;
+
+    let title = "x".repeat(100);
+    let res = app.post("/todos", &token, json!({ "title": title })).await;
+    assert_eq!(res.status, StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn responses_should_be_compressed() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+    for i in 0..10 {
+        app.post("/todos", &token, json!({ "title": format!("todo {}", i) }))
+            .await;
+    }
+
+    let res = app
+        .request(
+            Request::get("/todos")
+                .bearer(&token)
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.header(header::CONTENT_ENCODING), Some("gzip"));
+
+    // 分段返回的内容不压缩
+    let res = app
+        .request(
+            Request::get("/hello.js")
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .header(header::RANGE, "bytes=0-39")
+                .empty(),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.header(header::CONTENT_ENCODING), None);
+    assert_eq!(res.body.len(), 40);
+}
@@ -0,0 +1,191 @@
+mod common;
+
+use axum::http::StatusCode;
+use axum_live::AppState;
+use common::TestApp;
+use serde_json::json;
+use std::time::Duration;
+
+#[tokio::test]
+async fn deleted_todos_should_be_restorable() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+    let todo = app
+        .post("/todos", &token, json!({ "title": "oops" }))
+        .await
+        .json();
+    app.post("/todos", &token, json!({ "title": "other" }))
+        .await;
+    let uri = format!("/todos/{}", todo["id"]);
+
+    assert_eq!(
+        app.delete(&uri, &token).await.status,
+        StatusCode::NO_CONTENT
+    );
+    let trash = app.get("/trash", &token).await.json();
+    assert_eq!(trash.as_array().unwrap().len(), 1);
+    assert_eq!(trash[0]["id"], todo["id"]);
+    assert_eq!(trash[0]["title"], "oops");
+    assert!(trash[0]["purge_at"].as_u64() > trash[0]["deleted_at"].as_u64());
+
+    let res = app
+        .post(&format!("/trash/{}/restore", todo["id"]), &token, json!({}))
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let restored = res.json();
+    assert_eq!(restored["title"], "oops");
+    assert_eq!(restored["version"], 2);
+    // 恢复后放到最后
+    let todos = app.get("/todos", &token).await.json();
+    assert_eq!(todos[1]["title"], "oops");
+
+    assert_eq!(app.get(&uri, &token).await.status, StatusCode::OK);
+    assert_eq!(app.get("/trash", &token).await.json(), json!([]));
+    let res = app
+        .post(&format!("/trash/{}/restore", todo["id"]), &token, json!({}))
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn bulk_deletes_should_go_to_trash() {
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+    let first = app
+        .post("/todos", &token, json!({ "title": "a" }))
+        .await
+        .json();
+    app.post("/todos", &token, json!({ "title": "b" })).await;
+    app.post("/todos/complete-all", &token, json!({})).await;
+
+    app.post(
+        "/todos/batch",
+        &token,
+        json!({ "operations": [{ "op": "delete", "id": first["id"] }] }),
+    )
+    .await;
+    app.delete("/todos?completed=true", &token).await;
+    assert_eq!(app.get("/todos", &token).await.json(), json!([]));
+
+    let trash = app.get("/trash", &token).await.json();
+    assert_eq!(trash.as_array().unwrap().len(), 2);
+
+    // 回滚的批量操作不会进回收站
+    let todo = app
+        .post("/todos", &token, json!({ "title": "c" }))
+        .await
+        .json();
+    app.post(
+        "/todos/batch",
+        &token,
+        json!({ "operations": [
+            { "op": "delete", "id": todo["id"] },
+            { "op": "delete", "id": 999_999 },
+        ] }),
+    )
+    .await;
+    assert_eq!(
+        app.get("/trash", &token)
+            .await
+            .json()
+            .as_array()
+            .unwrap()
+            .len(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn trash_should_be_per_user() {
+    let app = TestApp::new();
+    let alice = app.login("alice@example.com").await;
+    let bob = app.login("bob@example.com").await;
+    let todo = app
+        .post("/todos", &alice, json!({ "title": "private" }))
+        .await
+        .json();
+    app.delete(&format!("/todos/{}", todo["id"]), &alice).await;
+
+    assert_eq!(app.get("/trash", &bob).await.json(), json!([]));
+    let res = app
+        .post(&format!("/trash/{}/restore", todo["id"]), &bob, json!({}))
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn shared_todos_need_editor_to_restore() {
+    let app = TestApp::new();
+    let alice = app.login("alice@example.com").await;
+    let bob = app.login("bob@example.com").await;
+    let list = app
+        .post("/lists", &alice, json!({ "name": "home" }))
+        .await
+        .json();
+    let list_uri = format!("/lists/{}", list["id"]);
+    let invitation = app
+        .post(
+            &format!("{}/invitations", list_uri),
+            &alice,
+            json!({ "email": "bob@example.com", "role": "viewer" }),
+        )
+        .await
+        .json();
+    app.post(
+        &format!("/invitations/{}/accept", invitation["id"]),
+        &bob,
+        json!({}),
+    )
+    .await;
+
+    let todo = app
+        .post(
+            &format!("{}/todos", list_uri),
+            &alice,
+            json!({ "title": "shared" }),
+        )
+        .await
+        .json();
+    app.delete(&format!("/todos/{}", todo["id"]), &alice).await;
+
+    // viewer 能看到但不能恢复
+    assert_eq!(app.get("/trash", &bob).await.json()[0]["id"], todo["id"]);
+    let restore = format!("/trash/{}/restore", todo["id"]);
+    let res = app.post(&restore, &bob, json!({})).await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+    let res = app.post(&restore, &alice, json!({})).await;
+    assert_eq!(res.status, StatusCode::OK);
+    let todos = app.get(&format!("{}/todos", list_uri), &bob).await.json();
+    assert_eq!(todos[0]["title"], "shared");
+}
+
+#[tokio::test]
+async fn trash_should_be_purged_after_retention() {
+    let app = TestApp::with_state(AppState::default().with_trash_retention(Duration::ZERO));
+    let token = app.login("alice@example.com").await;
+    let todo = app
+        .post("/todos", &token, json!({ "title": "gone" }))
+        .await
+        .json();
+    app.delete(&format!("/todos/{}", todo["id"]), &token).await;
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(app.get("/trash", &token).await.json(), json!([]));
+    let res = app
+        .post(&format!("/trash/{}/restore", todo["id"]), &token, json!({}))
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn changing_retention_should_keep_existing_todos() {
+    let state = AppState::default();
+    let app = TestApp::with_state(state.clone());
+    let token = app.login("alice@example.com").await;
+    app.post("/todos", &token, json!({ "title": "kept" })).await;
+
+    let app = TestApp::with_state(state.with_trash_retention(Duration::from_secs(60)));
+    let todos = app.get("/todos", &token).await.json();
+    assert_eq!(todos[0]["title"], "kept");
+}
@@ -0,0 +1,371 @@
+mod common;
+
+use axum::body::Bytes;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use axum::{Router, Server};
+use axum_live::{AppState, WebhookConfig};
+use common::TestApp;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+struct Received {
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+/// 在随机端口上起一个接收 webhook 的服务，前 `failures` 次请求返回 500
+async fn receiver(failures: usize) -> (String, mpsc::UnboundedReceiver<Received>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let app = Router::new().route(
+        "/hook",
+        post(move |headers: HeaderMap, body: Bytes| {
+            let tx = tx.clone();
+            let calls = calls.clone();
+            async move {
+                if calls.fetch_add(1, Ordering::SeqCst) < failures {
+                    return StatusCode::INTERNAL_SERVER_ERROR;
+                }
+                tx.send(Received { headers, body }).unwrap();
+                StatusCode::NO_CONTENT
+            }
+        }),
+    );
+    let server =
+        Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(app.into_make_service());
+    let url = format!("http://{}/hook", server.local_addr());
+    tokio::spawn(server);
+    (url, rx)
+}
+
+fn test_app() -> TestApp {
+    TestApp::with_state(AppState::default().with_webhook_config(WebhookConfig {
+        max_attempts: 3,
+        initial_backoff: Duration::from_millis(10),
+        request_timeout: Duration::from_secs(5),
+        // 接收方就在本机
+        allow_private_targets: true,
+    }))
+}
+
+async fn next(rx: &mut mpsc::UnboundedReceiver<Received>) -> (HeaderMap, Value) {
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("webhook not delivered in time")
+        .unwrap();
+    let body = serde_json::from_slice(&received.body).unwrap();
+    (received.headers, body)
+}
+
+/// 投递是异步的，轮询直到最新一条记录变成 `status`
+async fn wait_for_delivery(app: &TestApp, uri: &str, token: &str, status: &str) -> Value {
+    let mut log = Value::Null;
+    for _ in 0..100 {
+        log = app.get(uri, token).await.json();
+        if log[0]["status"] == status {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(log[0]["status"], status);
+    log
+}
+
+fn verify(secret: &str, headers: &HeaderMap, body: &[u8]) {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    let expected: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    assert_eq!(
+        headers["x-webhook-signature"].to_str().unwrap(),
+        format!("sha256={}", expected)
+    );
+}
+
+#[tokio::test]
+async fn webhooks_should_deliver_signed_events() {
+    let app = test_app();
+    let token = app.login("alice@example.com").await;
+    let (url, mut rx) = receiver(0).await;
+
+    let res = app.post("/webhooks", &token, json!({ "url": url })).await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    let secret = res.json()["secret"].as_str().unwrap().to_string();
+    let listed = app.get("/webhooks", &token).await.json();
+    assert!(listed[0].get("secret").is_none());
+
+    let todo = app
+        .post("/todos", &token, json!({ "title": "ship it" }))
+        .await
+        .json();
+    let uri = format!("/todos/{}", todo["id"]);
+    app.patch(&uri, &token, json!({ "completed": true })).await;
+    app.delete(&uri, &token).await;
+
+    let mut events = Vec::new();
+    for _ in 0..4 {
+        let received = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        verify(&secret, &received.headers, &received.body);
+        let body: Value = serde_json::from_slice(&received.body).unwrap();
+        assert_eq!(
+            received.headers["x-webhook-event"].to_str().unwrap(),
+            body["event"]
+        );
+        assert_eq!(body["todo"]["id"], todo["id"]);
+        events.push(body["event"].as_str().unwrap().to_string());
+    }
+    // 每次投递是单独的任务，到达顺序不固定
+    events.sort();
+    assert_eq!(events, ["completed", "created", "deleted", "updated"]);
+}
+
+#[tokio::test]
+async fn webhooks_should_filter_events_and_owners() {
+    let app = test_app();
+    let alice = app.login("alice@example.com").await;
+    let bob = app.login("bob@example.com").await;
+    let (url, mut rx) = receiver(0).await;
+
+    app.post(
+        "/webhooks",
+        &alice,
+        json!({ "url": url, "events": ["completed"] }),
+    )
+    .await;
+
+    // bob 的 todo 不会通知 alice
+    let todo = app
+        .post("/todos", &bob, json!({ "title": "bob" }))
+        .await
+        .json();
+    app.patch(
+        &format!("/todos/{}", todo["id"]),
+        &bob,
+        json!({ "completed": true }),
+    )
+    .await;
+
+    let todo = app
+        .post("/todos", &alice, json!({ "title": "alice" }))
+        .await
+        .json();
+    let uri = format!("/todos/{}", todo["id"]);
+    app.patch(&uri, &alice, json!({ "title": "renamed" })).await;
+    app.patch(&uri, &alice, json!({ "completed": true })).await;
+    // 已经完成的再更新不会重复发送 completed
+    app.patch(&uri, &alice, json!({ "title": "again" })).await;
+
+    let (_, body) = next(&mut rx).await;
+    assert_eq!(body["event"], "completed");
+    assert_eq!(body["todo"]["title"], "renamed");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn webhooks_should_notify_members_of_deleted_lists() {
+    let app = test_app();
+    let alice = app.login("alice@example.com").await;
+    let bob = app.login("bob@example.com").await;
+    let (url, mut rx) = receiver(0).await;
+
+    let list = format!(
+        "/lists/{}",
+        app.post("/lists", &alice, json!({ "name": "groceries" }))
+            .await
+            .json()["id"]
+    );
+    let invitation = app
+        .post(
+            &format!("{}/invitations", list),
+            &alice,
+            json!({ "email": "bob@example.com", "role": "viewer" }),
+        )
+        .await
+        .json()["id"]
+        .clone();
+    app.post(
+        &format!("/invitations/{}/accept", invitation),
+        &bob,
+        json!({}),
+    )
+    .await;
+    app.post(
+        &format!("{}/todos", list),
+        &alice,
+        json!({ "title": "milk" }),
+    )
+    .await;
+
+    app.post(
+        "/webhooks",
+        &bob,
+        json!({ "url": url, "events": ["deleted"] }),
+    )
+    .await;
+    app.delete(&list, &alice).await;
+
+    let (_, body) = next(&mut rx).await;
+    assert_eq!(body["event"], "deleted");
+    assert_eq!(body["todo"]["title"], "milk");
+}
+
+#[tokio::test]
+async fn webhooks_should_retry_and_log_deliveries() {
+    let app = test_app();
+    let token = app.login("alice@example.com").await;
+    let (url, mut rx) = receiver(2).await;
+
+    let webhook = app
+        .post(
+            "/webhooks",
+            &token,
+            json!({ "url": url, "events": ["created"] }),
+        )
+        .await
+        .json();
+    let deliveries = format!("/webhooks/{}/deliveries", webhook["id"]);
+
+    app.post("/todos", &token, json!({ "title": "retry me" }))
+        .await;
+    let (headers, body) = next(&mut rx).await;
+    assert_eq!(body["event"], "created");
+
+    let log = wait_for_delivery(&app, &deliveries, &token, "succeeded").await;
+    assert_eq!(log.as_array().unwrap().len(), 1);
+    assert_eq!(
+        log[0]["id"].to_string(),
+        headers["x-webhook-delivery"].to_str().unwrap()
+    );
+    let attempts = log[0]["attempts"].as_array().unwrap();
+    assert_eq!(attempts.len(), 3);
+    assert_eq!(attempts[0]["status_code"], 500);
+    assert_eq!(attempts[2]["status_code"], 204);
+
+    let bob = app.login("bob@example.com").await;
+    assert_eq!(
+        app.get(&deliveries, &bob).await.status,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn webhooks_should_give_up_after_max_attempts() {
+    let app = test_app();
+    let token = app.login("alice@example.com").await;
+    let (url, _rx) = receiver(usize::MAX).await;
+
+    let webhook = app
+        .post("/webhooks", &token, json!({ "url": url }))
+        .await
+        .json();
+    app.post("/todos", &token, json!({ "title": "never delivered" }))
+        .await;
+
+    let deliveries = format!("/webhooks/{}/deliveries", webhook["id"]);
+    let log = wait_for_delivery(&app, &deliveries, &token, "failed").await;
+    assert_eq!(log[0]["attempts"].as_array().unwrap().len(), 3);
+
+    let uri = format!("/webhooks/{}", webhook["id"]);
+    assert_eq!(
+        app.delete(&uri, &token).await.status,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(app.get("/webhooks", &token).await.json(), json!([]));
+}
+
+#[tokio::test]
+async fn webhooks_should_reject_invalid_urls() {
+    let app = test_app();
+    let token = app.login("alice@example.com").await;
+
+    for url in ["not a url", "ftp://example.com/hook"] {
+        let res = app.post("/webhooks", &token, json!({ "url": url })).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    }
+    let res = app
+        .post(
+            "/webhooks",
+            &token,
+            json!({ "url": "http://example.com", "events": ["exploded"] }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    // 默认不能投递到本机和内网
+    let app = TestApp::new();
+    let token = app.login("alice@example.com").await;
+    for url in [
+        "http://127.0.0.1:8080/hook",
+        "http://localhost/hook",
+        "http://10.1.2.3/hook",
+        "http://192.168.0.1/hook",
+        "http://169.254.169.254/latest/meta-data",
+        "http://[::1]/hook",
+        "http://[::ffff:127.0.0.1]/hook",
+        "http://[fd00::1]/hook",
+        "http://0.1.2.3/hook",
+        "http://198.18.0.1/hook",
+        "http://192.0.0.8/hook",
+        "http://240.0.0.1/hook",
+        "http://224.0.0.1/hook",
+        "http://[64:ff9b::a9fe:a9fe]/hook",
+        "http://[2002:a9fe:a9fe::1]/hook",
+    ] {
+        let res = app.post("/webhooks", &token, json!({ "url": url })).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", url);
+    }
+    let res = app
+        .post(
+            "/webhooks",
+            &token,
+            json!({ "url": "http://93.184.216.34/hook" }),
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn webhooks_should_not_follow_redirects() {
+    let app = test_app();
+    let token = app.login("alice@example.com").await;
+    let (target, mut rx) = receiver(0).await;
+    // 注册的地址把请求重定向到别的地址，比如内网
+    let redirect = Router::new().route(
+        "/hook",
+        post(move || {
+            let target = target.clone();
+            async move { (StatusCode::FOUND, [(axum::http::header::LOCATION, target)]) }
+        }),
+    );
+    let server =
+        Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(redirect.into_make_service());
+    let url = format!("http://{}/hook", server.local_addr());
+    tokio::spawn(server);
+
+    let webhook = app
+        .post("/webhooks", &token, json!({ "url": url }))
+        .await
+        .json();
+    app.post("/todos", &token, json!({ "title": "redirected" }))
+        .await;
+
+    let uri = format!("/webhooks/{}/deliveries", webhook["id"]);
+    let log = wait_for_delivery(&app, &uri, &token, "failed").await;
+    assert_eq!(log[0]["attempts"][0]["status_code"], 302);
+    assert!(rx.try_recv().is_err(), "redirect should not be followed");
+}
@@ -0,0 +1,107 @@
+//! Fan-out cost of a chat message when thousands of rooms are open.
+//!
+//! Every connection sits in its own room and one room gets `MESSAGES`
+//! messages. With per-room channels only the members of that room do any
+//! work; the `global` baseline is the old single broadcast channel, where
+//! every connection receives every message and filters out other rooms.
+//!
+//! Run with `cargo bench -p ws-server`.
+
+use axum::extract::ws::Message;
+use fake_socket::{create_fake_connection, FakeClient};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+use ws_server::{handle_socket, ChatState};
+use ws_shared::Msg;
+
+const MESSAGES: usize = 1_000;
+const ROOMS: &[usize] = &[10, 100, 1_000, 5_000];
+
+fn main() {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    println!(
+        "{:>8} {:>16} {:>16}",
+        "rooms", "per-room (µs/msg)", "global (µs/msg)"
+    );
+    for &rooms in ROOMS {
+        let per_room = rt.block_on(per_room(rooms));
+        let global = rt.block_on(global(rooms));
+        println!(
+            "{:>8} {:>16.2} {:>16.2}",
+            rooms,
+            per_message(per_room),
+            per_message(global)
+        );
+    }
+}
+
+fn per_message(elapsed: Duration) -> f64 {
+    elapsed.as_secs_f64() * 1e6 / MESSAGES as f64
+}
+
+async fn per_room(rooms: usize) -> Duration {
+    let state = ChatState::new();
+    let mut clients: Vec<FakeClient<Message>> = Vec::with_capacity(rooms);
+    for i in 0..rooms {
+        let (mut client, socket) = create_fake_connection();
+        tokio::spawn(handle_socket(socket, state.clone(), format!("user{i}")));
+        send(
+            &client,
+            &Msg::join(&format!("room{i}"), &format!("user{i}")),
+        );
+        // wait for the join to come back, so the room is subscribed
+        client.recv().await.unwrap();
+        clients.push(client);
+    }
+
+    let client = &mut clients[0];
+    let start = Instant::now();
+    for i in 0..MESSAGES {
+        send(client, &Msg::message("room0", "user0", &i.to_string()));
+        client.recv().await.unwrap();
+    }
+    start.elapsed()
+}
+
+/// the old behaviour: one channel for everything, filtered by each connection
+async fn global(rooms: usize) -> Duration {
+    let (tx, _) = broadcast::channel::<Arc<Msg>>(MESSAGES);
+    let (done_tx, mut done_rx) = mpsc::channel(rooms);
+    for i in 0..rooms {
+        let mut rx = tx.subscribe();
+        let done = done_tx.clone();
+        let room = format!("room{i}");
+        tokio::spawn(async move {
+            let mut seen = 0;
+            while let Ok(msg) = rx.recv().await {
+                seen += 1;
+                if msg.room == room {
+                    let _: String = msg.as_ref().try_into().unwrap();
+                }
+                if seen == MESSAGES {
+                    break;
+                }
+            }
+            done.send(()).await.unwrap();
+        });
+    }
+
+    let start = Instant::now();
+    for i in 0..MESSAGES {
+        tx.send(Arc::new(Msg::message("room0", "user0", &i.to_string())))
+            .unwrap();
+    }
+    for _ in 0..rooms {
+        done_rx.recv().await.unwrap();
+    }
+    start.elapsed()
+}
+
+fn send(client: &FakeClient<Message>, msg: &Msg) {
+    client.send(Message::Text(msg.try_into().unwrap())).unwrap();
+}
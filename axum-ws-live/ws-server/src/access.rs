@@ -0,0 +1,221 @@
+use dashmap::mapref::entry::Entry;
+use std::collections::HashSet;
+
+use ws_shared::{dm_peer, DM_PREFIX};
+
+use crate::{BusEvent, State};
+
+/// A room only its owner and the users they let in can join
+#[derive(Debug)]
+pub(crate) struct PrivateRoom {
+    owner: String,
+    invited: HashSet<String>,
+    // users waiting for the owner to approve their `RequestJoin`
+    pending: HashSet<String>,
+}
+
+impl PrivateRoom {
+    fn allows(&self, username: &str) -> bool {
+        self.owner == username || self.invited.contains(username)
+    }
+}
+
+impl State {
+    /// public rooms are open to anyone, private ones to their owner and
+    /// invited users, direct messages to their two users
+    pub(crate) fn can_join(&self, username: &str, room: &str) -> Result<(), &'static str> {
+        if room.starts_with(DM_PREFIX) {
+            return dm_peer(room, username)
+                .map(|_| ())
+                .ok_or("not part of this conversation");
+        }
+        match self.private_rooms.get(room) {
+            Some(private) if !private.allows(username) => Err("not invited to this room"),
+            _ => Ok(()),
+        }
+    }
+
+    /// tell the other nodes who may join the room
+    pub(crate) fn share_private(&self, room: &str) {
+        let (Some(bus), Some(private)) = (&self.bus, self.private_rooms.get(room)) else {
+            return;
+        };
+        bus.publish(BusEvent::PrivateRoom {
+            node: self.node.clone(),
+            room: room.to_string(),
+            owner: private.owner.clone(),
+            invited: private.invited.clone(),
+            pending: private.pending.clone(),
+        });
+    }
+
+    /// add what another node knows about a private room. Users are only ever
+    /// let in, so merging converges whatever order the nodes hear it in.
+    /// Returns the members on this node who aren't let in
+    pub(crate) fn merge_private(
+        &self,
+        room: &str,
+        owner: String,
+        invited: HashSet<String>,
+        pending: HashSet<String>,
+    ) -> Result<Vec<String>, &'static str> {
+        if room.starts_with(DM_PREFIX) {
+            return Err("reserved room name");
+        }
+        let mut private = self
+            .private_rooms
+            .entry(room.to_string())
+            .or_insert_with(|| PrivateRoom {
+                owner: owner.clone(),
+                invited: HashSet::new(),
+                pending: HashSet::new(),
+            });
+        if private.owner != owner {
+            return Err("room has another owner");
+        }
+        private.invited.extend(invited);
+        private.pending.extend(pending);
+        let PrivateRoom {
+            invited, pending, ..
+        } = &mut *private;
+        pending.retain(|username| !invited.contains(username));
+        drop(private);
+
+        if let Some(mut users) = self.remote_users.get_mut(room) {
+            users.retain(|username, _| self.can_join(username, room).is_ok());
+        }
+        self.remote_users
+            .remove_if(room, |_, users| users.is_empty());
+        Ok(self
+            .users_of(room)
+            .into_iter()
+            .filter(|username| self.can_join(username, room).is_err())
+            .collect())
+    }
+
+    pub(crate) fn create_private(&self, owner: &str, room: &str) -> Result<(), &'static str> {
+        if room.starts_with(DM_PREFIX) {
+            return Err("reserved room name");
+        }
+        if self.room_users.contains_key(room) || self.remote_users.contains_key(room) {
+            return Err("room already exists");
+        }
+        match self.private_rooms.entry(room.to_string()) {
+            Entry::Occupied(_) => Err("room already exists"),
+            Entry::Vacant(entry) => {
+                entry.insert(PrivateRoom {
+                    owner: owner.to_string(),
+                    invited: HashSet::new(),
+                    pending: HashSet::new(),
+                });
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn invite(
+        &self,
+        owner: &str,
+        room: &str,
+        invitee: &str,
+    ) -> Result<(), &'static str> {
+        let mut private = self.owned(owner, room)?;
+        private.pending.remove(invitee);
+        private.invited.insert(invitee.to_string());
+        Ok(())
+    }
+
+    /// returns the owner to ask
+    pub(crate) fn request_join(&self, username: &str, room: &str) -> Result<String, &'static str> {
+        let mut private = self
+            .private_rooms
+            .get_mut(room)
+            .ok_or("not a private room")?;
+        if private.allows(username) {
+            return Err("already invited to this room");
+        }
+        private.pending.insert(username.to_string());
+        Ok(private.owner.clone())
+    }
+
+    pub(crate) fn approve(
+        &self,
+        owner: &str,
+        room: &str,
+        requester: &str,
+    ) -> Result<(), &'static str> {
+        let mut private = self.owned(owner, room)?;
+        if !private.pending.remove(requester) {
+            return Err("no such join request");
+        }
+        private.invited.insert(requester.to_string());
+        Ok(())
+    }
+
+    fn owned(
+        &self,
+        owner: &str,
+        room: &str,
+    ) -> Result<dashmap::mapref::one::RefMut<'_, String, PrivateRoom>, &'static str> {
+        let private = self
+            .private_rooms
+            .get_mut(room)
+            .ok_or("not a private room")?;
+        if private.owner != owner {
+            return Err("only the owner can do this");
+        }
+        Ok(private)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ws_shared::dm_room;
+
+    #[test]
+    fn private_rooms_should_only_let_allowed_users_in() {
+        let state = State::default();
+        assert!(state.can_join("bob", "secret").is_ok());
+        state.create_private("tyr", "secret").unwrap();
+        assert_eq!(
+            state.create_private("bob", "secret"),
+            Err("room already exists")
+        );
+        assert_eq!(
+            state.can_join("bob", "secret"),
+            Err("not invited to this room")
+        );
+        assert!(state.can_join("tyr", "secret").is_ok());
+
+        assert_eq!(
+            state.invite("bob", "secret", "bob"),
+            Err("only the owner can do this")
+        );
+        state.invite("tyr", "secret", "alice").unwrap();
+        assert!(state.can_join("alice", "secret").is_ok());
+
+        assert_eq!(
+            state.approve("tyr", "secret", "bob"),
+            Err("no such join request")
+        );
+        assert_eq!(state.request_join("bob", "secret"), Ok("tyr".into()));
+        state.approve("tyr", "secret", "bob").unwrap();
+        assert!(state.can_join("bob", "secret").is_ok());
+    }
+
+    #[test]
+    fn direct_messages_should_only_let_their_users_in() {
+        let state = State::default();
+        let room = dm_room("tyr", "alice");
+        assert!(state.can_join("alice", &room).is_ok());
+        assert_eq!(
+            state.can_join("bob", &room),
+            Err("not part of this conversation")
+        );
+        assert_eq!(
+            state.create_private("bob", &room),
+            Err("reserved room name")
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+};
+use jsonwebtoken as jwt;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+use tracing::log::warn;
+
+use crate::ChatState;
+
+// same default as the todo service, so its login tokens work here as well
+pub const DEFAULT_SECRET: &[u8] = b"abcdefghijklmnopqrstuvwxy";
+
+/// JWT claims. `name` is the username the connection is bound to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub name: String,
+    pub exp: u64,
+}
+
+impl Claims {
+    pub fn new(name: &str, ttl: Duration) -> Self {
+        let exp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            + ttl;
+        Self {
+            name: name.into(),
+            exp: exp.as_secs(),
+        }
+    }
+
+    pub fn encode(&self, secret: &[u8]) -> Result<String, jwt::errors::Error> {
+        jwt::encode(
+            &jwt::Header::default(),
+            self,
+            &jwt::EncodingKey::from_secret(secret),
+        )
+    }
+
+    pub fn decode(token: &str, secret: &[u8]) -> Result<Self, jwt::errors::Error> {
+        let key = jwt::DecodingKey::from_secret(secret);
+        jwt::decode::<Claims>(token, &key, &jwt::Validation::default()).map(|data| data.claims)
+    }
+}
+
+/// browsers can't set headers on a WebSocket handshake, so the token may
+/// also come as `?token=...`. The header wins if both are present
+fn token_from_parts(parts: &Parts) -> Option<String> {
+    let bearer = parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+    // JWTs are url-safe, no need to percent-decode
+    bearer.or_else(|| {
+        parts
+            .uri
+            .query()?
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+            .map(|token| token.to_string())
+    })
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Claims
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let state = parts
+            .extensions
+            .get::<ChatState>()
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "missing chat state"))?;
+        let token = token_from_parts(parts).ok_or((StatusCode::UNAUTHORIZED, "missing token"))?;
+        Claims::decode(&token, state.secret()).map_err(|e| {
+            warn!("invalid token: {e}");
+            (StatusCode::UNAUTHORIZED, "invalid token")
+        })
+    }
+}
@@ -0,0 +1,648 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::log::{debug, warn};
+
+use ws_shared::{dm_peer, Msg, MsgData, Status};
+
+use crate::{handle_message, Claims, History, State};
+
+// events a node buffers for its relay task, and for each of its peers
+const BUS_CAPACITY: usize = 1024;
+// wait between attempts to reach a peer
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+// longest line a peer may send, it's dropped when it sends more
+const MAX_FRAME: usize = 1024 * 1024;
+// how long the token a node introduces itself with is valid
+const HANDSHAKE_TTL: Duration = Duration::from_secs(60);
+
+/// What the nodes of a cluster tell each other
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum BusEvent {
+    /// a message delivered to a room on `node`, presence changes included
+    Room {
+        node: String,
+        msg: Msg,
+        #[serde(default)]
+        private: bool,
+    },
+    /// who may join a private room, as far as `node` knows
+    PrivateRoom {
+        node: String,
+        room: String,
+        owner: String,
+        invited: HashSet<String>,
+        pending: HashSet<String>,
+    },
+    /// a message for every connection of `username`, with `join` the user
+    /// also joins the room of the message
+    Notify {
+        node: String,
+        username: String,
+        msg: Msg,
+        join: bool,
+    },
+    /// the peer at this address can be reached again, tell it what it missed
+    NodeUp(String),
+    /// `node` can't be reached anymore, forget about its users
+    NodeDown(String),
+}
+
+/// Carries room messages between the nodes of a cluster, so users connected
+/// to different nodes can talk to each other. Every node keeps the messages
+/// it hears about in its history, missing what was sent while it was down.
+/// Nodes also share who owns the private rooms and who they let in, and
+/// ignore the messages of a private room they don't know about yet or of a
+/// user not let in
+pub trait Bus: Debug + Send + Sync + 'static {
+    /// hand the event to the other nodes, must not block
+    fn publish(&self, event: BusEvent);
+    /// events of the other nodes. A node may get its own events back too
+    fn subscribe(&self) -> broadcast::Receiver<BusEvent>;
+    /// events given up on because a node was unreachable or too slow
+    fn dropped(&self) -> u64 {
+        0
+    }
+}
+
+/// Nodes living in the same process, for tests
+#[derive(Debug, Clone)]
+pub struct MemoryBus {
+    tx: broadcast::Sender<BusEvent>,
+}
+
+impl Default for MemoryBus {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(BUS_CAPACITY).0,
+        }
+    }
+}
+
+impl MemoryBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Bus for MemoryBus {
+    fn publish(&self, event: BusEvent) {
+        // no node listening is fine
+        let _ = self.tx.send(event);
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<BusEvent> {
+        self.tx.subscribe()
+    }
+}
+
+/// Nodes connected peer to peer over TCP. Every node dials the peers it is
+/// given and sends its events as JSON lines, and reads the events of the
+/// peers dialing it. A peer first sends a JWT naming its node, signed with
+/// the secret all nodes share, and is dropped if it can't. Events published
+/// while a peer is unreachable, or faster than it takes them, are lost
+#[derive(Debug, Clone)]
+pub struct TcpBus {
+    inner: Arc<TcpInner>,
+}
+
+#[derive(Debug)]
+struct TcpInner {
+    node: String,
+    local_addr: SocketAddr,
+    secret: Arc<[u8]>,
+    incoming: broadcast::Sender<BusEvent>,
+    // one writer task per peer
+    peers: DashMap<SocketAddr, Peer>,
+    dropped: AtomicU64,
+    // stops accepting peers when the bus is dropped
+    _shutdown: oneshot::Sender<()>,
+}
+
+#[derive(Debug)]
+struct Peer {
+    tx: mpsc::Sender<Arc<String>>,
+    connected: Arc<AtomicBool>,
+}
+
+impl TcpBus {
+    /// listen for peers on `addr`, accepting those that know `secret`
+    pub async fn bind(node: &str, addr: impl ToSocketAddrs, secret: &[u8]) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let secret: Arc<[u8]> = secret.into();
+        let (incoming, _) = broadcast::channel(BUS_CAPACITY);
+        let (tx, key) = (incoming.clone(), secret.clone());
+        let (shutdown, mut dropped) = oneshot::channel();
+        tokio::spawn(async move {
+            loop {
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    _ = &mut dropped => return,
+                };
+                match accepted {
+                    Ok((stream, addr)) => {
+                        debug!("peer connected from {addr}");
+                        tokio::spawn(read_peer(stream, key.clone(), tx.clone()));
+                    }
+                    Err(e) => warn!("failed to accept peer: {e}"),
+                }
+            }
+        });
+        Ok(Self {
+            inner: Arc::new(TcpInner {
+                node: node.to_string(),
+                local_addr,
+                secret,
+                incoming,
+                peers: DashMap::default(),
+                dropped: AtomicU64::new(0),
+                _shutdown: shutdown,
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr
+    }
+
+    /// how many of the peers it was given the bus can send to right now
+    pub fn connected_peers(&self) -> usize {
+        self.inner
+            .peers
+            .iter()
+            .filter(|peer| peer.connected.load(Ordering::Relaxed))
+            .count()
+    }
+
+    /// send events to the peer from now on, reconnecting whenever the
+    /// connection drops
+    pub fn connect(&self, peer: SocketAddr) {
+        if peer == self.inner.local_addr || self.inner.peers.contains_key(&peer) {
+            return;
+        }
+        let (tx, rx) = mpsc::channel(BUS_CAPACITY);
+        let connected = Arc::new(AtomicBool::new(false));
+        self.inner.peers.insert(
+            peer,
+            Peer {
+                tx,
+                connected: connected.clone(),
+            },
+        );
+        let (node, secret) = (self.inner.node.clone(), self.inner.secret.clone());
+        let incoming = self.inner.incoming.clone();
+        tokio::spawn(write_peer(node, secret, peer, connected, incoming, rx));
+    }
+}
+
+impl Bus for TcpBus {
+    fn publish(&self, event: BusEvent) {
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => Arc::new(line + "\n"),
+            Err(e) => {
+                warn!("failed to encode bus event: {e}");
+                return;
+            }
+        };
+        for peer in self.inner.peers.iter() {
+            if !peer.connected.load(Ordering::Relaxed) || peer.tx.try_send(line.clone()).is_err() {
+                self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<BusEvent> {
+        self.inner.incoming.subscribe()
+    }
+
+    fn dropped(&self) -> u64 {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// the next line without its newline, an error if it's longer than `MAX_FRAME`
+async fn read_frame(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let read = reader
+        .take(MAX_FRAME as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        let e = if read > MAX_FRAME {
+            "line too long"
+        } else {
+            "unexpected end of stream"
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+async fn read_peer(stream: TcpStream, secret: Arc<[u8]>, incoming: broadcast::Sender<BusEvent>) {
+    let mut reader = BufReader::new(stream);
+    // the first line is a token saying which node is talking
+    let node = match read_frame(&mut reader).await {
+        Ok(Some(token)) => match Claims::decode(&token, &secret) {
+            Ok(claims) => claims.name,
+            Err(e) => {
+                warn!("dropping bus peer with invalid token: {e}");
+                return;
+            }
+        },
+        _ => return,
+    };
+    loop {
+        match read_frame(&mut reader).await {
+            Ok(Some(line)) => match serde_json::from_str(&line) {
+                Ok(event) => {
+                    let _ = incoming.send(event);
+                }
+                Err(e) => warn!("invalid bus event from {node}: {e}"),
+            },
+            Ok(None) => break,
+            Err(e) => {
+                warn!("failed to read from {node}: {e}");
+                break;
+            }
+        }
+    }
+    let _ = incoming.send(BusEvent::NodeDown(node));
+}
+
+async fn write_peer(
+    node: String,
+    secret: Arc<[u8]>,
+    peer: SocketAddr,
+    connected: Arc<AtomicBool>,
+    incoming: broadcast::Sender<BusEvent>,
+    mut rx: mpsc::Receiver<Arc<String>>,
+) {
+    let mut retry = false;
+    loop {
+        connected.store(false, Ordering::Relaxed);
+        if retry {
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+        retry = true;
+        let mut stream = match TcpStream::connect(peer).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("failed to reach {peer}: {e}");
+                continue;
+            }
+        };
+        // drop what was queued before the connection broke, it's stale by now
+        while rx.try_recv().is_ok() {}
+        let token = match Claims::new(&node, HANDSHAKE_TTL).encode(&secret) {
+            Ok(token) => token,
+            Err(e) => {
+                warn!("failed to sign bus token: {e}");
+                return;
+            }
+        };
+        if stream
+            .write_all(format!("{token}\n").as_bytes())
+            .await
+            .is_err()
+        {
+            continue;
+        }
+        connected.store(true, Ordering::Relaxed);
+        let _ = incoming.send(BusEvent::NodeUp(peer.to_string()));
+        loop {
+            // the bus is gone
+            let Some(line) = rx.recv().await else {
+                return;
+            };
+            if let Err(e) = stream.write_all(line.as_bytes()).await {
+                warn!("failed to send to {peer}: {e}");
+                break;
+            }
+        }
+    }
+}
+
+/// deliver the events of the other nodes to this one, until it's dropped
+pub(crate) async fn relay(state: Weak<State>, mut events: broadcast::Receiver<BusEvent>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("lost {n} events from the bus");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        state.receive(event).await;
+    }
+}
+
+impl State {
+    pub(crate) fn publish(&self, msg: &Msg) {
+        if let Some(bus) = &self.bus {
+            bus.publish(BusEvent::Room {
+                node: self.node.clone(),
+                msg: msg.clone(),
+                private: self.private_rooms.contains_key(&msg.room),
+            });
+        }
+    }
+
+    /// send a message to every connection of the user in the cluster, with
+    /// `join` the user also joins the room of the message
+    pub(crate) async fn notify_cluster(self: &Arc<Self>, username: &str, msg: Msg, join: bool) {
+        if let Some(bus) = &self.bus {
+            bus.publish(BusEvent::Notify {
+                node: self.node.clone(),
+                username: username.to_string(),
+                msg: msg.clone(),
+                join,
+            });
+        }
+        self.join_notified(username, msg, join).await;
+    }
+
+    async fn join_notified(self: &Arc<Self>, username: &str, msg: Msg, join: bool) {
+        let room = msg.room.clone();
+        self.notify(username, msg);
+        if join
+            && self.status(username) != Status::Offline
+            && !self.is_member(username, &room)
+            && self.can_join(username, &room).is_ok()
+        {
+            handle_message(Msg::join(&room, username), self.clone()).await;
+        }
+    }
+
+    async fn receive(self: Arc<Self>, event: BusEvent) {
+        match event {
+            BusEvent::Room { node, .. }
+            | BusEvent::PrivateRoom { node, .. }
+            | BusEvent::Notify { node, .. }
+                if node == self.node => {}
+            // a private room this node doesn't know about yet, someone else's
+            // public room of the same name, or a user not let in
+            BusEvent::Room { msg, private, .. }
+                if private != self.private_rooms.contains_key(&msg.room)
+                    || self.can_join(&msg.username, &msg.room).is_err() => {}
+            // an edit or reaction could point at a message of this node
+            BusEvent::Room { node, msg, .. }
+                if msg.id != 0 && History::node_of(msg.id) == self.history.node() =>
+            {
+                warn!("node {node} uses the node index of this node, ignoring its messages");
+            }
+            BusEvent::Room { node, msg, .. } => {
+                self.track_remote(&node, &msg);
+                // the id is unique in the cluster, edits and reactions can
+                // point at it on this node too
+                if matches!(
+                    msg.data,
+                    MsgData::Message(_)
+                        | MsgData::Edit { .. }
+                        | MsgData::Delete { .. }
+                        | MsgData::Reaction { .. }
+                ) {
+                    self.history.insert(&msg).await;
+                }
+                // a direct message pulls the user on this node into the conversation
+                if let (MsgData::Message(_), Some(peer)) =
+                    (&msg.data, dm_peer(&msg.room, &msg.username))
+                {
+                    if self.status(peer) != Status::Offline && !self.is_member(peer, &msg.room) {
+                        handle_message(Msg::join(&msg.room, peer), self.clone()).await;
+                    }
+                }
+                let Some(tx) = self.rooms.get(&msg.room).map(|tx| tx.clone()) else {
+                    return;
+                };
+                let _ = tx.send(Arc::new(msg));
+            }
+            BusEvent::PrivateRoom {
+                node,
+                room,
+                owner,
+                invited,
+                pending,
+            } => match self.merge_private(&room, owner, invited, pending) {
+                // members let in before the room was known to be private
+                Ok(outsiders) => {
+                    for username in outsiders {
+                        handle_message(Msg::new(&room, &username, MsgData::Leave), self.clone())
+                            .await;
+                    }
+                }
+                Err(reason) => warn!("ignoring private room {room} of node {node}: {reason}"),
+            },
+            BusEvent::Notify {
+                username,
+                msg,
+                join,
+                ..
+            } => self.join_notified(&username, msg, join).await,
+            BusEvent::NodeUp(_) => {
+                let rooms: Vec<_> = self.private_rooms.iter().map(|r| r.key().clone()).collect();
+                for room in rooms {
+                    self.share_private(&room);
+                }
+            }
+            BusEvent::NodeDown(node) => {
+                for mut users in self.remote_users.iter_mut() {
+                    users.retain(|_, (from, _)| *from != node);
+                }
+                self.remote_users.retain(|_, users| !users.is_empty());
+            }
+        }
+    }
+
+    /// keep track of who is in which room on the other nodes
+    fn track_remote(&self, node: &str, msg: &Msg) {
+        let (room, username) = (&msg.room, &msg.username);
+        match msg.data {
+            MsgData::Join => {
+                let remote = (node.to_string(), Status::Online);
+                self.remote_users
+                    .entry(room.clone())
+                    .or_default()
+                    .insert(username.clone(), remote);
+            }
+            MsgData::Leave => {
+                if let Some(mut users) = self.remote_users.get_mut(room) {
+                    users.remove(username);
+                }
+                self.remote_users
+                    .remove_if(room, |_, users| users.is_empty());
+            }
+            MsgData::Presence(status) => {
+                if let Some(mut users) = self.remote_users.get_mut(room) {
+                    if let Some(remote) = users.get_mut(username) {
+                        remote.1 = status;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"bus secret";
+
+    /// the next event, other than a peer coming up
+    async fn recv(rx: &mut broadcast::Receiver<BusEvent>) -> BusEvent {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .expect("no bus event")
+                .unwrap();
+            if !matches!(event, BusEvent::NodeUp(_)) {
+                return event;
+            }
+        }
+    }
+
+    pub(crate) async fn wait_for_peers(bus: &TcpBus, peers: usize) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while bus.connected_peers() < peers {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("peers not connected");
+    }
+
+    #[tokio::test]
+    async fn tcp_bus_should_relay_between_nodes() {
+        let mut nodes = Vec::new();
+        for node in ["a", "b", "c"] {
+            nodes.push(TcpBus::bind(node, "127.0.0.1:0", SECRET).await.unwrap());
+        }
+        for bus in &nodes {
+            for peer in &nodes {
+                bus.connect(peer.local_addr());
+            }
+        }
+        let mut b = nodes[1].subscribe();
+        let mut c = nodes[2].subscribe();
+        wait_for_peers(&nodes[0], 2).await;
+
+        let event = BusEvent::Room {
+            node: "a".into(),
+            msg: Msg::message("lobby", "tyr", "hello"),
+            private: false,
+        };
+        nodes[0].publish(event.clone());
+        assert_eq!(recv(&mut b).await, event);
+        assert_eq!(recv(&mut c).await, event);
+
+        // a going away is noticed by the others
+        drop(nodes.remove(0));
+        assert_eq!(recv(&mut b).await, BusEvent::NodeDown("a".into()));
+        assert_eq!(recv(&mut c).await, BusEvent::NodeDown("a".into()));
+    }
+
+    #[tokio::test]
+    async fn tcp_bus_should_drop_unauthenticated_peers() {
+        let a = TcpBus::bind("a", "127.0.0.1:0", SECRET).await.unwrap();
+        let mut events = a.subscribe();
+        let forged = BusEvent::Room {
+            node: "x".into(),
+            msg: Msg::message("lobby", "mallory", "trust me"),
+            private: false,
+        };
+        let line = serde_json::to_string(&forged).unwrap();
+        let wrong = Claims::new("x", HANDSHAKE_TTL).encode(b"guess").unwrap();
+        for handshake in ["x".to_string(), wrong] {
+            let mut stream = TcpStream::connect(a.local_addr()).await.unwrap();
+            let frames = format!("{handshake}\n{line}\n");
+            stream.write_all(frames.as_bytes()).await.unwrap();
+        }
+
+        // only what a node knowing the secret sends gets through
+        let b = TcpBus::bind("b", "127.0.0.1:0", SECRET).await.unwrap();
+        b.connect(a.local_addr());
+        wait_for_peers(&b, 1).await;
+        let event = BusEvent::Room {
+            node: "b".into(),
+            msg: Msg::message("lobby", "tyr", "hello"),
+            private: false,
+        };
+        b.publish(event.clone());
+        // nothing from the others came before
+        let received = tokio::time::timeout(Duration::from_secs(5), events.recv()).await;
+        assert_eq!(received.unwrap().unwrap(), event);
+    }
+
+    #[tokio::test]
+    async fn tcp_bus_should_drop_events_it_cant_send() {
+        let a = TcpBus::bind("a", "127.0.0.1:0", SECRET).await.unwrap();
+        let event = BusEvent::NodeDown("z".into());
+        // nobody listens there
+        let gone = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        a.connect(gone.local_addr().unwrap());
+        drop(gone);
+        a.publish(event.clone());
+        assert_eq!(a.dropped(), 1);
+
+        // a peer that doesn't read fills up its queue
+        let c = TcpBus::bind("c", "127.0.0.1:0", SECRET).await.unwrap();
+        let b = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        c.connect(b.local_addr().unwrap());
+        let _stalled = b.accept().await.unwrap();
+        wait_for_peers(&c, 1).await;
+        let big = BusEvent::Room {
+            node: "c".into(),
+            msg: Msg::message("lobby", "tyr", &"x".repeat(64 * 1024)),
+            private: false,
+        };
+        for _ in 0..BUS_CAPACITY * 2 {
+            c.publish(big.clone());
+        }
+        assert!(c.dropped() > 0);
+    }
+
+    #[tokio::test]
+    async fn tcp_bus_should_drop_peers_sending_long_lines() {
+        let a = TcpBus::bind("a", "127.0.0.1:0", SECRET).await.unwrap();
+        let mut events = a.subscribe();
+        let mut stream = TcpStream::connect(a.local_addr()).await.unwrap();
+        let token = Claims::new("x", HANDSHAKE_TTL).encode(SECRET).unwrap();
+        stream
+            .write_all(format!("{token}\n").as_bytes())
+            .await
+            .unwrap();
+        let event = BusEvent::Room {
+            node: "x".into(),
+            msg: Msg::message("lobby", "tyr", "hello"),
+            private: false,
+        };
+        let line = serde_json::to_string(&event).unwrap() + "\n";
+        stream.write_all(line.as_bytes()).await.unwrap();
+        assert_eq!(recv(&mut events).await, event);
+
+        // the connection is gone once the line gets too long
+        let _ = stream.write_all(&vec![b' '; MAX_FRAME + 1]).await;
+        let _ = stream.write_all(line.as_bytes()).await;
+        assert_eq!(recv(&mut events).await, BusEvent::NodeDown("x".into()));
+    }
+}
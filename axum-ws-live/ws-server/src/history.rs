@@ -0,0 +1,327 @@
+use dashmap::DashMap;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::log::warn;
+
+use ws_shared::Msg;
+
+pub type BackendError = Box<dyn Error + Send + Sync>;
+
+// recent messages kept in memory for each room
+pub const RING_CAPACITY: usize = 256;
+// the low bits of a message id are the index of the node that stored it, so
+// ids stay unique across a cluster
+const NODE_BITS: u32 = 16;
+
+/// Durable storage for chat messages. Calls are blocking, `History` runs
+/// them on the blocking thread pool
+pub trait HistoryBackend: Send + Sync + 'static {
+    fn append(&self, msg: &Msg) -> Result<(), BackendError>;
+    /// up to `limit` messages of the room with id below `before`, oldest first
+    fn load(&self, room: &str, before: Option<u64>, limit: usize)
+        -> Result<Vec<Msg>, BackendError>;
+    /// the largest id stored, so ids keep growing after a restart
+    fn last_id(&self) -> Result<u64, BackendError>;
+}
+
+/// Chat history: the last messages of every room in memory, and everything
+/// in the optional durable backend
+pub struct History {
+    recent: DashMap<String, VecDeque<Msg>>,
+    capacity: usize,
+    backend: Option<Arc<dyn HistoryBackend>>,
+    // ids are handed out as `next_seq` followed by `node`
+    next_seq: AtomicU64,
+    node: u16,
+}
+
+impl std::fmt::Debug for History {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("History")
+            .field("rooms", &self.recent.len())
+            .field("capacity", &self.capacity)
+            .field("durable", &self.backend.is_some())
+            .finish()
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::in_memory(RING_CAPACITY)
+    }
+}
+
+impl History {
+    /// only the last `capacity` messages of each room are kept
+    pub fn in_memory(capacity: usize) -> Self {
+        Self {
+            recent: DashMap::default(),
+            capacity,
+            backend: None,
+            next_seq: AtomicU64::new(1),
+            node: 0,
+        }
+    }
+
+    pub fn with_backend(
+        capacity: usize,
+        backend: impl HistoryBackend,
+    ) -> Result<Self, BackendError> {
+        let last_id = backend.last_id()?;
+        Ok(Self {
+            backend: Some(Arc::new(backend)),
+            next_seq: AtomicU64::new((last_id >> NODE_BITS) + 1),
+            ..Self::in_memory(capacity)
+        })
+    }
+
+    /// store the messages of the node with this index in a cluster
+    pub(crate) fn with_node(mut self, node: u16) -> Self {
+        self.node = node;
+        self
+    }
+
+    pub(crate) fn node(&self) -> u16 {
+        self.node
+    }
+
+    /// assign an id to the message and store it
+    pub async fn append(&self, msg: &mut Msg) {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        msg.id = seq << NODE_BITS | u64::from(self.node);
+        self.store(msg).await;
+    }
+
+    /// store a message another node assigned the id of. Ids handed out from
+    /// now on are larger, so the history stays in order across nodes
+    pub(crate) async fn insert(&self, msg: &Msg) {
+        self.next_seq
+            .fetch_max((msg.id >> NODE_BITS) + 1, Ordering::Relaxed);
+        self.store(msg).await;
+    }
+
+    async fn store(&self, msg: &Msg) {
+        {
+            let mut ring = self.recent.entry(msg.room.clone()).or_default();
+            if ring.len() == self.capacity {
+                ring.pop_front();
+            }
+            ring.push_back(msg.clone());
+        }
+
+        if let Some(backend) = self.backend.clone() {
+            let msg = msg.clone();
+            let res = tokio::task::spawn_blocking(move || backend.append(&msg)).await;
+            if let Ok(Err(e)) | Err(e) = res.map_err(BackendError::from) {
+                warn!("failed to store message: {e}");
+            }
+        }
+    }
+
+    /// index of the node that stored the message with this id
+    pub(crate) fn node_of(id: u64) -> u16 {
+        (id & ((1 << NODE_BITS) - 1)) as u16
+    }
+
+    /// the stored message of the room with this id
+    pub async fn find(&self, room: &str, id: u64) -> Option<Msg> {
+        let before = Some(id.saturating_add(1));
+        self.load(room, before, 1)
+            .await
+            .pop()
+            .filter(|msg| msg.id == id)
+    }
+
+    /// up to `limit` messages of the room with id below `before`, oldest first.
+    /// Served from memory when it has enough of them
+    pub async fn load(&self, room: &str, before: Option<u64>, limit: usize) -> Vec<Msg> {
+        let before = before.unwrap_or(u64::MAX);
+        let recent: Vec<Msg> = self
+            .recent
+            .get(room)
+            .map(|ring| {
+                let older = ring.iter().filter(|msg| msg.id < before);
+                let skip = older.clone().count().saturating_sub(limit);
+                older.skip(skip).cloned().collect()
+            })
+            .unwrap_or_default();
+        let Some(backend) = self.backend.clone() else {
+            return recent;
+        };
+        if recent.len() >= limit {
+            return recent;
+        }
+
+        let room = room.to_string();
+        let res =
+            tokio::task::spawn_blocking(move || backend.load(&room, Some(before), limit)).await;
+        match res.map_err(BackendError::from) {
+            Ok(Ok(messages)) => messages,
+            Ok(Err(e)) | Err(e) => {
+                warn!("failed to load history: {e}");
+                recent
+            }
+        }
+    }
+}
+
+/// Stores messages in a SQLite database, one row per message
+pub struct SqliteHistory {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteHistory {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, BackendError> {
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, BackendError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, BackendError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY,
+                room TEXT NOT NULL,
+                body TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS messages_room_id ON messages (room, id);",
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl HistoryBackend for SqliteHistory {
+    fn append(&self, msg: &Msg) -> Result<(), BackendError> {
+        let body = serde_json::to_string(msg)?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO messages (id, room, body) VALUES (?1, ?2, ?3)",
+            params![msg.id, msg.room, body],
+        )?;
+        Ok(())
+    }
+
+    fn load(
+        &self,
+        room: &str,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<Msg>, BackendError> {
+        let before = before.map_or(i64::MAX, |before| before.min(i64::MAX as u64) as i64);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(
+            "SELECT body FROM messages WHERE room = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3",
+        )?;
+        let rows = stmt.query_map(params![room, before, limit as i64], |row| {
+            row.get::<_, String>(0)
+        })?;
+        let mut messages = rows
+            .map(|body| Ok(serde_json::from_str(&body?)?))
+            .collect::<Result<Vec<Msg>, BackendError>>()?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    fn last_id(&self) -> Result<u64, BackendError> {
+        let conn = self.conn.lock().unwrap();
+        let id: Option<u64> = conn
+            .query_row("SELECT MAX(id) FROM messages", [], |row| row.get(0))
+            .optional()?
+            .flatten();
+        Ok(id.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ws_shared::MsgData;
+
+    async fn append(history: &History, room: &str, text: &str) -> Msg {
+        let mut msg = Msg::message(room, "tyr", text);
+        history.append(&mut msg).await;
+        msg
+    }
+
+    #[tokio::test]
+    async fn ring_should_keep_the_latest_messages() {
+        let history = History::in_memory(2);
+        let first = append(&history, "lobby", "one").await;
+        append(&history, "lobby", "two").await;
+        let third = append(&history, "lobby", "three").await;
+        append(&history, "rust", "other room").await;
+
+        let messages = history.load("lobby", None, 10).await;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1], third);
+        assert!(first.id < third.id);
+        assert_eq!(history.load("lobby", Some(third.id), 10).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn ids_should_tell_the_node_apart() {
+        let a = History::default().with_node(1);
+        let b = History::default().with_node(2);
+        let (first, second) = (
+            append(&a, "lobby", "a").await,
+            append(&b, "lobby", "b").await,
+        );
+        assert_ne!(first.id, second.id);
+        assert_eq!(History::node_of(first.id), 1);
+        assert_eq!(History::node_of(second.id), 2);
+        assert!(append(&a, "lobby", "again").await.id > first.id);
+
+        // a message from b makes a's next id larger
+        let mut remote = Msg::message("lobby", "alice", "b");
+        remote.id = 100 << NODE_BITS | 2;
+        a.insert(&remote).await;
+        assert!(append(&a, "lobby", "after").await.id > remote.id);
+        assert_eq!(a.find("lobby", remote.id).await, Some(remote));
+    }
+
+    #[tokio::test]
+    async fn backend_should_serve_what_the_ring_dropped() {
+        let backend = SqliteHistory::open_in_memory().unwrap();
+        let history = History::with_backend(1, backend).unwrap();
+        for text in ["one", "two", "three"] {
+            append(&history, "lobby", text).await;
+        }
+
+        let messages = history.load("lobby", None, 2).await;
+        let texts: Vec<_> = messages.into_iter().map(|msg| msg.data).collect();
+        assert_eq!(
+            texts,
+            [
+                MsgData::Message("two".into()),
+                MsgData::Message("three".into())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sqlite_history_should_survive_restart() {
+        let path = std::env::temp_dir().join(format!("ws-history-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let history =
+            History::with_backend(RING_CAPACITY, SqliteHistory::open(&path).unwrap()).unwrap();
+        let stored = append(&history, "lobby", "persisted").await;
+        drop(history);
+
+        let history =
+            History::with_backend(RING_CAPACITY, SqliteHistory::open(&path).unwrap()).unwrap();
+        assert_eq!(history.load("lobby", None, 10).await, vec![stored.clone()]);
+        // ids keep growing after the restart
+        assert!(append(&history, "lobby", "again").await.id > stored.id);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,17 +1,120 @@
+mod access;
+mod auth;
+mod bus;
+mod history;
+mod presence;
+
 use axum::{
-    extract::{ws::Message, WebSocketUpgrade},
+    extract::{
+        ws::{close_code, CloseFrame, Message},
+        Path, WebSocketUpgrade,
+    },
+    http::{header, StatusCode},
     response::IntoResponse,
-    Extension,
+    Extension, Json,
 };
 use dashmap::{DashMap, DashSet};
 use futures::{Sink, SinkExt, Stream, StreamExt};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::broadcast;
-use tracing::log::warn;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::StreamMap;
+use tracing::log::{debug, warn};
+
+use ws_shared::{dm_peer, Codec, CodecError, Member, Msg, MsgData, Status, PROTOCOL_VERSION};
+
+use access::PrivateRoom;
+use presence::{Heartbeat, Peer, Tick};
+
+pub use auth::{Claims, DEFAULT_SECRET};
+pub use bus::{Bus, BusEvent, MemoryBus, TcpBus};
+pub use history::{BackendError, History, HistoryBackend, SqliteHistory, RING_CAPACITY};
+pub use presence::{AWAY_AFTER, OFFLINE_AFTER};
 
-use ws_shared::{Msg, MsgData};
+// messages a room buffers for its slowest member
+pub const CAPACITY: usize = 64;
+// messages sent to a client right after it joins a room
+const BACKLOG: usize = 50;
+// the most messages one `History` request can ask for
+const MAX_HISTORY_LIMIT: usize = 200;
+// largest text message a client may send
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+// how long a close frame may take to go out before the socket is dropped
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+// the longest a sender is held back under `LagPolicy::Backpressure`, once
+// per stall of the room
+const BACKPRESSURE_TIMEOUT: Duration = Duration::from_secs(5);
+const BACKPRESSURE_POLL: Duration = Duration::from_millis(5);
 
-const CAPACITY: usize = 64;
+/// What to do when a connection can't keep up with a room
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// drop the oldest messages and tell the client how many it missed
+    #[default]
+    DropOldest,
+    /// close the connection with 1013, the client reconnects and catches up
+    Disconnect,
+    /// hold the sender until the room buffer has space again. A single slow
+    /// member throttles everyone posting to the room, and a held sender's own
+    /// connection waits too. If the member doesn't catch up in time the room
+    /// falls back to dropping until its buffer drains. Joins, leaves and
+    /// presence changes are never held
+    Backpressure,
+}
+
+/// Tells the send task of a connection which room channels to listen on,
+/// or hands it something meant for this connection only
+#[derive(Debug)]
+enum Control {
+    Join(String, broadcast::Receiver<Arc<Msg>>),
+    Leave(String),
+    Reply(Msg),
+    Frame(Message),
+    // send the close frame and stop
+    Close(CloseFrame<'static>),
+}
+
+/// Options to create a `ChatState`
+#[derive(Debug)]
+pub struct ChatConfig {
+    // secret to verify the JWT on upgrade
+    pub secret: Vec<u8>,
+    pub history: History,
+    // larger messages close the connection with 1009
+    pub max_message_size: usize,
+    // messages buffered per room before slow members lag
+    pub room_capacity: usize,
+    pub lag_policy: LagPolicy,
+    // heartbeat timeouts of a connection, see `AWAY_AFTER` and `OFFLINE_AFTER`
+    pub away_after: Duration,
+    pub offline_after: Duration,
+    // name of this node in a cluster, and the bus to reach the other nodes
+    pub node: String,
+    // number of this node, unique in the cluster. It is part of the id of
+    // every message the node stores
+    pub node_index: u16,
+    pub bus: Option<Arc<dyn Bus>>,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            secret: DEFAULT_SECRET.to_vec(),
+            history: History::default(),
+            max_message_size: MAX_MESSAGE_SIZE,
+            room_capacity: CAPACITY,
+            lag_policy: LagPolicy::default(),
+            away_after: AWAY_AFTER,
+            offline_after: OFFLINE_AFTER,
+            node: "local".into(),
+            node_index: 0,
+            bus: None,
+        }
+    }
+}
 
 #[derive(Debug)]
 struct State {
@@ -19,16 +122,116 @@ struct State {
     user_rooms: DashMap<String, DashSet<String>>,
     // for a given room, how many users are in it
     room_users: DashMap<String, DashSet<String>>,
-    tx: broadcast::Sender<Arc<Msg>>,
+    // one channel per room, only connections of its members subscribe,
+    // so a message costs nothing for sockets outside the room
+    rooms: DashMap<String, broadcast::Sender<Arc<Msg>>>,
+    // open connections of each user. The same user may connect from several
+    // tabs, they all follow the user's rooms and only the last one closing
+    // leaves them
+    connections: DashMap<String, HashMap<u64, Peer>>,
+    // rooms created with `CreatePrivate`, all others are public
+    private_rooms: DashMap<String, PrivateRoom>,
+    next_connection_id: AtomicU64,
+    secret: Vec<u8>,
+    history: History,
+    max_message_size: usize,
+    room_capacity: usize,
+    lag_policy: LagPolicy,
+    // rooms whose slow member already made a sender wait in vain, nobody waits
+    // for it again until it caught up
+    stalled_rooms: DashSet<String>,
+    // times a connection fell behind, and the messages it missed
+    lag_events: AtomicU64,
+    lagged_messages: AtomicU64,
+    away_after: Duration,
+    offline_after: Duration,
+    node: String,
+    bus: Option<Arc<dyn Bus>>,
+    // users in each room connected to other nodes, with their node and status
+    remote_users: DashMap<String, HashMap<String, (String, Status)>>,
 }
 
 impl Default for State {
     fn default() -> Self {
-        let (tx, _rx) = broadcast::channel(CAPACITY);
+        Self::new(ChatConfig::default())
+    }
+}
+
+impl State {
+    fn new(config: ChatConfig) -> Self {
         Self {
             user_rooms: DashMap::default(),
             room_users: DashMap::default(),
-            tx,
+            rooms: DashMap::default(),
+            connections: DashMap::default(),
+            private_rooms: DashMap::default(),
+            next_connection_id: AtomicU64::new(1),
+            secret: config.secret,
+            history: config.history.with_node(config.node_index),
+            max_message_size: config.max_message_size,
+            room_capacity: config.room_capacity,
+            lag_policy: config.lag_policy,
+            stalled_rooms: DashSet::default(),
+            lag_events: AtomicU64::new(0),
+            lagged_messages: AtomicU64::new(0),
+            away_after: config.away_after,
+            offline_after: config.offline_after,
+            node: config.node,
+            bus: config.bus,
+            remote_users: DashMap::default(),
+        }
+    }
+
+    fn rooms_of(&self, username: &str) -> Vec<String> {
+        self.user_rooms
+            .get(username)
+            .map(|rooms| rooms.clone().into_iter().collect())
+            .unwrap_or_default()
+    }
+
+    fn users_of(&self, room: &str) -> Vec<String> {
+        self.room_users
+            .get(room)
+            .map(|users| users.clone().into_iter().collect())
+            .unwrap_or_default()
+    }
+
+    fn is_member(&self, username: &str, room: &str) -> bool {
+        self.room_users
+            .get(room)
+            .is_some_and(|users| users.contains(username))
+    }
+
+    fn room_channel(&self, room: &str) -> broadcast::Sender<Arc<Msg>> {
+        self.rooms
+            .entry(room.to_string())
+            .or_insert_with(|| broadcast::channel(self.room_capacity).0)
+            .clone()
+    }
+
+    /// send a message to every connection of the user
+    fn notify(&self, username: &str, msg: Msg) {
+        let Some(connections) = self.connections.get(username) else {
+            return;
+        };
+        for peer in connections.values() {
+            let _ = peer.ctrl.send(Control::Reply(msg.clone()));
+        }
+    }
+
+    /// update the subscriptions of every connection of the user
+    fn subscribe(&self, username: &str, room: &str, join: bool) {
+        let Some(connections) = self.connections.get(username) else {
+            return;
+        };
+        let tx = join.then(|| self.room_channel(room));
+        for peer in connections.values() {
+            let subscription = match &tx {
+                Some(tx) => Control::Join(room.to_string(), tx.subscribe()),
+                None => Control::Leave(room.to_string()),
+            };
+            // the connection is closing, it will be cleaned up soon
+            let _ = peer.ctrl.send(subscription);
         }
     }
 }
@@ -41,93 +244,584 @@ impl ChatState {
         Self(Default::default())
     }
 
+    /// with a bus, this has to be called within a tokio runtime
+    pub fn with_config(config: ChatConfig) -> Self {
+        let state = Arc::new(State::new(config));
+        if let Some(bus) = &state.bus {
+            tokio::spawn(bus::relay(Arc::downgrade(&state), bus.subscribe()));
+        }
+        Self(state)
+    }
+
+    pub fn with_secret(secret: &[u8]) -> Self {
+        Self::with_config(ChatConfig {
+            secret: secret.to_vec(),
+            ..Default::default()
+        })
+    }
+
+    pub(crate) fn secret(&self) -> &[u8] {
+        &self.0.secret
+    }
+
     pub fn get_user_rooms(&self, username: &str) -> Vec<String> {
-        self.0
-            .user_rooms
-            .get(username)
-            .map(|rooms| rooms.clone().into_iter().collect())
-            .unwrap_or_default()
+        self.0.rooms_of(username)
     }
 
     pub fn get_room_users(&self, room: &str) -> Vec<String> {
-        self.0
-            .room_users
-            .get(room)
-            .map(|users| users.clone().into_iter().collect())
-            .unwrap_or_default()
+        self.0.users_of(room)
+    }
+
+    /// users in the room with their status, sorted by name
+    pub fn get_room_members(&self, room: &str) -> Vec<Member> {
+        self.0.members(room)
+    }
+
+    pub fn get_user_status(&self, username: &str) -> Status {
+        self.0.status(username)
+    }
+
+    /// how many times a connection fell behind its rooms
+    pub fn lag_events(&self) -> u64 {
+        self.0.lag_events.load(Ordering::Relaxed)
+    }
+
+    /// counters in the Prometheus text format
+    pub fn metrics(&self) -> String {
+        let state = &self.0;
+        format!(
+            "# HELP ws_lag_events_total Times a connection fell behind a room.\n\
+             # TYPE ws_lag_events_total counter\n\
+             ws_lag_events_total {}\n\
+             # HELP ws_lagged_messages_total Messages dropped for slow connections.\n\
+             # TYPE ws_lagged_messages_total counter\n\
+             ws_lagged_messages_total {}\n\
+             # HELP ws_bus_dropped_events_total Events not sent to other nodes.\n\
+             # TYPE ws_bus_dropped_events_total counter\n\
+             ws_bus_dropped_events_total {}\n\
+             # HELP ws_connections Open WebSocket connections.\n\
+             # TYPE ws_connections gauge\n\
+             ws_connections {}\n",
+            state.lag_events.load(Ordering::Relaxed),
+            state.lagged_messages.load(Ordering::Relaxed),
+            state.bus.as_ref().map_or(0, |bus| bus.dropped()),
+            state
+                .connections
+                .iter()
+                .map(|connections| connections.len())
+                .sum::<usize>(),
+        )
     }
 }
 
+/// members of a room and their status, for dashboards. Private rooms only
+/// show them to users allowed in
+pub async fn room_users_handler(
+    claims: Claims,
+    Path(room): Path<String>,
+    Extension(state): Extension<ChatState>,
+) -> Result<Json<Vec<Member>>, (StatusCode, &'static str)> {
+    state
+        .0
+        .can_join(&claims.name, &room)
+        .map_err(|reason| (StatusCode::FORBIDDEN, reason))?;
+    Ok(Json(state.get_room_members(&room)))
+}
+
+pub async fn metrics_handler(Extension(state): Extension<ChatState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics(),
+    )
+}
+
+/// The upgrade is rejected with 401 unless a valid JWT comes in the
+/// `Authorization: Bearer` header or the `token` query param. Clients
+/// offering the `chat.msgpack` subprotocol get binary MessagePack frames
 pub async fn ws_handler(
+    claims: Claims,
     ws: WebSocketUpgrade,
     Extension(state): Extension<ChatState>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+    let max = state.0.max_message_size;
+    ws.max_message_size(max)
+        .max_frame_size(max)
+        .protocols(Codec::SUBPROTOCOLS)
+        .on_upgrade(|socket| {
+            let codec = socket
+                .protocol()
+                .and_then(|protocol| protocol.to_str().ok())
+                .and_then(Codec::from_subprotocol)
+                .unwrap_or_default();
+            handle_socket_with_codec(socket, state, claims.name, codec)
+        })
 }
 
-async fn handle_socket<S>(socket: S, state: ChatState)
+/// Serve one connection of an authenticated user until either side closes it
+pub async fn handle_socket<S>(socket: S, state: ChatState, username: String)
 where
     S: Stream<Item = Result<Message, axum::Error>> + Sink<Message> + Send + 'static,
 {
-    let mut rx = state.0.tx.subscribe();
+    handle_socket_with_codec(socket, state, username, Codec::Json).await
+}
+
+/// Like `handle_socket`, sending frames in the given wire format. Text
+/// frames from the client are always JSON
+pub async fn handle_socket_with_codec<S>(
+    socket: S,
+    state: ChatState,
+    username: String,
+    codec: Codec,
+) where
+    S: Stream<Item = Result<Message, axum::Error>> + Sink<Message> + Send + 'static,
+{
+    let id = state.0.next_connection_id.fetch_add(1, Ordering::Relaxed);
+    let (ctrl_tx, mut ctrl_rx) = mpsc::unbounded_channel();
+    // a new tab joins the rooms the user is already in
+    for room in state.get_user_rooms(&username) {
+        let rx = state.0.room_channel(&room).subscribe();
+        let _ = ctrl_tx.send(Control::Join(room, rx));
+    }
+    let peer = Peer {
+        ctrl: ctrl_tx.clone(),
+        away: false,
+    };
+    state.0.update_peers(&username, |peers| {
+        peers.insert(id, peer);
+    });
     let (mut sender, mut receiver) = socket.split();
+    // until the client says hello it gets what version 1 understands
+    let version = Arc::new(AtomicU32::new(1));
 
-    let state1 = state.clone();
+    let conn = Connection {
+        id,
+        username: username.clone(),
+        state: state.clone(),
+        ctrl: ctrl_tx,
+        version: version.clone(),
+        codec,
+    };
+    // returns true if a close frame is queued for the send task
     let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(data)) = receiver.next().await {
-            match data {
-                Message::Text(msg) => {
-                    handle_message(msg.as_str().try_into().unwrap(), state1.0.clone()).await;
-                }
-                _ => (),
+        let mut heartbeat = Heartbeat::new(conn.state.0.away_after, conn.state.0.offline_after);
+        loop {
+            let deadline = heartbeat.deadline();
+            let close = tokio::select! {
+                frame = receiver.next() => match frame {
+                    Some(Ok(frame)) => {
+                        if heartbeat.seen(!matches!(frame, Message::Pong(_))) {
+                            conn.set_away(false);
+                        }
+                        conn.handle_frame(frame).await
+                    }
+                    Some(Err(e)) => {
+                        warn!("failed to read from {}: {e}", conn.username);
+                        read_error_close(e)
+                    }
+                    None => return false,
+                },
+                _ = tokio::time::sleep_until(deadline) => match heartbeat.tick() {
+                    Some(Tick::Ping) => {
+                        let _ = conn.ctrl.send(Control::Frame(Message::Ping(vec![])));
+                        None
+                    }
+                    Some(Tick::Away) => {
+                        conn.set_away(true);
+                        None
+                    }
+                    Some(Tick::Dead) => Some(close_frame(close_code::AWAY, "heartbeat timeout")),
+                    None => None,
+                },
+            };
+            if let Some(close) = close {
+                return conn.ctrl.send(Control::Close(close)).is_ok();
             }
         }
     });
 
+    let (lag_state, lag_username) = (state.clone(), username.clone());
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            let data = msg.as_ref().try_into().unwrap();
-            if sender.send(Message::Text(data)).await.is_err() {
+        let mut rooms: StreamMap<String, BroadcastStream<Arc<Msg>>> = StreamMap::new();
+        loop {
+            let frame = tokio::select! {
+                // drain the rooms first, so a leave message still reaches the
+                // leaving user before the room is unsubscribed
+                biased;
+                Some((room, msg)) = rooms.next(), if !rooms.is_empty() => match msg {
+                    Ok(msg) => encode_for(&msg, version.load(Ordering::Relaxed), codec),
+                    Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                        warn!("{lag_username} lagged behind {missed} messages in {room}");
+                        let state = &lag_state.0;
+                        state.lag_events.fetch_add(1, Ordering::Relaxed);
+                        state.lagged_messages.fetch_add(missed, Ordering::Relaxed);
+                        if state.lag_policy == LagPolicy::Disconnect {
+                            let close = close_frame(close_code::AGAIN, "too slow");
+                            let _ = sender.send(Message::Close(Some(close))).await;
+                            break;
+                        }
+                        // the stream goes on with the oldest message still buffered
+                        let resync = Msg::new(&room, &lag_username, MsgData::Resync { missed });
+                        encode(&resync, codec)
+                    }
+                },
+                ctrl = ctrl_rx.recv() => match ctrl {
+                    Some(Control::Join(room, rx)) => {
+                        rooms.insert(room, BroadcastStream::new(rx));
+                        continue;
+                    }
+                    Some(Control::Leave(room)) => {
+                        rooms.remove(&room);
+                        continue;
+                    }
+                    Some(Control::Reply(msg)) => {
+                        encode_for(&msg, version.load(Ordering::Relaxed), codec)
+                    }
+                    Some(Control::Frame(frame)) => Some(frame),
+                    Some(Control::Close(close)) => {
+                        let _ = sender.send(Message::Close(Some(close))).await;
+                        break;
+                    }
+                    None => break,
+                },
+            };
+            let Some(frame) = frame else {
+                continue;
+            };
+            if sender.send(frame).await.is_err() {
                 warn!("failed to send message");
                 break;
             }
         }
     });
 
-    // if any of the tasks failed, we need to shut down the other one
+    // if any of the tasks failed, we need to shut down the other one.
+    // A queued close frame gets a moment to go out first
     tokio::select! {
-        _ = &mut recv_task => send_task.abort(),
+        closing = &mut recv_task => {
+            if let Ok(true) = closing {
+                let _ = tokio::time::timeout(CLOSE_TIMEOUT, &mut send_task).await;
+            }
+            send_task.abort();
+        }
         _ = &mut send_task => recv_task.abort(),
     }
 
-    // this user has left. Should send a leave message to all rooms
-    // usually we can get username from auth header, here we just use "fake_user"
-    let username = "fake_user";
-    warn!("connected for {username} closed");
-    for room in state.get_user_rooms(username) {
-        if let Err(e) = state.0.tx.send(Arc::new(Msg::leave(&room, username))) {
-            warn!("failed to send leave message: {e}");
+    warn!("connection for {username} closed");
+    let mut last = true;
+    state.0.update_peers(&username, |peers| {
+        peers.remove(&id);
+        last = peers.is_empty();
+    });
+    if !last {
+        return;
+    }
+    // the user has no connection left, leave all the rooms
+    for room in state.get_user_rooms(&username) {
+        handle_message(Msg::leave(&room, &username), state.0.clone()).await;
+    }
+}
+
+/// The receiving side of one connection
+struct Connection {
+    id: u64,
+    username: String,
+    state: ChatState,
+    ctrl: mpsc::UnboundedSender<Control>,
+    // protocol version agreed on with `Hello`
+    version: Arc<AtomicU32>,
+    // wire format of binary frames
+    codec: Codec,
+}
+
+impl Connection {
+    /// send a message to this connection only
+    fn reply(&self, msg: Msg) {
+        // the send task is gone, the connection is closing anyway
+        let _ = self.ctrl.send(Control::Reply(msg));
+    }
+
+    fn set_away(&self, away: bool) {
+        self.state.0.update_peers(&self.username, |peers| {
+            if let Some(peer) = peers.get_mut(&self.id) {
+                peer.away = away;
+            }
+        });
+    }
+
+    /// tell the client what was wrong with a message it sent
+    fn error(&self, room: &str, reason: impl Into<String>) {
+        self.reply(Msg::new(
+            room,
+            &self.username,
+            MsgData::Error(reason.into()),
+        ));
+    }
+
+    /// returns the close frame to answer with if the connection is done
+    async fn handle_frame(&self, frame: Message) -> Option<CloseFrame<'static>> {
+        match frame {
+            Message::Text(text) if text.len() > self.state.0.max_message_size => {
+                Some(close_frame(close_code::SIZE, "message too big"))
+            }
+            Message::Binary(data) if data.len() > self.state.0.max_message_size => {
+                Some(close_frame(close_code::SIZE, "message too big"))
+            }
+            Message::Text(text) => {
+                self.handle_msg(Codec::Json.decode(text.as_bytes())).await;
+                None
+            }
+            Message::Binary(data) if self.codec.is_binary() => {
+                self.handle_msg(self.codec.decode(&data)).await;
+                None
+            }
+            Message::Binary(_) => Some(close_frame(
+                close_code::UNSUPPORTED,
+                "binary frames need the chat.msgpack subprotocol",
+            )),
+            Message::Ping(data) => {
+                let _ = self.ctrl.send(Control::Frame(Message::Pong(data)));
+                None
+            }
+            Message::Pong(_) => None,
+            // echo the client's close code back
+            Message::Close(frame) => {
+                let code = frame.map_or(close_code::NORMAL, |frame| frame.code);
+                Some(close_frame(code, ""))
+            }
+        }
+    }
+
+    async fn handle_msg(&self, msg: Result<Msg, CodecError>) {
+        let state = &self.state.0;
+        let username = &self.username;
+        let mut msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("invalid message from {username}: {e}");
+                return self.error("", format!("invalid message: {e}"));
+            }
+        };
+        // the handshake doesn't belong to a room
+        if let MsgData::Hello { version } = msg.data {
+            return self.hello(version);
+        }
+        if msg.room.is_empty() {
+            return self.error("", "room is required");
+        }
+        // the connection speaks for the authenticated user only
+        if &msg.username != username {
+            warn!("{username} sent a message as {}", msg.username);
+            msg.username = username.clone();
+        }
+        // ids are handed out by the server when it stores a message
+        msg.id = 0;
+
+        let room = msg.room.clone();
+        // a direct message pulls both users into the conversation
+        if let (MsgData::Message(_), Some(peer)) = (&msg.data, dm_peer(&room, username)) {
+            self.open_direct(&room, peer).await;
+        }
+        let in_room_only = matches!(
+            msg.data,
+            MsgData::Leave
+                | MsgData::Message(_)
+                | MsgData::Typing(_)
+                | MsgData::Members
+                | MsgData::Edit { .. }
+                | MsgData::Delete { .. }
+                | MsgData::Reaction { .. }
+        );
+        if in_room_only && !state.is_member(username, &room) {
+            return self.error(&room, "not a member of this room");
+        }
+        match msg.data {
+            MsgData::History { before, limit } => {
+                // only members can read the history of a room
+                if !state.is_member(username, &room) {
+                    return self.error(&room, "not a member of this room");
+                }
+                let limit = limit.min(MAX_HISTORY_LIMIT);
+                let messages = state.history.load(&room, before, limit).await;
+                self.reply(Msg::new(&room, username, MsgData::Backlog(messages)));
+            }
+            MsgData::Backlog(_)
+            | MsgData::Error(_)
+            | MsgData::Resync { .. }
+            | MsgData::Ack { .. }
+            | MsgData::Notice(_)
+            | MsgData::MemberList(_)
+            | MsgData::Presence(_) => self.error(&room, "only the server sends this message"),
+            MsgData::Members => {
+                let members = state.members(&room);
+                self.reply(Msg::new(&room, username, MsgData::MemberList(members)));
+            }
+            MsgData::Hello { .. } => unreachable!("handled above"),
+            MsgData::Unknown => self.error(&room, "unsupported message"),
+            MsgData::Edit { id, .. } | MsgData::Delete { id } => {
+                match state.history.find(&room, id).await {
+                    Some(orig)
+                        if &orig.username == username
+                            && matches!(orig.data, MsgData::Message(_)) =>
+                    {
+                        self.accept(msg).await
+                    }
+                    Some(_) => self.error(&room, "only the author can change this message"),
+                    None => self.error(&room, "no such message"),
+                }
+            }
+            MsgData::Reaction { id, .. } => match state.history.find(&room, id).await {
+                Some(_) => self.accept(msg).await,
+                None => self.error(&room, "no such message"),
+            },
+            MsgData::CreatePrivate => match state.create_private(username, &room) {
+                Ok(()) => {
+                    state.share_private(&room);
+                    msg.data = MsgData::Join;
+                    self.accept(msg).await;
+                }
+                Err(reason) => self.error(&room, reason),
+            },
+            MsgData::Invite(ref invitee) => match state.invite(username, &room, invitee) {
+                Ok(()) => {
+                    state.share_private(&room);
+                    state.notify_cluster(invitee, msg.clone(), false).await;
+                    self.reply(msg);
+                }
+                Err(reason) => self.error(&room, reason),
+            },
+            MsgData::RequestJoin => match state.request_join(username, &room) {
+                Ok(owner) => {
+                    state.share_private(&room);
+                    state.notify_cluster(&owner, msg.clone(), false).await;
+                    self.reply(msg);
+                }
+                Err(reason) => self.error(&room, reason),
+            },
+            MsgData::Approve(ref requester) => match state.approve(username, &room, requester) {
+                Ok(()) => {
+                    state.share_private(&room);
+                    let invite = Msg::new(&room, username, MsgData::Invite(requester.clone()));
+                    state.notify_cluster(requester, invite, true).await;
+                    self.reply(msg);
+                }
+                Err(reason) => self.error(&room, reason),
+            },
+            MsgData::Join => {
+                if let Err(reason) = state.can_join(username, &room) {
+                    return self.error(&room, reason);
+                }
+                self.accept(msg).await;
+                // catch up on what was said before joining
+                let messages = state.history.load(&room, None, BACKLOG).await;
+                if !messages.is_empty() {
+                    self.reply(Msg::new(&room, username, MsgData::Backlog(messages)));
+                }
+            }
+            _ => self.accept(msg).await,
+        }
+    }
+
+    /// join both users of a direct message room, the other one only if online.
+    /// Otherwise they read it from the history when they join
+    async fn open_direct(&self, room: &str, peer: &str) {
+        let state = &self.state.0;
+        for user in [self.username.as_str(), peer] {
+            let online = user == self.username || state.status(user) != Status::Offline;
+            if online && !state.is_member(user, room) {
+                handle_message(Msg::join(room, user), state.clone()).await;
+            }
+        }
+    }
+
+    fn hello(&self, version: u32) {
+        if version == 0 {
+            return self.error("", "unsupported protocol version");
+        }
+        let version = version.min(PROTOCOL_VERSION);
+        self.version.store(version, Ordering::Relaxed);
+        self.reply(Msg::new("", &self.username, MsgData::Hello { version }));
+    }
+
+    /// hand the message to its room, and ack it if the client asked for it
+    async fn accept(&self, msg: Msg) {
+        let room = msg.room.clone();
+        let client_id = msg.client_id.clone();
+        let id = handle_message(msg, self.state.0.clone()).await;
+        if let Some(client_id) = client_id {
+            self.reply(Msg::new(
+                &room,
+                &self.username,
+                MsgData::Ack { client_id, id },
+            ));
+        }
+    }
+}
+
+fn close_frame(code: u16, reason: &'static str) -> CloseFrame<'static> {
+    CloseFrame {
+        code,
+        reason: reason.into(),
+    }
+}
+
+/// the close frame to send after a read error, if the socket is still usable
+fn read_error_close(e: axum::Error) -> Option<CloseFrame<'static>> {
+    let e = e.into_inner().downcast::<tungstenite::Error>().ok()?;
+    match *e {
+        tungstenite::Error::Capacity(_) => Some(close_frame(close_code::SIZE, "message too big")),
+        tungstenite::Error::Utf8 => Some(close_frame(close_code::INVALID, "invalid utf-8")),
+        tungstenite::Error::Protocol(_) => {
+            Some(close_frame(close_code::PROTOCOL, "protocol error"))
+        }
+        _ => None,
+    }
+}
+
+/// encode for a peer speaking `version`, skipping what it has no use for
+fn encode_for(msg: &Msg, version: u32, codec: Codec) -> Option<Message> {
+    if msg.data.version() <= version {
+        return encode(msg, codec);
+    }
+    let msg = msg.clone().downgrade(version)?;
+    encode(&msg, codec)
+}
+
+fn encode(msg: &Msg, codec: Codec) -> Option<Message> {
+    let frame = match codec {
+        Codec::Json => String::try_from(msg)
+            .map(Message::Text)
+            .map_err(CodecError::Json),
+        _ => codec.encode(msg).map(Message::Binary),
+    };
+    match frame {
+        Ok(frame) => Some(frame),
+        Err(e) => {
+            warn!("failed to encode message: {e}");
+            None
         }
     }
 }
 
-async fn handle_message(msg: Msg, state: Arc<State>) {
-    println!("receive: {:?}", msg);
-    let msg = match msg.data {
+/// returns the id the message is stored as, 0 if it isn't stored
+async fn handle_message(mut msg: Msg, state: Arc<State>) -> u64 {
+    debug!("receive: {:?}", msg);
+    let room = msg.room.clone();
+    let username = msg.username.clone();
+    let leave = msg.data == MsgData::Leave;
+    match msg.data {
         MsgData::Join => {
-            let username = msg.username.clone();
-            let room = msg.room.clone();
             state
                 .user_rooms
                 .entry(username.clone())
-                .or_insert_with(DashSet::new)
+                .or_default()
                 .insert(room.clone());
             state
                 .room_users
-                .entry(room)
-                .or_insert_with(DashSet::new)
-                .insert(username);
-            msg
+                .entry(room.clone())
+                .or_default()
+                .insert(username.clone());
+            // subscribe before sending, so the user sees their own join
+            state.subscribe(&username, &room, true);
         }
         MsgData::Leave => {
             if let Some(v) = state.user_rooms.get_mut(&msg.username) {
@@ -144,14 +838,68 @@ async fn handle_message(msg: Msg, state: Arc<State>) {
                     state.room_users.remove(&msg.room);
                 }
             }
-            msg
         }
-        _ => msg,
+        MsgData::Message(_)
+        | MsgData::Edit { .. }
+        | MsgData::Delete { .. }
+        | MsgData::Reaction { .. } => state.history.append(&mut msg).await,
+        _ => (),
     };
 
-    if let Err(e) = state.tx.send(Arc::new(msg)) {
+    let id = msg.id;
+    // only what users post waits for slow members, not the bookkeeping
+    let hold = matches!(
+        msg.data,
+        MsgData::Message(_)
+            | MsgData::Edit { .. }
+            | MsgData::Delete { .. }
+            | MsgData::Reaction { .. }
+    );
+    state.publish(&msg);
+    // nobody is in the room, nobody to deliver to
+    let Some(tx) = state.rooms.get(&room).map(|tx| tx.clone()) else {
+        return id;
+    };
+    if hold && state.lag_policy == LagPolicy::Backpressure {
+        state.wait_for_capacity(&room, &tx).await;
+    }
+    if let Err(e) = tx.send(Arc::new(msg)) {
         warn!("error sending message: {e}");
     }
+    if leave {
+        state.subscribe(&username, &room, false);
+        // drop the channel of an empty room
+        if state
+            .rooms
+            .remove_if(&room, |_, _| !state.room_users.contains_key(&room))
+            .is_some()
+        {
+            state.stalled_rooms.remove(&room);
+        }
+    }
+    id
+}
+
+impl State {
+    /// wait until the slowest member of the room made some room in the buffer,
+    /// unless it already let a sender down since it last caught up
+    async fn wait_for_capacity(&self, room: &str, tx: &broadcast::Sender<Arc<Msg>>) {
+        if tx.len() < self.room_capacity {
+            self.stalled_rooms.remove(room);
+            return;
+        }
+        if self.stalled_rooms.contains(room) {
+            return;
+        }
+        let deadline = tokio::time::Instant::now() + BACKPRESSURE_TIMEOUT;
+        while tx.len() >= self.room_capacity {
+            if tokio::time::Instant::now() >= deadline {
+                self.stalled_rooms.insert(room.to_string());
+                return;
+            }
+            tokio::time::sleep(BACKPRESSURE_POLL).await;
+        }
+    }
 }
 
 #[cfg(test)]
@@ -159,6 +907,7 @@ mod tests {
     use super::*;
     use anyhow::{Ok, Result};
     use fake_socket::*;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn handle_join_should_work() -> Result<()> {
@@ -199,7 +948,7 @@ mod tests {
         .await?;
 
         let msg2 = &Msg::new("lobby", "tyr", MsgData::Leave);
-        client2.send(Message::Text(msg2.try_into()?))?;
+        client1.send(Message::Text(msg2.try_into()?))?;
 
         // then get alice join msg
         assert!(client1.recv().await.is_some());
@@ -215,36 +964,992 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn spoofed_username_should_be_overwritten() -> Result<()> {
+        let (client1, mut client2, state) = prepare_connections().await?;
+
+        let msg = &Msg::message("lobby", "alice", "i am alice");
+        client1.send(Message::Text(msg.try_into()?))?;
+        verify(
+            &mut client2,
+            "lobby",
+            "tyr",
+            MsgData::Message("i am alice".into()),
+        )
+        .await?;
+
+        // leaving on behalf of someone else only leaves yourself
+        let msg = &Msg::leave("lobby", "alice");
+        client1.send(Message::Text(msg.try_into()?))?;
+        verify(&mut client2, "lobby", "tyr", MsgData::Leave).await?;
+        assert_eq!(state.get_room_users("lobby"), vec!["alice"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn disconnect_should_leave_rooms_of_the_real_user() -> Result<()> {
+        let (client1, mut client2, state) = prepare_connections().await?;
+
+        // a second connection of tyr keeps him in the room
+        let (extra, socket) = create_fake_connection();
+        let state1 = state.clone();
+        let handle = tokio::spawn(async move {
+            handle_socket(socket, state1, "tyr".into()).await;
+        });
+        drop(extra);
+        handle.await?;
+        assert_eq!(state.get_user_rooms("tyr"), vec!["lobby"]);
+
+        drop(client1);
+        verify(&mut client2, "lobby", "tyr", MsgData::Leave).await?;
+        assert!(state.get_user_rooms("tyr").is_empty());
+        assert_eq!(state.get_room_users("lobby"), vec!["alice"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upgrade_should_require_valid_token() -> Result<()> {
+        use axum::extract::FromRequestParts;
+        use axum::http::{Request, StatusCode};
+
+        let state = ChatState::with_secret(b"secret");
+        let token = Claims::new("tyr", Duration::from_secs(60)).encode(b"secret")?;
+        let extract = |req: Request<()>| {
+            let state = state.clone();
+            async move {
+                let (mut parts, _) = req.into_parts();
+                parts.extensions.insert(state);
+                Claims::from_request_parts(&mut parts, &()).await
+            }
+        };
+
+        let req = Request::get("/")
+            .header("Authorization", format!("Bearer {token}"))
+            .body(())?;
+        assert_eq!(extract(req).await.unwrap().name, "tyr");
+        let req = Request::get(format!("/?room=lobby&token={token}")).body(())?;
+        assert_eq!(extract(req).await.unwrap().name, "tyr");
+
+        let req = Request::get("/").body(())?;
+        assert_eq!(extract(req).await.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        let other = Claims::new("tyr", Duration::from_secs(60)).encode(b"other")?;
+        let req = Request::get(format!("/?token={other}")).body(())?;
+        assert_eq!(extract(req).await.unwrap_err().0, StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn messages_should_only_reach_room_members() -> Result<()> {
+        let (mut client1, mut client2, state) = prepare_connections().await?;
+
+        let msg = &Msg::join("rust", "tyr");
+        client1.send(Message::Text(msg.try_into()?))?;
+        verify(&mut client1, "rust", "tyr", MsgData::Join).await?;
+        let msg = &Msg::message("rust", "tyr", "ownership");
+        client1.send(Message::Text(msg.try_into()?))?;
+        verify(
+            &mut client1,
+            "rust",
+            "tyr",
+            MsgData::Message("ownership".into()),
+        )
+        .await?;
+        assert_no_message(&mut client2).await;
+
+        // a second tab of tyr follows the rooms he is in
+        let (mut tab, socket) = create_fake_connection();
+        let state = state.clone();
+        tokio::spawn(async move {
+            handle_socket(socket, state, "tyr".into()).await;
+        });
+        let msg = &Msg::message("lobby", "alice", "hi");
+        client2.send(Message::Text(msg.try_into()?))?;
+        verify(&mut tab, "lobby", "alice", MsgData::Message("hi".into())).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn join_should_replay_history_and_support_scrollback() -> Result<()> {
+        let (mut client1, mut client2, state) = prepare_connections().await?;
+        for text in ["one", "two", "three"] {
+            client1.send(Message::Text(
+                (&Msg::message("lobby", "tyr", text)).try_into()?,
+            ))?;
+            client1.recv().await;
+            client2.recv().await;
+        }
+
+        let (mut bob, socket) = create_fake_connection();
+        let state1 = state.clone();
+        tokio::spawn(async move {
+            handle_socket(socket, state1, "bob".into()).await;
+        });
+        // not in the room yet, so no history
+        bob.send(Message::Text(
+            (&Msg::history("lobby", "bob", None, 10)).try_into()?,
+        ))?;
+        verify(
+            &mut bob,
+            "lobby",
+            "bob",
+            MsgData::Error("not a member of this room".into()),
+        )
+        .await?;
+
+        bob.send(Message::Text((&Msg::join("lobby", "bob")).try_into()?))?;
+        verify(&mut bob, "lobby", "bob", MsgData::Join).await?;
+        let backlog = recv_backlog(&mut bob).await?;
+        let texts: Vec<_> = backlog.iter().map(|msg| msg.data.clone()).collect();
+        assert_eq!(
+            texts,
+            ["one", "two", "three"].map(|text| MsgData::Message(text.into()))
+        );
+        assert!(backlog.iter().all(|msg| msg.username == "tyr"));
+
+        let before = Some(backlog[2].id);
+        bob.send(Message::Text(
+            (&Msg::history("lobby", "bob", before, 1)).try_into()?,
+        ))?;
+        let older = recv_backlog(&mut bob).await?;
+        assert_eq!(older, &backlog[1..2]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn malformed_messages_should_get_an_error_frame() -> Result<()> {
+        let (mut client1, mut client2, _state) = prepare_connections().await?;
+
+        client1.send(Message::Text("not json".into()))?;
+        match recv_msg(&mut client1).await?.data {
+            MsgData::Error(reason) => assert!(reason.starts_with("invalid message")),
+            data => panic!("expected error, got {:?}", data),
+        }
+        let msg = &Msg::new("lobby", "tyr", MsgData::Backlog(vec![]));
+        client1.send(Message::Text(msg.try_into()?))?;
+        verify(
+            &mut client1,
+            "lobby",
+            "tyr",
+            MsgData::Error("only the server sends this message".into()),
+        )
+        .await?;
+        assert_no_message(&mut client2).await;
+
+        // the connection is still usable
+        let msg = &Msg::message("lobby", "tyr", "still here");
+        client1.send(Message::Text(msg.try_into()?))?;
+        verify(
+            &mut client2,
+            "lobby",
+            "tyr",
+            MsgData::Message("still here".into()),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ping_and_close_should_be_answered() -> Result<()> {
+        let (mut client1, mut client2, state) = prepare_connections().await?;
+
+        client1.send(Message::Ping(b"hb".to_vec()))?;
+        assert_eq!(client1.recv().await, Some(Message::Pong(b"hb".to_vec())));
+
+        client1.send(Message::Close(Some(close_frame(close_code::AWAY, "bye"))))?;
+        match client1.recv().await {
+            Some(Message::Close(Some(frame))) => assert_eq!(frame.code, close_code::AWAY),
+            msg => panic!("expected close, got {:?}", msg),
+        }
+        verify(&mut client2, "lobby", "tyr", MsgData::Leave).await?;
+        assert_eq!(state.get_room_users("lobby"), vec!["alice"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oversized_and_binary_frames_should_close_the_connection() -> Result<()> {
+        let state = ChatState::with_config(ChatConfig {
+            max_message_size: 64,
+            ..Default::default()
+        });
+        let (mut client, socket) = create_fake_connection();
+        let state1 = state.clone();
+        tokio::spawn(async move {
+            handle_socket(socket, state1, "tyr".into()).await;
+        });
+        client.send(Message::Text("x".repeat(65)))?;
+        assert_eq!(recv_close_code(&mut client).await, close_code::SIZE);
+
+        let (mut client, socket) = create_fake_connection();
+        tokio::spawn(async move {
+            handle_socket(socket, state, "tyr".into()).await;
+        });
+        client.send(Message::Binary(vec![1, 2, 3]))?;
+        assert_eq!(recv_close_code(&mut client).await, close_code::UNSUPPORTED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lagging_connection_should_get_a_resync() -> Result<()> {
+        let (mut client, state) = flood(LagPolicy::DropOldest).await?;
+        verify(&mut client, "lobby", "tyr", MsgData::Resync { missed: 8 }).await?;
+        // then the messages still buffered
+        for text in ["8", "9"] {
+            verify(&mut client, "lobby", "alice", MsgData::Message(text.into())).await?;
+        }
+        assert_eq!(state.lag_events(), 1);
+        assert!(state.metrics().contains("ws_lagged_messages_total 8\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lagging_connection_should_be_closed_under_disconnect_policy() -> Result<()> {
+        let (mut client, state) = flood(LagPolicy::Disconnect).await?;
+        assert_eq!(recv_close_code(&mut client).await, close_code::AGAIN);
+        assert_eq!(state.lag_events(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn backpressure_should_not_drop_messages() -> Result<()> {
+        let (mut client, state) = flood(LagPolicy::Backpressure).await?;
+        for i in 0..10 {
+            let text = i.to_string();
+            verify(&mut client, "lobby", "alice", MsgData::Message(text)).await?;
+        }
+        assert_eq!(state.lag_events(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backpressure_should_give_up_on_a_stalled_member() -> Result<()> {
+        let state = ChatState::with_config(ChatConfig {
+            room_capacity: 2,
+            lag_policy: LagPolicy::Backpressure,
+            ..Default::default()
+        })
+        .0;
+        // a member that never reads
+        let _stalled = state.room_channel("lobby").subscribe();
+        for i in 0..2 {
+            handle_message(
+                Msg::message("lobby", "alice", &i.to_string()),
+                state.clone(),
+            )
+            .await;
+        }
+
+        // joins and presence changes go out right away
+        let start = tokio::time::Instant::now();
+        handle_message(Msg::join("lobby", "bob"), state.clone()).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // the first message waits for the member in vain, the others don't
+        for i in 2..10 {
+            handle_message(
+                Msg::message("lobby", "alice", &i.to_string()),
+                state.clone(),
+            )
+            .await;
+        }
+        let waited = start.elapsed();
+        assert!(waited >= BACKPRESSURE_TIMEOUT);
+        assert!(waited < BACKPRESSURE_TIMEOUT + Duration::from_secs(1));
+
+        Ok(())
+    }
+
+    /// tyr joins a room with a 2 message buffer, then alice sends 10 messages
+    /// without giving his connection a chance to read them
+    async fn flood(lag_policy: LagPolicy) -> Result<(FakeClient<Message>, ChatState)> {
+        let state = ChatState::with_config(ChatConfig {
+            room_capacity: 2,
+            lag_policy,
+            ..Default::default()
+        });
+        let (mut client, socket) = create_fake_connection();
+        let state1 = state.clone();
+        tokio::spawn(async move {
+            handle_socket(socket, state1, "tyr".into()).await;
+        });
+        client.send(Message::Text((&Msg::join("lobby", "tyr")).try_into()?))?;
+        verify(&mut client, "lobby", "tyr", MsgData::Join).await?;
+
+        // the test runtime is single threaded, the send task of tyr only runs
+        // when this one yields
+        for i in 0..10 {
+            let msg = Msg::message("lobby", "alice", &i.to_string());
+            handle_message(msg, state.0.clone()).await;
+        }
+        Ok((client, state))
+    }
+
+    #[tokio::test]
+    async fn hello_should_negotiate_version_and_ack_messages() -> Result<()> {
+        let (mut client1, mut client2, _state) = prepare_connections().await?;
+
+        let mut hello = Msg::hello("tyr");
+        hello.data = MsgData::Hello { version: 99 };
+        client1.send(Message::Text((&hello).try_into()?))?;
+        verify(&mut client1, "", "tyr", MsgData::Hello { version: 2 }).await?;
+
+        let msg = Msg::message("lobby", "tyr", "helo").with_client_id("c1");
+        client1.send(Message::Text((&msg).try_into()?))?;
+        let echo = recv_msg(&mut client1).await?;
+        assert_eq!(echo.client_id.as_deref(), Some("c1"));
+        let ack = MsgData::Ack {
+            client_id: "c1".into(),
+            id: echo.id,
+        };
+        verify(&mut client1, "lobby", "tyr", ack).await?;
+        // a version 1 client gets the message but not what it doesn't know
+        assert_eq!(recv_msg(&mut client2).await?.data, echo.data);
+
+        let edit = Msg::new(
+            "lobby",
+            "tyr",
+            MsgData::Edit {
+                id: echo.id,
+                text: "hello".into(),
+            },
+        );
+        client1.send(Message::Text((&edit).try_into()?))?;
+        assert!(matches!(
+            recv_msg(&mut client1).await?.data,
+            MsgData::Edit { id, .. } if id == echo.id
+        ));
+        assert_no_message(&mut client2).await;
+
+        let notice = Msg::notice("lobby", "hi");
+        client1.send(Message::Text((&notice).try_into()?))?;
+        verify(
+            &mut client1,
+            "lobby",
+            "tyr",
+            MsgData::Error("only the server sends this message".into()),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_the_author_should_edit_or_delete() -> Result<()> {
+        let (mut client1, mut client2, _state) = prepare_connections().await?;
+        client2.send(Message::Text((&Msg::hello("alice")).try_into()?))?;
+        recv_msg(&mut client2).await?;
+
+        client1.send(Message::Text(
+            (&Msg::message("lobby", "tyr", "mine")).try_into()?,
+        ))?;
+        let id = recv_msg(&mut client1).await?.id;
+        recv_msg(&mut client2).await?;
+
+        let delete = Msg::new("lobby", "alice", MsgData::Delete { id });
+        client2.send(Message::Text((&delete).try_into()?))?;
+        verify(
+            &mut client2,
+            "lobby",
+            "alice",
+            MsgData::Error("only the author can change this message".into()),
+        )
+        .await?;
+
+        // anyone in the room can react, and is told when typing
+        let reaction = MsgData::Reaction {
+            id,
+            emoji: "👍".into(),
+        };
+        client2.send(Message::Text(
+            (&Msg::new("lobby", "alice", reaction.clone())).try_into()?,
+        ))?;
+        verify(&mut client2, "lobby", "alice", reaction).await?;
+        client1.send(Message::Text(
+            (&Msg::new("lobby", "tyr", MsgData::Typing(true))).try_into()?,
+        ))?;
+        verify(&mut client2, "lobby", "tyr", MsgData::Typing(true)).await?;
+
+        let unknown = r#"{"room":"lobby","username":"tyr","timestamp":0,"data":{"Poll":[]}}"#;
+        client1.send(Message::Text(unknown.into()))?;
+        verify(
+            &mut client1,
+            "lobby",
+            "tyr",
+            MsgData::Error("unsupported message".into()),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn message_pack_connection_should_get_binary_frames() -> Result<()> {
+        let (mut client1, mut client2, state) = prepare_connections().await?;
+        let (mut bob, socket) = create_fake_connection();
+        tokio::spawn(async move {
+            handle_socket_with_codec(socket, state, "bob".into(), Codec::MessagePack).await;
+        });
+
+        let join = Codec::MessagePack.encode(&Msg::join("lobby", "bob"))?;
+        bob.send(Message::Binary(join))?;
+        let Some(Message::Binary(data)) = bob.recv().await else {
+            panic!("expected a binary frame");
+        };
+        assert_eq!(Codec::MessagePack.decode(&data)?.data, MsgData::Join);
+        // json clients in the same room get text frames
+        verify(&mut client1, "lobby", "bob", MsgData::Join).await?;
+        verify(&mut client2, "lobby", "bob", MsgData::Join).await?;
+
+        // text frames are still understood
+        bob.send(Message::Text(
+            (&Msg::message("lobby", "bob", "hi")).try_into()?,
+        ))?;
+        verify(&mut client1, "lobby", "bob", MsgData::Message("hi".into())).await?;
+        let Some(Message::Binary(data)) = bob.recv().await else {
+            panic!("expected a binary frame");
+        };
+        assert_eq!(
+            Codec::MessagePack.decode(&data)?.data,
+            MsgData::Message("hi".into())
+        );
+
+        bob.send(Message::Binary(b"garbage".to_vec()))?;
+        let Some(Message::Binary(data)) = bob.recv().await else {
+            panic!("expected a binary frame");
+        };
+        assert!(matches!(
+            Codec::MessagePack.decode(&data)?.data,
+            MsgData::Error(_)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn presence_should_be_listed_and_announced() -> Result<()> {
+        let state = ChatState::with_config(ChatConfig {
+            away_after: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let (client1, mut client2, state) = prepare_connections_with(state).await?;
+        client2.send(Message::Text((&Msg::hello("alice")).try_into()?))?;
+        recv_msg(&mut client2).await?;
+
+        let online = |username: &str| Member {
+            username: username.into(),
+            status: Status::Online,
+        };
+        client2.send(Message::Text(
+            (&Msg::new("lobby", "alice", MsgData::Members)).try_into()?,
+        ))?;
+        let members = MsgData::MemberList(vec![online("alice"), online("tyr")]);
+        verify(&mut client2, "lobby", "alice", members).await?;
+
+        // tyr said nothing for a while
+        recv_presence(&mut client2, "tyr", Status::Away).await?;
+        assert_eq!(state.get_user_status("tyr"), Status::Away);
+        let claims = Claims::new("alice", Duration::from_secs(60));
+        let Json(members) =
+            room_users_handler(claims, Path("lobby".into()), Extension(state.clone()))
+                .await
+                .unwrap();
+        assert_eq!(members[1].status, Status::Away);
+
+        client1.send(Message::Ping(vec![]))?;
+        recv_presence(&mut client2, "tyr", Status::Online).await?;
+
+        drop(client1);
+        recv_presence(&mut client2, "tyr", Status::Offline).await?;
+        assert_eq!(state.get_room_users("lobby"), vec!["alice"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn silent_connection_should_be_pinged_then_closed() -> Result<()> {
+        let state = ChatState::with_config(ChatConfig {
+            offline_after: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let (mut client, socket) = create_fake_connection();
+        tokio::spawn(async move {
+            handle_socket(socket, state, "tyr".into()).await;
+        });
+
+        assert_eq!(client.recv().await, Some(Message::Ping(vec![])));
+        client.send(Message::Pong(vec![]))?;
+        assert_eq!(client.recv().await, Some(Message::Ping(vec![])));
+        assert_eq!(recv_close_code(&mut client).await, close_code::AWAY);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn private_room_should_need_an_invite_or_approval() -> Result<()> {
+        let (mut client1, mut client2, state) = prepare_connections().await?;
+        for (client, username) in [(&mut client1, "tyr"), (&mut client2, "alice")] {
+            client.send(Message::Text((&Msg::hello(username)).try_into()?))?;
+            recv_msg(client).await?;
+        }
+        let (mut bob, socket) = create_fake_connection();
+        let state1 = state.clone();
+        tokio::spawn(async move {
+            handle_socket(socket, state1, "bob".into()).await;
+        });
+        bob.send(Message::Text((&Msg::hello("bob")).try_into()?))?;
+        recv_msg(&mut bob).await?;
+
+        let create = Msg::new("secret", "tyr", MsgData::CreatePrivate);
+        client1.send(Message::Text((&create).try_into()?))?;
+        verify(&mut client1, "secret", "tyr", MsgData::Join).await?;
+        // a public room can't be taken over
+        let create = Msg::new("lobby", "alice", MsgData::CreatePrivate);
+        client2.send(Message::Text((&create).try_into()?))?;
+        let error = MsgData::Error("room already exists".into());
+        verify(&mut client2, "lobby", "alice", error).await?;
+
+        bob.send(Message::Text((&Msg::join("secret", "bob")).try_into()?))?;
+        let error = MsgData::Error("not invited to this room".into());
+        verify(&mut bob, "secret", "bob", error).await?;
+        assert_eq!(state.get_room_users("secret"), vec!["tyr"]);
+        // nor pretend to leave it
+        bob.send(Message::Text(
+            (&Msg::new("secret", "bob", MsgData::Leave)).try_into()?,
+        ))?;
+        let error = MsgData::Error("not a member of this room".into());
+        verify(&mut bob, "secret", "bob", error).await?;
+        assert_no_message(&mut client1).await;
+        let claims = Claims::new("bob", Duration::from_secs(60));
+        let res = room_users_handler(claims, Path("secret".into()), Extension(state.clone()));
+        assert_eq!(res.await.unwrap_err().0, StatusCode::FORBIDDEN);
+
+        // alice is invited and can join
+        let invite = MsgData::Invite("alice".into());
+        client1.send(Message::Text(
+            (&Msg::new("secret", "tyr", invite.clone())).try_into()?,
+        ))?;
+        verify(&mut client1, "secret", "tyr", invite.clone()).await?;
+        verify(&mut client2, "secret", "tyr", invite).await?;
+        client2.send(Message::Text((&Msg::join("secret", "alice")).try_into()?))?;
+        verify(&mut client2, "secret", "alice", MsgData::Join).await?;
+
+        // bob asks, tyr approves and bob is in
+        let request = Msg::new("secret", "bob", MsgData::RequestJoin);
+        bob.send(Message::Text((&request).try_into()?))?;
+        verify(&mut bob, "secret", "bob", MsgData::RequestJoin).await?;
+        verify(&mut client1, "secret", "alice", MsgData::Join).await?;
+        verify(&mut client1, "secret", "bob", MsgData::RequestJoin).await?;
+        let approve = MsgData::Approve("bob".into());
+        client2.send(Message::Text(
+            (&Msg::new("secret", "alice", approve.clone())).try_into()?,
+        ))?;
+        let error = MsgData::Error("only the owner can do this".into());
+        verify(&mut client2, "secret", "alice", error).await?;
+        client1.send(Message::Text(
+            (&Msg::new("secret", "tyr", approve)).try_into()?,
+        ))?;
+        verify(&mut bob, "secret", "tyr", MsgData::Invite("bob".into())).await?;
+        verify(&mut bob, "secret", "bob", MsgData::Join).await?;
+        let mut users = state.get_room_users("secret");
+        users.sort();
+        assert_eq!(users, vec!["alice", "bob", "tyr"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn direct_messages_should_reach_only_the_two_users() -> Result<()> {
+        let (mut client1, mut client2, state) = prepare_connections().await?;
+        let room = ws_shared::dm_room("tyr", "alice");
+
+        client1.send(Message::Text(
+            (&Msg::direct("tyr", "alice", "psst")).try_into()?,
+        ))?;
+        verify(&mut client1, &room, "tyr", MsgData::Join).await?;
+        verify(&mut client1, &room, "alice", MsgData::Join).await?;
+        verify(&mut client1, &room, "tyr", MsgData::Message("psst".into())).await?;
+        verify(&mut client2, &room, "alice", MsgData::Join).await?;
+        verify(&mut client2, &room, "tyr", MsgData::Message("psst".into())).await?;
+
+        // nobody else can join or write to it
+        let (mut bob, socket) = create_fake_connection();
+        tokio::spawn(async move {
+            handle_socket(socket, state, "bob".into()).await;
+        });
+        bob.send(Message::Text((&Msg::join(&room, "bob")).try_into()?))?;
+        let error = MsgData::Error("not part of this conversation".into());
+        verify(&mut bob, &room, "bob", error).await?;
+        bob.send(Message::Text(
+            (&Msg::message(&room, "bob", "hi")).try_into()?,
+        ))?;
+        let error = MsgData::Error("not a member of this room".into());
+        verify(&mut bob, &room, "bob", error).await?;
+        assert_no_message(&mut client2).await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nodes_should_share_rooms_and_presence_over_a_bus() -> Result<()> {
+        let bus = Arc::new(MemoryBus::new());
+        let node = |name: &str, node_index| {
+            ChatState::with_config(ChatConfig {
+                node: name.into(),
+                node_index,
+                bus: Some(bus.clone()),
+                ..Default::default()
+            })
+        };
+        let (a, b) = (node("a", 1), node("b", 2));
+        let mut tyr = connect(&a, "tyr");
+        let mut alice = connect(&b, "alice");
+        tyr.send(Message::Text((&Msg::hello("tyr")).try_into()?))?;
+        recv_msg(&mut tyr).await?;
+
+        tyr.send(Message::Text((&Msg::join("lobby", "tyr")).try_into()?))?;
+        verify(&mut tyr, "lobby", "tyr", MsgData::Join).await?;
+        alice.send(Message::Text((&Msg::join("lobby", "alice")).try_into()?))?;
+        verify(&mut alice, "lobby", "alice", MsgData::Join).await?;
+        verify(&mut tyr, "lobby", "alice", MsgData::Join).await?;
+
+        alice.send(Message::Text(
+            (&Msg::message("lobby", "alice", "hi from b")).try_into()?,
+        ))?;
+        let hi = MsgData::Message("hi from b".into());
+        verify(&mut tyr, "lobby", "alice", hi).await?;
+        let members: Vec<_> = a
+            .get_room_members("lobby")
+            .into_iter()
+            .map(|member| member.username)
+            .collect();
+        assert_eq!(members, ["alice", "tyr"]);
+
+        drop(alice);
+        recv_presence(&mut tyr, "alice", Status::Offline).await?;
+        verify(&mut tyr, "lobby", "alice", MsgData::Leave).await?;
+        assert_eq!(a.get_room_members("lobby").len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn message_ids_should_be_unique_across_nodes() -> Result<()> {
+        let bus = Arc::new(MemoryBus::new());
+        let node = |name: &str, node_index| {
+            ChatState::with_config(ChatConfig {
+                node: name.into(),
+                node_index,
+                bus: Some(bus.clone()),
+                ..Default::default()
+            })
+        };
+        let (a, b) = (node("a", 1), node("b", 2));
+        let mut tyr = connect(&a, "tyr");
+        let mut alice = connect(&b, "alice");
+        for (client, username) in [(&mut tyr, "tyr"), (&mut alice, "alice")] {
+            client.send(Message::Text((&Msg::hello(username)).try_into()?))?;
+            recv_msg(client).await?;
+        }
+        tyr.send(Message::Text((&Msg::join("lobby", "tyr")).try_into()?))?;
+        verify(&mut tyr, "lobby", "tyr", MsgData::Join).await?;
+        alice.send(Message::Text((&Msg::join("lobby", "alice")).try_into()?))?;
+        verify(&mut alice, "lobby", "alice", MsgData::Join).await?;
+        verify(&mut tyr, "lobby", "alice", MsgData::Join).await?;
+
+        tyr.send(Message::Text(
+            (&Msg::message("lobby", "tyr", "from a")).try_into()?,
+        ))?;
+        let id = recv_msg(&mut tyr).await?.id;
+        assert_eq!(recv_msg(&mut alice).await?.id, id);
+        alice.send(Message::Text(
+            (&Msg::message("lobby", "alice", "from b")).try_into()?,
+        ))?;
+        assert_ne!(recv_msg(&mut alice).await?.id, id);
+        recv_msg(&mut tyr).await?;
+
+        // b knows who wrote the message of a
+        let edit = MsgData::Edit {
+            id,
+            text: "hacked".into(),
+        };
+        alice.send(Message::Text(
+            (&Msg::new("lobby", "alice", edit)).try_into()?,
+        ))?;
+        let error = MsgData::Error("only the author can change this message".into());
+        verify(&mut alice, "lobby", "alice", error).await?;
+        assert_no_message(&mut tyr).await;
+        let reaction = MsgData::Reaction {
+            id,
+            emoji: "+1".into(),
+        };
+        alice.send(Message::Text(
+            (&Msg::new("lobby", "alice", reaction.clone())).try_into()?,
+        ))?;
+        verify(&mut tyr, "lobby", "alice", reaction).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn private_rooms_should_span_nodes() -> Result<()> {
+        let bus = Arc::new(MemoryBus::new());
+        let node = |name: &str, node_index| {
+            ChatState::with_config(ChatConfig {
+                node: name.into(),
+                node_index,
+                bus: Some(bus.clone()),
+                ..Default::default()
+            })
+        };
+        let (a, b) = (node("a", 1), node("b", 2));
+        let mut tyr = connect(&a, "tyr");
+        let mut alice = connect(&b, "alice");
+        let mut bob = connect(&b, "bob");
+        for (client, username) in [(&mut tyr, "tyr"), (&mut alice, "alice"), (&mut bob, "bob")] {
+            client.send(Message::Text((&Msg::hello(username)).try_into()?))?;
+            recv_msg(client).await?;
+        }
+
+        let create = Msg::new("secret", "tyr", MsgData::CreatePrivate);
+        tyr.send(Message::Text((&create).try_into()?))?;
+        verify(&mut tyr, "secret", "tyr", MsgData::Join).await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !b.0.private_rooms.contains_key("secret") {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        // b knows the room is taken, and who may join it
+        alice.send(Message::Text((&Msg::join("secret", "alice")).try_into()?))?;
+        let error = MsgData::Error("not invited to this room".into());
+        verify(&mut alice, "secret", "alice", error).await?;
+        let create = Msg::new("secret", "alice", MsgData::CreatePrivate);
+        alice.send(Message::Text((&create).try_into()?))?;
+        let error = MsgData::Error("room already exists".into());
+        verify(&mut alice, "secret", "alice", error).await?;
+
+        // the invite reaches alice on b
+        let invite = MsgData::Invite("alice".into());
+        tyr.send(Message::Text(
+            (&Msg::new("secret", "tyr", invite.clone())).try_into()?,
+        ))?;
+        verify(&mut tyr, "secret", "tyr", invite.clone()).await?;
+        verify(&mut alice, "secret", "tyr", invite).await?;
+        alice.send(Message::Text((&Msg::join("secret", "alice")).try_into()?))?;
+        verify(&mut alice, "secret", "alice", MsgData::Join).await?;
+        verify(&mut tyr, "secret", "alice", MsgData::Join).await?;
+        tyr.send(Message::Text(
+            (&Msg::message("secret", "tyr", "for invited eyes only")).try_into()?,
+        ))?;
+        let id = recv_msg(&mut tyr).await?.id;
+        assert_eq!(recv_msg(&mut alice).await?.id, id);
+        assert_no_message(&mut bob).await;
+
+        // bob asks from b, tyr approves from a and bob is in
+        let request = Msg::new("secret", "bob", MsgData::RequestJoin);
+        bob.send(Message::Text((&request).try_into()?))?;
+        verify(&mut bob, "secret", "bob", MsgData::RequestJoin).await?;
+        verify(&mut tyr, "secret", "bob", MsgData::RequestJoin).await?;
+        let approve = MsgData::Approve("bob".into());
+        tyr.send(Message::Text(
+            (&Msg::new("secret", "tyr", approve.clone())).try_into()?,
+        ))?;
+        verify(&mut tyr, "secret", "tyr", approve).await?;
+        verify(&mut bob, "secret", "tyr", MsgData::Invite("bob".into())).await?;
+        verify(&mut bob, "secret", "bob", MsgData::Join).await?;
+        verify(&mut tyr, "secret", "bob", MsgData::Join).await?;
+        verify(&mut alice, "secret", "bob", MsgData::Join).await?;
+        let mut members: Vec<_> = a
+            .get_room_members("secret")
+            .into_iter()
+            .map(|member| member.username)
+            .collect();
+        members.sort();
+        assert_eq!(members, vec!["alice", "bob", "tyr"]);
+
+        // a node coming up hears about the room again
+        let mut events = bus.subscribe();
+        bus.publish(BusEvent::NodeUp("c".into()));
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await??;
+            if let BusEvent::PrivateRoom { room, invited, .. } = event {
+                assert_eq!(room, "secret");
+                assert!(invited.contains("alice") && invited.contains("bob"));
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn private_rooms_should_drop_messages_they_cant_check() -> Result<()> {
+        let bus = Arc::new(MemoryBus::new());
+        let a = ChatState::with_config(ChatConfig {
+            node: "a".into(),
+            node_index: 1,
+            bus: Some(bus.clone()),
+            ..Default::default()
+        });
+        let mut alice = connect(&a, "alice");
+        alice.send(Message::Text((&Msg::join("secret", "alice")).try_into()?))?;
+        verify(&mut alice, "secret", "alice", MsgData::Join).await?;
+
+        // a private room a hasn't heard of yet
+        bus.publish(BusEvent::Room {
+            node: "b".into(),
+            msg: Msg::message("secret", "tyr", "for my eyes only"),
+            private: true,
+        });
+        assert_no_message(&mut alice).await;
+
+        // once it hears, alice has to go
+        bus.publish(BusEvent::PrivateRoom {
+            node: "b".into(),
+            room: "secret".into(),
+            owner: "tyr".into(),
+            invited: Default::default(),
+            pending: Default::default(),
+        });
+        verify(&mut alice, "secret", "alice", MsgData::Leave).await?;
+        assert!(a.get_room_members("secret").is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn servers_should_talk_over_tcp() -> Result<()> {
+        let mut nodes = Vec::new();
+        for name in ["a", "b"] {
+            let bus = TcpBus::bind(name, "127.0.0.1:0", b"bus secret").await?;
+            nodes.push((name, bus));
+        }
+        for (_, bus) in &nodes {
+            for (_, peer) in &nodes {
+                bus.connect(peer.local_addr());
+            }
+        }
+        for (_, bus) in &nodes {
+            crate::bus::tests::wait_for_peers(bus, 1).await;
+        }
+        let mut states = nodes.into_iter().zip(1..).map(|((name, bus), node_index)| {
+            ChatState::with_config(ChatConfig {
+                node: name.into(),
+                node_index,
+                bus: Some(Arc::new(bus)),
+                ..Default::default()
+            })
+        });
+        let (a, b) = (states.next().unwrap(), states.next().unwrap());
+
+        let mut tyr = connect(&a, "tyr");
+        tyr.send(Message::Text((&Msg::join("lobby", "tyr")).try_into()?))?;
+        verify(&mut tyr, "lobby", "tyr", MsgData::Join).await?;
+        // a direct message reaches tyr on the other server
+        let alice = connect(&b, "alice");
+        alice.send(Message::Text(
+            (&Msg::direct("alice", "tyr", "psst")).try_into()?,
+        ))?;
+        let room = ws_shared::dm_room("alice", "tyr");
+        verify(&mut tyr, &room, "tyr", MsgData::Join).await?;
+        verify(&mut tyr, &room, "alice", MsgData::Message("psst".into())).await?;
+
+        Ok(())
+    }
+
+    fn connect(state: &ChatState, username: &str) -> FakeClient<Message> {
+        let (client, socket) = create_fake_connection();
+        tokio::spawn(handle_socket(socket, state.clone(), username.into()));
+        client
+    }
+
+    /// skip other messages until `username` has `status`
+    async fn recv_presence(
+        client: &mut FakeClient<Message>,
+        username: &str,
+        status: Status,
+    ) -> Result<()> {
+        loop {
+            let msg = recv_msg(client).await?;
+            if msg.username == username && msg.data == MsgData::Presence(status) {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn recv_close_code(client: &mut FakeClient<Message>) -> u16 {
+        match client.recv().await {
+            Some(Message::Close(Some(frame))) => frame.code,
+            msg => panic!("expected close, got {:?}", msg),
+        }
+    }
+
+    async fn recv_msg(client: &mut FakeClient<Message>) -> Result<Msg> {
+        match client.recv().await {
+            Some(Message::Text(msg)) => Ok(Msg::try_from(msg.as_str())?),
+            msg => panic!("expected text, got {:?}", msg),
+        }
+    }
+
+    async fn recv_backlog(client: &mut FakeClient<Message>) -> Result<Vec<Msg>> {
+        match client.recv().await {
+            Some(Message::Text(msg)) => match Msg::try_from(msg.as_str())?.data {
+                MsgData::Backlog(messages) => Ok(messages),
+                data => panic!("expected backlog, got {:?}", data),
+            },
+            msg => panic!("expected backlog, got {:?}", msg),
+        }
+    }
+
+    async fn assert_no_message(client: &mut FakeClient<Message>) {
+        let recv = tokio::time::timeout(Duration::from_millis(50), client.recv()).await;
+        assert!(recv.is_err(), "unexpected message: {:?}", recv);
+    }
+
     async fn prepare_connections() -> Result<(FakeClient<Message>, FakeClient<Message>, ChatState)>
     {
+        prepare_connections_with(ChatState::new()).await
+    }
+
+    async fn prepare_connections_with(
+        state: ChatState,
+    ) -> Result<(FakeClient<Message>, FakeClient<Message>, ChatState)> {
         let (mut client1, socket1) = create_fake_connection();
         let (mut client2, socket2) = create_fake_connection();
-        let state = ChatState::new();
 
         // mimic server hehavior
         let state1 = state.clone();
         tokio::spawn(async move {
-            handle_socket(socket1, state1).await;
+            handle_socket(socket1, state1, "tyr".into()).await;
         });
 
         let state2 = state.clone();
         tokio::spawn(async move {
-            handle_socket(socket2, state2).await;
+            handle_socket(socket2, state2, "alice".into()).await;
         });
 
         let msg1 = &Msg::join("lobby", "tyr");
         client1.send(Message::Text(msg1.try_into()?))?;
 
+        // tyr gets his own join msg, alice is not in the room yet
+        verify(&mut client1, "lobby", "tyr", MsgData::Join).await?;
+
         let msg2 = &Msg::join("lobby", "alice");
         client2.send(Message::Text(msg2.try_into()?))?;
 
-        // should first get tyr join msg
-        verify(&mut client1, "lobby", "tyr", MsgData::Join).await?;
-        verify(&mut client2, "lobby", "tyr", MsgData::Join).await?;
-
-        // then get alice join msg
-        assert!(client1.recv().await.is_some());
-        assert!(client2.recv().await.is_some());
+        // then both get alice join msg
+        verify(&mut client1, "lobby", "alice", MsgData::Join).await?;
+        verify(&mut client2, "lobby", "alice", MsgData::Join).await?;
 
         Ok((client1, client2, state))
     }
@@ -255,12 +1960,10 @@ mod tests {
         username: &str,
         data: MsgData,
     ) -> Result<()> {
-        if let Some(Message::Text(msg)) = client.recv().await {
-            let msg: Msg = Msg::try_from(msg.as_str())?;
-            assert_eq!(msg.room, room);
-            assert_eq!(msg.username, username);
-            assert_eq!(msg.data, data);
-        }
+        let msg = recv_msg(client).await?;
+        assert_eq!(msg.room, room);
+        assert_eq!(msg.username, username);
+        assert_eq!(msg.data, data);
 
         Ok(())
     }
@@ -1,12 +1,60 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use axum::{routing::get, Extension, Router, Server};
-use ws_server::{ws_handler, ChatState};
+use ws_server::{
+    metrics_handler, room_users_handler, ws_handler, ChatConfig, ChatState, History, LagPolicy,
+    SqliteHistory, TcpBus, RING_CAPACITY,
+};
 
 #[tokio::main]
 async fn main() {
-    let addr = SocketAddr::from(([127, 0, 0, 1], 8000));
-    let app = Router::new().route("/", get(ws_handler).layer(Extension(ChatState::default())));
+    let port = std::env::var("PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(8000);
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let mut config = ChatConfig::default();
+    if let Ok(secret) = std::env::var("JWT_SECRET") {
+        config.secret = secret.into_bytes();
+    }
+    // keep the chat history in a SQLite file, otherwise only in memory
+    if let Ok(path) = std::env::var("HISTORY_DB") {
+        let backend = SqliteHistory::open(&path).expect("failed to open history db");
+        config.history =
+            History::with_backend(RING_CAPACITY, backend).expect("failed to read history db");
+    }
+    // what to do with connections that can't keep up
+    config.lag_policy = match std::env::var("LAG_POLICY").as_deref() {
+        Ok("disconnect") => LagPolicy::Disconnect,
+        Ok("backpressure") => LagPolicy::Backpressure,
+        _ => LagPolicy::DropOldest,
+    };
+    // join other servers: BUS_ADDR to listen on, BUS_PEERS the comma separated
+    // bus addresses of the others, NODE_INDEX a number unique to this server,
+    // BUS_SECRET what the servers prove to each other they belong to the cluster with
+    if let Ok(bus_addr) = std::env::var("BUS_ADDR") {
+        config.node = std::env::var("NODE_ID").unwrap_or_else(|_| bus_addr.clone());
+        config.node_index = std::env::var("NODE_INDEX")
+            .expect("NODE_INDEX is required with BUS_ADDR")
+            .parse()
+            .expect("NODE_INDEX must be a number below 65536");
+        let bus_secret = std::env::var("BUS_SECRET").expect("BUS_SECRET is required with BUS_ADDR");
+        let bus = TcpBus::bind(&config.node, &bus_addr, bus_secret.as_bytes())
+            .await
+            .expect("failed to bind bus address");
+        let peers = std::env::var("BUS_PEERS").unwrap_or_default();
+        for peer in peers.split(',').filter(|peer| !peer.is_empty()) {
+            bus.connect(peer.trim().parse().expect("invalid bus peer address"));
+        }
+        config.bus = Some(Arc::new(bus));
+    }
+    let state = ChatState::with_config(config);
+    let app = Router::new()
+        .route("/", get(ws_handler))
+        .route("/rooms/:room/users", get(room_users_handler))
+        .route("/metrics", get(metrics_handler))
+        .layer(Extension(state));
     println!("Listening on {:?}", addr);
     Server::bind(&addr)
         .serve(app.into_make_service())
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use ws_shared::{Member, Msg, MsgData, Status};
+
+use crate::{Control, State};
+
+// no message from any connection of the user for this long makes them away
+pub const AWAY_AFTER: Duration = Duration::from_secs(60);
+// a connection not heard from for this long is dead and gets closed. Halfway
+// there the server pings it, so an idle client still answers with a pong
+pub const OFFLINE_AFTER: Duration = Duration::from_secs(180);
+
+/// An open connection of a user
+#[derive(Debug)]
+pub(crate) struct Peer {
+    pub(crate) ctrl: mpsc::UnboundedSender<Control>,
+    // the user hasn't done anything on this connection for a while
+    pub(crate) away: bool,
+}
+
+/// What the heartbeat of a connection asks for
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Tick {
+    Ping,
+    Away,
+    Dead,
+}
+
+/// Tracks when a connection was last heard from
+#[derive(Debug)]
+pub(crate) struct Heartbeat {
+    away_after: Duration,
+    offline_after: Duration,
+    // last frame sent by the client itself, pongs don't count
+    last_active: Instant,
+    // last frame of any kind
+    last_seen: Instant,
+    pinged: bool,
+    away: bool,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(away_after: Duration, offline_after: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            away_after,
+            offline_after,
+            last_active: now,
+            last_seen: now,
+            pinged: false,
+            away: false,
+        }
+    }
+
+    /// a frame arrived, returns true if it brought the connection back from away
+    pub(crate) fn seen(&mut self, active: bool) -> bool {
+        let now = Instant::now();
+        self.last_seen = now;
+        self.pinged = false;
+        if !active {
+            return false;
+        }
+        self.last_active = now;
+        std::mem::replace(&mut self.away, false)
+    }
+
+    /// when `tick` has something to do next
+    pub(crate) fn deadline(&self) -> Instant {
+        let mut deadline = self.last_seen + self.offline_after;
+        if !self.pinged {
+            deadline = deadline.min(self.last_seen + self.offline_after / 2);
+        }
+        if !self.away {
+            deadline = deadline.min(self.last_active + self.away_after);
+        }
+        deadline
+    }
+
+    pub(crate) fn tick(&mut self) -> Option<Tick> {
+        let now = Instant::now();
+        if now >= self.last_seen + self.offline_after {
+            return Some(Tick::Dead);
+        }
+        if !self.away && now >= self.last_active + self.away_after {
+            self.away = true;
+            return Some(Tick::Away);
+        }
+        if !self.pinged && now >= self.last_seen + self.offline_after / 2 {
+            self.pinged = true;
+            return Some(Tick::Ping);
+        }
+        None
+    }
+}
+
+impl State {
+    /// online if any connection of the user is active, away if all are idle
+    pub(crate) fn status(&self, username: &str) -> Status {
+        match self.connections.get(username) {
+            Some(peers) if peers.values().any(|peer| !peer.away) => Status::Online,
+            Some(peers) if !peers.is_empty() => Status::Away,
+            _ => Status::Offline,
+        }
+    }
+
+    /// members on this node and the other nodes of the cluster
+    pub(crate) fn members(&self, room: &str) -> Vec<Member> {
+        let mut members: Vec<_> = self
+            .users_of(room)
+            .into_iter()
+            .map(|username| Member {
+                status: self.status(&username),
+                username,
+            })
+            .collect();
+        if let Some(remote) = self.remote_users.get(room) {
+            for (username, (_, status)) in remote.iter() {
+                if !members.iter().any(|member| &member.username == username) {
+                    members.push(Member {
+                        username: username.clone(),
+                        status: *status,
+                    });
+                }
+            }
+        }
+        members.sort_by(|a, b| a.username.cmp(&b.username));
+        members
+    }
+
+    /// change the connections of the user, and tell the user's rooms if that
+    /// changed their status
+    pub(crate) fn update_peers(
+        &self,
+        username: &str,
+        update: impl FnOnce(&mut HashMap<u64, Peer>),
+    ) {
+        let before = self.status(username);
+        update(&mut self.connections.entry(username.to_string()).or_default());
+        self.connections
+            .remove_if(username, |_, peers| peers.is_empty());
+        let after = self.status(username);
+        if before != after {
+            self.announce(username, after);
+        }
+    }
+
+    fn announce(&self, username: &str, status: Status) {
+        for room in self.rooms_of(username) {
+            let Some(tx) = self.rooms.get(&room).map(|tx| tx.clone()) else {
+                continue;
+            };
+            let msg = Msg::new(&room, username, MsgData::Presence(status));
+            self.publish(&msg);
+            // nobody listening is fine
+            let _ = tx.send(Arc::new(msg));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeat_should_ping_then_go_away_then_die() {
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(10), Duration::from_secs(60));
+        assert_eq!(heartbeat.tick(), None);
+
+        tokio::time::sleep_until(heartbeat.deadline()).await;
+        assert_eq!(heartbeat.tick(), Some(Tick::Away));
+        // a pong keeps the connection alive but not active
+        assert!(!heartbeat.seen(false));
+        tokio::time::sleep_until(heartbeat.deadline()).await;
+        assert_eq!(heartbeat.tick(), Some(Tick::Ping));
+        assert!(heartbeat.seen(true));
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(heartbeat.tick(), Some(Tick::Away));
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert_eq!(heartbeat.tick(), Some(Tick::Ping));
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(heartbeat.tick(), Some(Tick::Dead));
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+
+use crate::Msg;
+
+/// How messages are put on the wire, picked with the WebSocket subprotocol
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// text frames, what clients get without asking for a subprotocol
+    #[default]
+    Json,
+    /// binary frames, about half the size of JSON
+    MessagePack,
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Json(serde_json::Error),
+    Encode(rmp_serde::encode::Error),
+    Decode(rmp_serde::decode::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Json(e) => write!(f, "{e}"),
+            CodecError::Encode(e) => write!(f, "{e}"),
+            CodecError::Decode(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl Codec {
+    /// subprotocols the server speaks, most preferred first
+    pub const SUBPROTOCOLS: [&'static str; 2] = ["chat.msgpack", "chat.json"];
+
+    pub fn subprotocol(&self) -> &'static str {
+        match self {
+            Codec::MessagePack => Self::SUBPROTOCOLS[0],
+            Codec::Json => Self::SUBPROTOCOLS[1],
+        }
+    }
+
+    pub fn from_subprotocol(name: &str) -> Option<Self> {
+        match name {
+            "chat.msgpack" => Some(Codec::MessagePack),
+            "chat.json" => Some(Codec::Json),
+            _ => None,
+        }
+    }
+
+    /// whether frames of this codec are binary
+    pub fn is_binary(&self) -> bool {
+        *self == Codec::MessagePack
+    }
+
+    pub fn encode(&self, msg: &Msg) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Codec::Json => serde_json::to_vec(msg).map_err(CodecError::Json),
+            // structs as maps, so optional fields can be left out
+            Codec::MessagePack => rmp_serde::to_vec_named(msg).map_err(CodecError::Encode),
+        }
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<Msg, CodecError> {
+        match self {
+            Codec::Json => serde_json::from_slice(data).map_err(CodecError::Json),
+            Codec::MessagePack => rmp_serde::from_slice(data).map_err(CodecError::Decode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MsgData;
+
+    fn samples() -> Vec<Msg> {
+        let mut stored = Msg::message("lobby", "tyr", "hello 世界");
+        stored.id = 42;
+        vec![
+            Msg::join("lobby", "tyr"),
+            stored.clone().with_client_id("c1"),
+            Msg::history("lobby", "tyr", Some(u64::MAX), 10),
+            Msg::new("lobby", "tyr", MsgData::Backlog(vec![stored])),
+            Msg::new("lobby", "tyr", MsgData::Typing(true)),
+            Msg::new(
+                "lobby",
+                "tyr",
+                MsgData::Reaction {
+                    id: 42,
+                    emoji: "🎉".into(),
+                },
+            ),
+            Msg::hello("tyr"),
+        ]
+    }
+
+    #[test]
+    fn codecs_should_round_trip() {
+        for codec in [Codec::Json, Codec::MessagePack] {
+            for msg in samples() {
+                let data = codec.encode(&msg).unwrap();
+                assert_eq!(codec.decode(&data).unwrap(), msg, "{codec:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn message_pack_should_be_smaller() {
+        let msg = &samples()[3];
+        let json = Codec::Json.encode(msg).unwrap();
+        let msgpack = Codec::MessagePack.encode(msg).unwrap();
+        assert!(msgpack.len() < json.len());
+        // and not understood as the other format
+        assert!(Codec::Json.decode(&msgpack).is_err());
+    }
+
+    #[test]
+    fn subprotocols_should_map_to_codecs() {
+        for name in Codec::SUBPROTOCOLS {
+            assert_eq!(Codec::from_subprotocol(name).unwrap().subprotocol(), name);
+        }
+        assert_eq!(Codec::from_subprotocol("graphql-ws"), None);
+    }
+}
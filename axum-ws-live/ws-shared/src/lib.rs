@@ -1,21 +1,213 @@
+mod codec;
+
+use std::cell::Cell;
 use std::time::SystemTime;
 
-use serde::{Deserialize, Serialize};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+
+pub use codec::{Codec, CodecError};
+
+// rooms named `dm:<user>:<user>` are direct messages between the two users.
+// Users with a `:` in their name can't have any, the room name would be ambiguous
+pub const DM_PREFIX: &str = "dm:";
+
+/// the direct message room of two users, the same whoever starts it
+pub fn dm_room(a: &str, b: &str) -> String {
+    let (a, b) = if a <= b { (a, b) } else { (b, a) };
+    format!("{DM_PREFIX}{a}:{b}")
+}
+
+/// the other user of a direct message room, if `username` is part of it
+pub fn dm_peer<'a>(room: &'a str, username: &str) -> Option<&'a str> {
+    let users = room.strip_prefix(DM_PREFIX)?;
+    let other = users
+        .strip_prefix(username)
+        .and_then(|rest| rest.strip_prefix(':'))
+        .or_else(|| {
+            users
+                .strip_suffix(username)
+                .and_then(|rest| rest.strip_suffix(':'))
+        })?;
+    let valid = !username.contains(':') && !other.contains(':');
+    (valid && dm_room(username, other) == room).then_some(other)
+}
+
+/// Version of the protocol spoken by this crate. Clients that never send
+/// `Hello` are treated as version 1
+pub const PROTOCOL_VERSION: u32 = 2;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub struct Msg {
+    // assigned by the server when the message is stored, 0 until then
+    #[serde(default)]
+    pub id: u64,
     pub room: String,
     pub username: String,
     pub timestamp: u64,
+    // generated by the client, echoed back in the `Ack` of the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(deserialize_with = "lenient")]
     pub data: MsgData,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum MsgData {
     Join,
     Leave,
     Message(String),
+    /// ask for up to `limit` older messages of the room, with id below `before`
+    History {
+        before: Option<u64>,
+        limit: usize,
+    },
+    /// stored messages sent back for `History`, and after `Join`, oldest first
+    Backlog(Vec<Msg>),
+    /// what was wrong with a message the client sent, only sent to that client
+    Error(String),
+    /// the connection fell behind and `missed` messages of the room were
+    /// dropped. Fetch them again with `History` if needed
+    Resync {
+        missed: u64,
+    },
+
+    // since version 2
+    /// first message of a connection, the server answers with the version
+    /// both sides speak
+    Hello {
+        version: u32,
+    },
+    /// the message with `client_id` was accepted and stored as `id`
+    Ack {
+        client_id: String,
+        id: u64,
+    },
+    /// started (true) or stopped (false) typing in the room
+    Typing(bool),
+    /// replace the text of the message `id`, only its author may
+    Edit {
+        id: u64,
+        text: String,
+    },
+    /// remove the message `id`, only its author may
+    Delete {
+        id: u64,
+    },
+    Reaction {
+        id: u64,
+        emoji: String,
+    },
+    /// sent by the server to everyone in the room
+    Notice(String),
+    /// ask who is in the room, answered with `MemberList`
+    Members,
+    MemberList(Vec<Member>),
+    /// the status of `username` changed
+    Presence(Status),
+    /// create the room as private, owned by the sender
+    CreatePrivate,
+    /// let a user join the private room. Only its owner may, the invited
+    /// user gets the same message
+    Invite(String),
+    /// ask the owner of a private room to let the sender in
+    RequestJoin,
+    /// accept a `RequestJoin`, the user joins right away if online
+    Approve(String),
+    /// a variant from a newer protocol version, never sent
+    #[serde(skip)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Online,
+    /// connected, but not heard from in a while
+    Away,
+    Offline,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub username: String,
+    pub status: Status,
+}
+
+/// unknown variants become `MsgData::Unknown` instead of failing the whole
+/// message, so a peer speaking an older version can skip them
+fn lenient<'de, D: Deserializer<'de>>(deserializer: D) -> Result<MsgData, D::Error> {
+    let value = serde_json::Value::deserialize(deserializer)?;
+    let unknown = Cell::new(false);
+    match MsgData::deserialize(TagCheck {
+        value,
+        unknown: &unknown,
+    }) {
+        Ok(data) => Ok(data),
+        Err(_) if unknown.get() => Ok(MsgData::Unknown),
+        Err(e) => Err(de::Error::custom(e)),
+    }
+}
+
+/// deserializes like the wrapped value, but checks the tag of an enum against
+/// the variants serde asks for and flags `unknown` instead of decoding it
+struct TagCheck<'a> {
+    value: serde_json::Value,
+    unknown: &'a Cell<bool>,
+}
+
+impl<'de> Deserializer<'de> for TagCheck<'_> {
+    type Error = serde_json::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.value.deserialize_any(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        // unit variants are a string, the others an object with the tag as only key
+        let tag = match &self.value {
+            serde_json::Value::String(tag) => Some(tag),
+            serde_json::Value::Object(map) if map.len() == 1 => map.keys().next(),
+            _ => None,
+        };
+        if tag.is_some_and(|tag| !variants.contains(&tag.as_str())) {
+            self.unknown.set(true);
+            return Err(de::Error::custom("unknown message kind"));
+        }
+        self.value.deserialize_enum(name, variants, visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl MsgData {
+    /// the first protocol version with this variant
+    pub fn version(&self) -> u32 {
+        match self {
+            MsgData::Join
+            | MsgData::Leave
+            | MsgData::Message(_)
+            | MsgData::History { .. }
+            | MsgData::Error(_)
+            | MsgData::Resync { .. } => 1,
+            MsgData::Backlog(messages) => messages
+                .iter()
+                .map(|msg| msg.data.version())
+                .max()
+                .unwrap_or(1),
+            _ => 2,
+        }
+    }
 }
 
 impl TryFrom<&str> for Msg {
@@ -37,29 +229,155 @@ impl TryFrom<&Msg> for String {
 impl Msg {
     pub fn new(room: &str, username: &str, data: MsgData) -> Self {
         Msg {
+            id: 0,
             room: room.into(),
             username: username.into(),
             timestamp: SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
+            client_id: None,
             data,
         }
     }
 
     pub fn join(room: &str, username: &str) -> Self {
-        Msg::new(room.into(), username.into(), MsgData::Join)
+        Msg::new(room, username, MsgData::Join)
     }
 
     pub fn leave(room: &str, username: &str) -> Self {
-        Msg::new(room.into(), username.into(), MsgData::Leave)
+        Msg::new(room, username, MsgData::Leave)
     }
 
     pub fn message(room: &str, username: &str, message: &str) -> Self {
-        Msg::new(
-            room.into(),
-            username.into(),
-            MsgData::Message(message.into()),
-        )
+        Msg::new(room, username, MsgData::Message(message.into()))
+    }
+
+    pub fn history(room: &str, username: &str, before: Option<u64>, limit: usize) -> Self {
+        Msg::new(room, username, MsgData::History { before, limit })
+    }
+
+    pub fn hello(username: &str) -> Self {
+        let version = PROTOCOL_VERSION;
+        Msg::new("", username, MsgData::Hello { version })
+    }
+
+    /// a message to `to` in the direct message room of the two users
+    pub fn direct(username: &str, to: &str, message: &str) -> Self {
+        Msg::message(&dm_room(username, to), username, message)
+    }
+
+    pub fn notice(room: &str, notice: &str) -> Self {
+        Msg::new(room, "", MsgData::Notice(notice.into()))
+    }
+
+    /// ask the server for an `Ack` once the message is accepted
+    pub fn with_client_id(mut self, client_id: &str) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+
+    /// the message as a peer speaking `version` understands it, `None` if it
+    /// has no use for it
+    pub fn downgrade(mut self, version: u32) -> Option<Msg> {
+        if self.data.version() <= version {
+            return Some(self);
+        }
+        self.client_id = None;
+        self.data = match self.data {
+            MsgData::Notice(notice) => MsgData::Message(notice),
+            MsgData::Backlog(messages) => MsgData::Backlog(
+                messages
+                    .into_iter()
+                    .filter_map(|msg| msg.downgrade(version))
+                    .collect(),
+            ),
+            _ => return None,
+        };
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_variants_should_degrade() {
+        let json = r#"{"room":"lobby","username":"tyr","timestamp":0,"data":{"Poll":["a","b"]},"color":"red"}"#;
+        let msg = Msg::try_from(json).unwrap();
+        assert_eq!(msg.data, MsgData::Unknown);
+        assert_eq!(msg.id, 0);
+
+        let json = r#"{"room":"lobby","username":"tyr","timestamp":0,"data":"Wave"}"#;
+        assert_eq!(Msg::try_from(json).unwrap().data, MsgData::Unknown);
+
+        // a known variant with a bad payload is still an error
+        let json = r#"{"room":"lobby","username":"tyr","timestamp":0,"data":{"Message":1}}"#;
+        assert!(Msg::try_from(json).is_err());
+        let json =
+            r#"{"room":"lobby","username":"tyr","timestamp":0,"data":{"Message":"a","Poll":1}}"#;
+        assert!(Msg::try_from(json).is_err());
+
+        // unknown kinds inside a backlog are skipped one by one
+        let json = r#"{"room":"lobby","username":"tyr","timestamp":0,"data":{"Backlog":[
+            {"room":"lobby","username":"tyr","timestamp":0,"data":{"Poll":[]}},
+            {"room":"lobby","username":"tyr","timestamp":0,"data":"Join"}]}}"#;
+        let MsgData::Backlog(messages) = Msg::try_from(json).unwrap().data else {
+            panic!("expected a backlog");
+        };
+        assert_eq!(messages[0].data, MsgData::Unknown);
+        assert_eq!(messages[1].data, MsgData::Join);
+    }
+
+    #[test]
+    fn v1_messages_should_keep_their_format() {
+        let msg = Msg::message("lobby", "tyr", "hi");
+        let json = String::try_from(&msg).unwrap();
+        assert!(!json.contains("client_id"));
+        assert!(json.contains(r#""data":{"Message":"hi"}"#));
+
+        let msg = msg.with_client_id("c1");
+        let json = String::try_from(&msg).unwrap();
+        assert_eq!(Msg::try_from(json.as_str()).unwrap(), msg);
+    }
+
+    #[test]
+    fn dm_room_should_be_shared_by_both_users() {
+        let room = dm_room("tyr", "alice");
+        assert_eq!(room, dm_room("alice", "tyr"));
+        assert_eq!(dm_peer(&room, "tyr"), Some("alice"));
+        assert_eq!(dm_peer(&room, "alice"), Some("tyr"));
+        assert_eq!(dm_peer(&room, "bob"), None);
+        assert_eq!(dm_peer("lobby", "tyr"), None);
+
+        // "a" and "b:c" would get the same room as "a:b" and "c"
+        let room = dm_room("a:b", "c");
+        assert_eq!(dm_peer(&room, "a"), None);
+        assert_eq!(dm_peer(&room, "c"), None);
+    }
+
+    #[test]
+    fn downgrade_should_drop_or_convert_new_variants() {
+        let typing = Msg::new("lobby", "tyr", MsgData::Typing(true));
+        assert_eq!(typing.clone().downgrade(2), Some(typing.clone()));
+        assert_eq!(typing.clone().downgrade(1), None);
+
+        let notice = Msg::notice("lobby", "maintenance at noon")
+            .downgrade(1)
+            .unwrap();
+        assert_eq!(notice.data, MsgData::Message("maintenance at noon".into()));
+
+        let message = Msg::message("lobby", "tyr", "hi");
+        let backlog = Msg::new(
+            "lobby",
+            "tyr",
+            MsgData::Backlog(vec![message.clone(), typing]),
+        );
+        assert_eq!(backlog.data.version(), 2);
+        assert_eq!(
+            backlog.downgrade(1).unwrap().data,
+            MsgData::Backlog(vec![message])
+        );
     }
 }